@@ -20,12 +20,17 @@ const ERR_ANNOTATION_NOT_BASE64: &str = "annotation is not base64 encoded";
 const ERR_DC_EMPTY: &str = "missing Dc value";
 const ERR_KBC_KBS_NOT_BASE64: &str = "KBC/KBS pair not base64 encoded";
 const ERR_KBC_KBS_NOT_FOUND: &str = "KBC/KBS pair not found";
+const ERR_LAYER_DIGEST_NOT_BASE64: &str = "layer digest not base64 encoded";
 const ERR_NO_KBC_NAME: &str = "missing KBC name";
 const ERR_NO_KBS_URI: &str = "missing KBS URI";
 const ERR_WRONG_DC_PARAM: &str = "Dc parameter not destined for agent";
 
 const KBC_KBS_PAIR_SEP: &str = "::";
 
+/// Dc parameter with the digest of the layer, which the annotation must be
+/// bound to if it is given.
+const LAYER_DIGEST_PARAM: &str = "attestation-agent-layer-digest";
+
 #[derive(Debug, Default)]
 pub struct KeyProvider {}
 
@@ -75,12 +80,8 @@ pub mod grpc {
 
             debug!("Call AA-KBC to decrypt...");
 
-            let decrypted_optsdata = attestation_agent
-                .decrypt_image_layer_annotation(
-                    &input_payload.kbc_name,
-                    &input_payload.kbs_uri,
-                    &input_payload.annotation,
-                )
+            let decrypted_optsdata = input_payload
+                .decrypt(&mut *attestation_agent)
                 .await
                 .map_err(|e| {
                     error!("Call AA-KBC to provide key failed: {}", e);
@@ -181,12 +182,8 @@ pub mod ttrpc {
 
             debug!("Call AA-KBC to decrypt...");
 
-            let decrypted_optsdata = attestation_agent
-                .decrypt_image_layer_annotation(
-                    &input_payload.kbc_name,
-                    &input_payload.kbs_uri,
-                    &input_payload.annotation,
-                )
+            let decrypted_optsdata = input_payload
+                .decrypt(&mut *attestation_agent)
                 .await
                 .map_err(|e| {
                     error!("Call AA-KBC to provide key failed: {}", e);
@@ -264,6 +261,32 @@ struct InputPayload {
     // Note: URI does *not* contain a scheme prefix.
     kbs_uri: String,
     annotation: String,
+    layer_digest: Option<String>,
+}
+
+impl InputPayload {
+    async fn decrypt<A: AttestationAPIs + Send>(
+        &self,
+        attestation_agent: &mut A,
+    ) -> Result<Vec<u8>> {
+        match &self.layer_digest {
+            Some(layer_digest) => {
+                attestation_agent
+                    .decrypt_bound_image_layer_annotation(
+                        &self.kbc_name,
+                        &self.kbs_uri,
+                        &self.annotation,
+                        layer_digest.as_bytes(),
+                    )
+                    .await
+            }
+            None => {
+                attestation_agent
+                    .decrypt_image_layer_annotation(&self.kbc_name, &self.kbs_uri, &self.annotation)
+                    .await
+            }
+        }
+    }
 }
 
 impl TryFrom<KeyProviderInput> for InputPayload {
@@ -274,19 +297,25 @@ impl TryFrom<KeyProviderInput> for InputPayload {
          * AA expects the received DC parameter format is:
          * "dc":{
          *     "Parameters":{
-         *         "attestation-agent":["< KBC_NAME::KBS_URI (base64encode) >"]
+         *         "attestation-agent":["< KBC_NAME::KBS_URI (base64encode) >"],
+         *         "attestation-agent-layer-digest":["< LAYER_DIGEST (base64encode) >"]
          *     }
          * }
+         *
+         * The layer digest is optional.
          */
 
         let annotation = get_annotation(&kpi)?;
 
         let (kbc_name, kbs_uri) = get_kbc_kbs_pair(&kpi)?;
 
+        let layer_digest = get_layer_digest(&kpi)?;
+
         let payload = InputPayload {
             kbc_name,
             kbs_uri,
             annotation,
+            layer_digest,
         };
 
         Ok(payload)
@@ -337,6 +366,24 @@ fn get_kbc_kbs_pair(kpi: &KeyProviderInput) -> Result<(String, String)> {
     }
 }
 
+fn get_layer_digest(kpi: &KeyProviderInput) -> Result<Option<String>> {
+    let value = match kpi
+        .keyunwrapparams
+        .dc
+        .as_ref()
+        .and_then(|dc| dc.parameters.get(LAYER_DIGEST_PARAM))
+        .and_then(|parameters_list| parameters_list.first())
+    {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+
+    let layer_digest =
+        base64::decode(value).map_err(|e| anyhow!("{}: {:?}", ERR_LAYER_DIGEST_NOT_BASE64, e))?;
+
+    Ok(Some(String::from_utf8(layer_digest)?))
+}
+
 fn str_to_kbc_kbs(value: &str) -> Result<(String, String)> {
     if let Some((kbc_name, kbs_uri)) = value.split_once(KBC_KBS_PAIR_SEP) {
         if kbc_name.is_empty() {
@@ -507,6 +554,74 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_get_layer_digest() {
+        #[derive(Debug)]
+        struct TestData {
+            dc: Option<Dc>,
+            result: Result<Option<String>>,
+        }
+
+        let layer_digest = "sha256:layer-digest";
+
+        let mut invalid_dc_not_base64: Dc = Dc::default();
+        invalid_dc_not_base64
+            .parameters
+            .insert(LAYER_DIGEST_PARAM.into(), vec![layer_digest.into()]);
+
+        let mut valid_dc: Dc = Dc::default();
+        valid_dc
+            .parameters
+            .insert(LAYER_DIGEST_PARAM.into(), vec![encode(layer_digest)]);
+
+        let tests = &[
+            TestData {
+                dc: None,
+                result: Ok(None),
+            },
+            TestData {
+                dc: Some(Dc::default()),
+                result: Ok(None),
+            },
+            TestData {
+                dc: Some(invalid_dc_not_base64),
+                result: Err(anyhow!(ERR_LAYER_DIGEST_NOT_BASE64)),
+            },
+            TestData {
+                dc: Some(valid_dc),
+                result: Ok(Some(layer_digest.into())),
+            },
+        ];
+
+        for (i, d) in tests.iter().enumerate() {
+            // Create a string containing details of the test
+            let msg = format!("test[{}]: {:?}", i, d);
+
+            let mut kpi = KeyProviderInput::default();
+            kpi.keyunwrapparams.dc = d.dc.clone();
+
+            let result = get_layer_digest(&kpi);
+
+            let msg = format!("{}: result: {:?}", msg, result);
+
+            if d.result.is_err() {
+                assert!(result.is_err(), "{}", msg);
+
+                let expected_error = format!("{:?}", d.result.as_ref().err().unwrap());
+                let actual_error = format!("{:?}", result.err().unwrap());
+
+                assert!(actual_error.starts_with(&expected_error), "{}", msg);
+            } else {
+                assert!(result.is_ok(), "{}", msg);
+
+                let expected_result = d.result.as_ref().unwrap();
+                let actual_result = result.unwrap();
+
+                assert_eq!(expected_result, &actual_result, "{}", msg);
+            }
+        }
+    }
+
     #[test]
     fn test_input_payload() {
         #[derive(Debug)]
@@ -544,6 +659,18 @@ mod tests {
             kbc_name: kbc_name.into(),
             kbs_uri: kbs_uri.into(),
             annotation: AGENT_NAME.into(),
+            layer_digest: None,
+        };
+
+        let layer_digest = "sha256:layer-digest";
+        let mut valid_dc_with_layer_digest = valid_dc.clone();
+        valid_dc_with_layer_digest
+            .parameters
+            .insert(LAYER_DIGEST_PARAM.into(), vec![encode(layer_digest)]);
+
+        let valid_result_with_layer_digest = InputPayload {
+            layer_digest: Some(layer_digest.into()),
+            ..valid_result.clone()
         };
 
         let tests = &[
@@ -602,6 +729,14 @@ mod tests {
                 ),
                 result: Ok(valid_result),
             },
+            TestData {
+                input: KeyProviderInput::default().with_key_unwrap_params(
+                    KeyUnwrapParams::default()
+                        .with_dc(valid_dc_with_layer_digest)
+                        .with_annotation(AGENT_NAME.into()),
+                ),
+                result: Ok(valid_result_with_layer_digest),
+            },
         ];
 
         for (i, d) in tests.iter().enumerate() {
@@ -675,6 +810,7 @@ mod tests {
             kbc_name: kbc_name.into(),
             kbs_uri: kbs_uri.into(),
             annotation,
+            layer_digest: None,
         };
 
         let default_serialised = serde_json::to_string(&default_input).unwrap();
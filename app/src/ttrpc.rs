@@ -45,8 +45,32 @@ pub async fn ttrpc_main() -> Result<()> {
                     .help("This Unix socket address which the GetResource ttRPC service will listen to, for example: --getresource_sock unix:///tmp/aa_getresource",
                     ),
             )
+            .arg(
+                Arg::with_name("Recipient preference")
+                    .long("recipient_preference")
+                    .takes_value(true)
+                    .help("Comma separated kid prefixes of the recipients of an annotation to try first, for example: --recipient_preference kbs://kbs.example.org:8080/,kbs:///dr/"),
+            )
+            .arg(
+                Arg::with_name("Reject unauthenticated wrap types")
+                    .long("reject_unauthenticated_wrap_types")
+                    .help("Refuse to decrypt image layers whose key is wrapped without authentication, s.t. A256CTR, or with an unknown wrap type"),
+            )
             .get_matches();
 
+    ASYNC_ATTESTATION_AGENT
+        .lock()
+        .await
+        .reject_unauthenticated_wrap_types(
+            app_matches.is_present("Reject unauthenticated wrap types"),
+        );
+    if let Some(preference) = app_matches.value_of("Recipient preference") {
+        ASYNC_ATTESTATION_AGENT
+            .lock()
+            .await
+            .recipient_preference(preference.split(',').map(String::from).collect());
+    }
+
     if !Path::new(DEFAULT_UNIX_SOCKET_DIR).exists() {
         std::fs::create_dir_all(DEFAULT_UNIX_SOCKET_DIR).expect("Create unix socket dir failed");
     }
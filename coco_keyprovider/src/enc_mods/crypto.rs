@@ -3,7 +3,11 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
-use aes_gcm::{aead::Aead, aes::Aes256, Aes256Gcm, Key, Nonce};
+use aes_gcm::{
+    aead::{Aead, Payload},
+    aes::Aes256,
+    Aes128Gcm, Aes256Gcm, KeyInit,
+};
 use anyhow::*;
 use strum_macros::EnumString;
 
@@ -13,11 +17,19 @@ pub const HARDCODED_KEY: &[u8] = &[
     245, 20, 202, 139, 155, 167, 240, 163, 55, 17, 218, 234,
 ];
 
-#[derive(Default, EnumString)]
+/// Wrap types of the attestation agent, s.t. `common::crypto::WrapType`.
+#[derive(Default, EnumString, Debug, PartialEq, Eq)]
 pub enum Algorithm {
     #[default]
     A256GCM,
     A256CTR,
+    #[strum(serialize = "A256CTR-HS256")]
+    A256CTRHS256,
+    A128GCM,
+    C20P,
+    XC20P,
+    A256GCMSIV,
+    A256KW,
 }
 
 impl ToString for Algorithm {
@@ -25,21 +37,72 @@ impl ToString for Algorithm {
         match self {
             Algorithm::A256GCM => "A256GCM".into(),
             Algorithm::A256CTR => "A256CTR".into(),
+            Algorithm::A256CTRHS256 => "A256CTR-HS256".into(),
+            Algorithm::A128GCM => "A128GCM".into(),
+            Algorithm::C20P => "C20P".into(),
+            Algorithm::XC20P => "XC20P".into(),
+            Algorithm::A256GCMSIV => "A256GCMSIV".into(),
+            Algorithm::A256KW => "A256KW".into(),
         }
     }
 }
 
-pub fn encrypt(data: &[u8], key: &[u8], iv: &[u8], algorithm: &Algorithm) -> Result<Vec<u8>> {
-    match algorithm {
-        Algorithm::A256GCM => {
-            use aes_gcm::KeyInit;
-            let encryption_key = Key::<Aes256Gcm>::from_slice(key);
-            let cipher = Aes256Gcm::new(encryption_key);
-            let nonce = Nonce::from_slice(iv);
-            cipher
-                .encrypt(nonce, data.as_ref())
-                .map_err(|e| anyhow!("Decrypt failed: {:?}", e))
+impl Algorithm {
+    /// Length in bytes of the key.
+    pub fn key_length(&self) -> usize {
+        match self {
+            Algorithm::A128GCM => 16,
+            Algorithm::A256CTRHS256 => 64,
+            _ => 32,
+        }
+    }
+
+    /// Length in bytes of the IV, which is empty for AES Key Wrap.
+    pub fn iv_length(&self) -> usize {
+        match self {
+            Algorithm::A256CTR | Algorithm::A256CTRHS256 => 16,
+            Algorithm::XC20P => 24,
+            Algorithm::A256KW => 0,
+            _ => 12,
         }
+    }
+}
+
+/// Encrypt `data`, authenticating `aad` with it. `A256CTR` and `A256KW` do
+/// not authenticate additional data, and fail if `aad` is not empty.
+pub fn encrypt(
+    data: &[u8],
+    key: &[u8],
+    iv: &[u8],
+    aad: &[u8],
+    algorithm: &Algorithm,
+) -> Result<Vec<u8>> {
+    if key.len() != algorithm.key_length() {
+        bail!(
+            "{} needs a {} bytes key, not {} bytes",
+            algorithm.to_string(),
+            algorithm.key_length(),
+            key.len()
+        );
+    }
+    if iv.len() != algorithm.iv_length() {
+        bail!(
+            "{} needs a {} bytes IV, not {} bytes",
+            algorithm.to_string(),
+            algorithm.iv_length(),
+            iv.len()
+        );
+    }
+
+    if !aad.is_empty() && matches!(algorithm, Algorithm::A256CTR | Algorithm::A256KW) {
+        bail!("{} does not authenticate AAD", algorithm.to_string());
+    }
+
+    let payload = Payload { msg: data, aad };
+    match algorithm {
+        Algorithm::A256GCM => Aes256Gcm::new_from_slice(key)?
+            .encrypt(iv.into(), payload)
+            .map_err(|e| anyhow!("Encrypt failed: {:?}", e)),
         Algorithm::A256CTR => {
             use ctr::cipher::{KeyIvInit, StreamCipher};
             let mut buf = data.to_vec();
@@ -47,5 +110,143 @@ pub fn encrypt(data: &[u8], key: &[u8], iv: &[u8], algorithm: &Algorithm) -> Res
             cipher.apply_keystream(&mut buf);
             Ok(buf)
         }
+        Algorithm::A256CTRHS256 => {
+            // Encrypt-then-MAC as `A128CBC-HS256` of RFC 7518 section
+            // 5.2.2: `key := MAC_KEY | ENC_KEY`.
+            use hmac::{Hmac, Mac};
+            let (mac_key, enc_key) = key.split_at(32);
+            let mut buf = encrypt(data, enc_key, iv, &[], &Algorithm::A256CTR)?;
+            let mut mac = <Hmac<sha2::Sha256> as Mac>::new_from_slice(mac_key)?;
+            mac.update(aad);
+            mac.update(iv);
+            mac.update(&buf);
+            mac.update(&(aad.len() as u64 * 8).to_be_bytes());
+            buf.extend_from_slice(&mac.finalize().into_bytes()[..16]);
+            Ok(buf)
+        }
+        Algorithm::A128GCM => Aes128Gcm::new_from_slice(key)?
+            .encrypt(iv.into(), payload)
+            .map_err(|e| anyhow!("Encrypt failed: {:?}", e)),
+        Algorithm::C20P => chacha20poly1305::ChaCha20Poly1305::new_from_slice(key)?
+            .encrypt(iv.into(), payload)
+            .map_err(|e| anyhow!("Encrypt failed: {:?}", e)),
+        Algorithm::XC20P => chacha20poly1305::XChaCha20Poly1305::new_from_slice(key)?
+            .encrypt(iv.into(), payload)
+            .map_err(|e| anyhow!("Encrypt failed: {:?}", e)),
+        Algorithm::A256GCMSIV => aes_gcm_siv::Aes256GcmSiv::new_from_slice(key)?
+            .encrypt(iv.into(), payload)
+            .map_err(|e| anyhow!("Encrypt failed: {:?}", e)),
+        Algorithm::A256KW => {
+            // Key wrap takes a multiple of 8 bytes. The PLBCO is JSON, which
+            // may end with whitespace.
+            let mut buf = data.to_vec();
+            let padding = (8 - buf.len() % 8) % 8;
+            buf.resize(std::cmp::max(16, buf.len() + padding), b' ');
+            aes_kw::KekAes256::try_from(key)
+                .map_err(|e| anyhow!("Invalid key: {:?}", e))?
+                .wrap_vec(&buf)
+                .map_err(|e| anyhow!("Encrypt failed: {:?}", e))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case(Algorithm::A256GCM, 16)]
+    #[case(Algorithm::A256CTR, 0)]
+    #[case(Algorithm::A256CTRHS256, 16)]
+    #[case(Algorithm::A128GCM, 16)]
+    #[case(Algorithm::C20P, 16)]
+    #[case(Algorithm::XC20P, 16)]
+    #[case(Algorithm::A256GCMSIV, 16)]
+    #[case(Algorithm::A256KW, 9)]
+    fn encrypt_lengths(#[case] algorithm: Algorithm, #[case] overhead: usize) {
+        let data = br#"{"key":"value"}"#;
+        let key = vec![7; algorithm.key_length()];
+        let iv = vec![9; algorithm.iv_length()];
+        let ciphertext = encrypt(data, &key, &iv, &[], &algorithm).expect("encrypt failed");
+        assert_eq!(ciphertext.len(), data.len() + overhead);
+
+        assert!(encrypt(data, &key[1..], &iv, &[], &algorithm).is_err());
+        assert!(encrypt(data, &key, &[0; 13], &[], &algorithm).is_err());
+    }
+
+    #[test]
+    fn encrypt_a256ctr_hs256() {
+        // The same vector as the attestation agent decrypts.
+        let key: Vec<u8> = (0..64).collect();
+        let iv: Vec<u8> = (0x40..0x50).collect();
+        let ciphertext = encrypt(
+            br#"{"optsdata":"plaintext message"}"#,
+            &key,
+            &iv,
+            &[],
+            &Algorithm::A256CTRHS256,
+        )
+        .expect("encrypt failed");
+        assert_eq!(
+            base64::encode(ciphertext),
+            "5euzvt2G1UfOf1RC8fNFIiY7bYs6wKmGxXmgOz2GsgCgmvnDYW5Vtdb/0f4m9fCF"
+        );
+    }
+
+    // The same vectors as the attestation agent decrypts.
+    #[rstest]
+    #[case(
+        Algorithm::A256GCM,
+        "mZvBU1JP42K5pTUMuRR/OhCoJjnPQS9OEjQ9hPB2z6bFlQXG23K1KFgBECTsMP3O"
+    )]
+    #[case(
+        Algorithm::A128GCM,
+        "QgsGuhk4ndH42GEl3FcRmSUTzQ5lprXz3jO7QBu5IXT6ck7DzcNYWJaT1BN7qpc0"
+    )]
+    #[case(
+        Algorithm::C20P,
+        "g3YT8QY5imAsrtQ6mZ8BrN2iF308iGECm3+hOo1CGBc5aIjFdHi9Neya3QxHYgAO"
+    )]
+    #[case(
+        Algorithm::A256GCMSIV,
+        "qidohylEeekSbg+yYlDcvLKWF6TY8yINhe3KKO+L0Je8PkbrWzSlKHn99vlL4Nwe"
+    )]
+    #[case(
+        Algorithm::A256CTRHS256,
+        "5euzvt2G1UfOf1RC8fNFIiY7bYs6wKmGxXmgOz2GsgB0iipk8LH70Dg8OMhhn051"
+    )]
+    fn encrypt_with_aad(#[case] algorithm: Algorithm, #[case] expected: &str) {
+        let key: Vec<u8> = (0..algorithm.key_length() as u8).collect();
+        let iv: Vec<u8> = (0x40..0x40 + algorithm.iv_length() as u8).collect();
+        let ciphertext = encrypt(
+            br#"{"optsdata":"plaintext message"}"#,
+            &key,
+            &iv,
+            b"sha256:0123456789abcdef",
+            &algorithm,
+        )
+        .expect("encrypt failed");
+        assert_eq!(base64::encode(ciphertext), expected);
+    }
+
+    #[rstest]
+    #[case(Algorithm::A256CTR)]
+    #[case(Algorithm::A256KW)]
+    fn encrypt_aad_unsupported(#[case] algorithm: Algorithm) {
+        let key = vec![0; algorithm.key_length()];
+        let iv = vec![0; algorithm.iv_length()];
+        assert!(encrypt(&[0; 16], &key, &iv, b"aad", &algorithm).is_err());
+    }
+
+    #[test]
+    fn parse_algorithm() {
+        assert_eq!("XC20P".parse::<Algorithm>().unwrap(), Algorithm::XC20P);
+        assert_eq!(Algorithm::A256GCMSIV.to_string(), "A256GCMSIV");
+        assert_eq!(
+            "A256CTR-HS256".parse::<Algorithm>().unwrap(),
+            Algorithm::A256CTRHS256
+        );
+        assert!("A512GCM".parse::<Algorithm>().is_err());
     }
 }
@@ -7,10 +7,11 @@ use std::collections::HashMap;
 
 use anyhow::*;
 use jwt_simple::prelude::Ed25519KeyPair;
-use log::{debug, info};
+use log::{debug, info, warn};
 use rand::RngCore;
 use reqwest::Url;
 use serde::{Deserialize, Serialize};
+use sharks::Sharks;
 use tokio::fs;
 
 use self::{crypto::Algorithm, kbs::register_kek};
@@ -18,6 +19,9 @@ use self::{crypto::Algorithm, kbs::register_kek};
 mod crypto;
 mod kbs;
 
+pub(crate) use kbs::build_http_client;
+pub use kbs::ProxyConfig;
+
 /// `AnnotationPacket` is what a encrypted image layer's
 /// `org.opencontainers.image.enc.keys.provider.attestation-agent`
 /// annotation should contain when it is encrypted by CoCo's
@@ -33,6 +37,21 @@ pub struct AnnotationPacket {
     pub iv: String,
     // Wrap type to specify encryption algorithm and mode
     pub wrap_type: String,
+    // Additional data authenticated with the wrapped data (base64-encoded),
+    // s.t. the digest of the layer, to bind it to its context
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aad: Option<String>,
+    // Shares of the KEK, if it is split across several KBSes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_shares: Option<KeyShares>,
+}
+
+/// The KEK split with Shamir secret sharing, s.t. any `threshold` of
+/// the `shares` registered in different KBSes reconstruct it.
+#[derive(Serialize, Deserialize)]
+pub struct KeyShares {
+    pub threshold: u8,
+    pub shares: Vec<String>,
 }
 
 struct InputParams {
@@ -49,14 +68,34 @@ struct InputParams {
     /// Specify the KEK to encrypted the image in local
     /// filesystem. This key will be read from fs and then
     /// used to encrypt the image. This key's length must
-    /// be 32 bytes
+    /// be 16 bytes for `A128GCM`, 64 bytes for `A256CTR-HS256`,
+    /// and 32 bytes otherwise
     keypath: Option<String>,
 
     /// Encryption algorithm, included in the `wrap_type`
     /// field of AnnotationPacket. Can be
     /// - `A256GCM`: aes 256 gcm (default)
-    /// - `A256CTR`: aes 256 ctr
+    /// - `A256CTR`: aes 256 ctr, without authentication
+    /// - `A256CTR-HS256`: aes 256 ctr with hmac sha 256
+    /// - `A128GCM`: aes 128 gcm
+    /// - `C20P`: chacha20-poly1305
+    /// - `XC20P`: xchacha20-poly1305
+    /// - `A256GCMSIV`: aes 256 gcm-siv
+    /// - `A256KW`: aes 256 key wrap
     algorithm: Algorithm,
+
+    /// Additional data authenticated with the wrapped data and
+    /// included in the `aad` field of AnnotationPacket, s.t. the
+    /// digest of the layer. Not supported by `A256CTR` and `A256KW`.
+    aad: Option<String>,
+
+    /// KBS Resource URIs the KEK is split across, s.t.
+    /// `kbs://kbs1:8080/default/image-kek/1`. Any `threshold`
+    /// of them reconstruct the KEK, which itself is not registered.
+    shares: Vec<String>,
+
+    /// Number of shares needed to reconstruct a split KEK.
+    threshold: u8,
 }
 
 const HARD_CODED_KEYID: &str = "kbs:///default/test-key/1";
@@ -82,69 +121,83 @@ fn parse_input_params(input: &str) -> Result<InputParams> {
         .unwrap_or(false);
     let keyid = map.get("keyid").map(|id| id.to_string());
     let keypath = map.get("keypath").map(|p| p.to_string());
-    let algorithm = map
-        .get("keypath")
-        .map(|alg| (*alg).try_into().unwrap_or_default())
+    let algorithm = match map.get("algorithm") {
+        Some(alg) => alg
+            .parse()
+            .map_err(|_| anyhow!("Unsupported algorithm {alg}"))?,
+        None => Algorithm::default(),
+    };
+    let aad = map.get("aad").map(|aad| aad.to_string());
+    let shares: Vec<String> = map
+        .get("shares")
+        .map(|shares| shares.split(',').map(String::from).collect())
         .unwrap_or_default();
+    let threshold = match map.get("threshold") {
+        Some(threshold) => threshold
+            .parse()
+            .map_err(|_| anyhow!("Illegal threshold {threshold}"))?,
+        None => 0,
+    };
+    if shares.is_empty() != (threshold == 0) {
+        bail!("shares and threshold must be given together");
+    }
+    if shares.len() > u8::MAX as usize || threshold as usize > shares.len() {
+        bail!("Illegal threshold {threshold} for {} shares", shares.len());
+    }
+    if sample && !shares.is_empty() {
+        bail!("sample keyprovider does not split the KEK");
+    }
     Ok(InputParams {
         sample,
         keyid,
         keypath,
         algorithm,
+        aad,
+        shares,
+        threshold,
     })
 }
 
 /// This function will generate (key, iv, keyid) for given `InputParams`
 async fn generate_key_parameters(input_params: &InputParams) -> Result<(Vec<u8>, Vec<u8>, String)> {
-    let sample_flag = input_params.sample;
-    match sample_flag {
+    let algorithm = &input_params.algorithm;
+    let mut iv = vec![0; algorithm.iv_length()];
+    match input_params.sample {
         // sample keyprovider will use hard coded key and iv
         true => {
             info!("Use sample keyprovider (HARDCODED KEY and IV)");
-            Ok((
-                crypto::HARDCODED_KEY.to_vec(),
-                [0; 12].to_vec(),
-                HARD_CODED_KEYID.into(),
-            ))
+            // The sample KBC only decrypts with the hardcoded A256GCM key.
+            if *algorithm != Algorithm::A256GCM {
+                bail!("sample keyprovider only supports A256GCM");
+            }
+            Ok((crypto::HARDCODED_KEY.to_vec(), iv, HARD_CODED_KEYID.into()))
         }
         // use input key and randomly generated iv
-        false => match &input_params.keypath {
-            Some(kpath) => {
-                debug!("use given key from: {kpath}");
-                let key = fs::read(kpath).await.context("read Key file failed")?;
-                let mut iv = [0; 12];
-                rand::rngs::OsRng.fill_bytes(&mut iv);
-                let kid = match &input_params.keyid {
-                    Some(kid) => kid.to_string(),
-                    None => {
-                        debug!("no kid input, generate a random kid");
-                        let tag = uuid::Uuid::new_v4().to_string();
-                        format!("{DEFAULT_KEY_REPO_PATH}/{tag}")
-                    }
-                };
-
-                Ok((key.to_vec(), iv.to_vec(), kid))
-            }
-            None => {
-                debug!("no key input, generate a random key");
-
-                let mut iv = [0; 12];
-                rand::rngs::OsRng.fill_bytes(&mut iv);
-
-                let mut key = [0; 32];
-                rand::rngs::OsRng.fill_bytes(&mut key);
-
-                let kid = match &input_params.keyid {
-                    Some(kid) => kid.to_string(),
-                    None => {
-                        debug!("no kid input, generate a random kid");
-                        let tag = uuid::Uuid::new_v4().to_string();
-                        format!("{DEFAULT_KEY_REPO_PATH}/{tag}")
-                    }
-                };
-                Ok((key.to_vec(), iv.to_vec(), kid))
-            }
-        },
+        false => {
+            rand::rngs::OsRng.fill_bytes(&mut iv);
+            let key = match &input_params.keypath {
+                Some(kpath) => {
+                    debug!("use given key from: {kpath}");
+                    fs::read(kpath).await.context("read Key file failed")?
+                }
+                None => {
+                    debug!("no key input, generate a random key");
+                    let mut key = vec![0; algorithm.key_length()];
+                    rand::rngs::OsRng.fill_bytes(&mut key);
+                    key
+                }
+            };
+
+            let kid = match &input_params.keyid {
+                Some(kid) => kid.to_string(),
+                None => {
+                    debug!("no kid input, generate a random kid");
+                    let tag = uuid::Uuid::new_v4().to_string();
+                    format!("{DEFAULT_KEY_REPO_PATH}/{tag}")
+                }
+            };
+            Ok((key, iv, kid))
+        }
     }
 }
 
@@ -171,6 +224,27 @@ fn normalize_path(keyid: &str) -> Result<(String, String)> {
     }
 }
 
+/// Split `key` into `count` shares, any `threshold` of which reconstruct it.
+fn split_key(key: &[u8], threshold: u8, count: usize) -> Vec<Vec<u8>> {
+    Sharks(threshold)
+        .dealer(key)
+        .take(count)
+        .map(|share| Vec::from(&share))
+        .collect()
+}
+
+/// URL of the KBS named by `kbs_addr` in a resource URI, reached with the
+/// scheme of the configured KBS, which is used if `kbs_addr` is empty.
+fn share_kbs_url(kbs: &Url, kbs_addr: &str) -> Result<Url> {
+    if kbs_addr.is_empty() {
+        return Ok(kbs.clone());
+    }
+
+    format!("{}://{kbs_addr}", kbs.scheme())
+        .parse()
+        .context(format!("Illegal KBS address {kbs_addr}"))
+}
+
 /// The input params vector should only have one element.
 /// The format of the element is in the following format:
 /// ```plaintext
@@ -184,9 +258,13 @@ fn normalize_path(keyid: &str) -> Result<(String, String)> {
 /// | sample    | `true` or `false`                    | Whether this image is encrypted by sample key provider. By default `false`                       |
 /// | keyid     | a KBS Resource URI, s.t. `kbs://..`  | Specify the KEK of this image. keyid field will be included in AnnotationPacket                  |
 /// | keypath   | path to the KEK, e.g. `/home/key`    | Specify the KEK to encrypted the image in local filesystem                                       |
-/// | algorithm | `A256GCM` or `A256CTR`               | Encryption algorithm, included in the `wrap_type` field of AnnotationPacket. By default `A256GCM`|
+/// | algorithm | `A256GCM`, `A256CTR`, `A256CTR-HS256`, `A128GCM`, `C20P`, `XC20P`, `A256GCMSIV` or `A256KW` | Encryption algorithm, included in the `wrap_type` field of AnnotationPacket. By default `A256GCM`|
+/// | aad       | any string, e.g. a layer digest      | Additional data authenticated with the wrapped data, included in the `aad` field of AnnotationPacket. Not supported by `A256CTR` and `A256KW`|
+/// | shares    | comma-separated KBS Resource URIs    | Split the KEK with Shamir secret sharing and register one share at each URI instead of the KEK. Needs `threshold`, `--kbs` and `--auth-private-key`|
+/// | threshold | a number, e.g. `2`                   | Number of the `shares` needed to reconstruct the KEK                                             |
 pub async fn enc_optsdata_gen_anno(
     kbs_parameter: (&Option<Url>, &Option<Ed25519KeyPair>),
+    http_client: &reqwest::Client,
     optsdata: &[u8],
     params: Vec<String>,
 ) -> Result<String> {
@@ -198,13 +276,44 @@ pub async fn enc_optsdata_gen_anno(
     let (kbs_addr, k_path) = normalize_path(&kid)?;
 
     let algorithm = input_params.algorithm;
-    let encrypt_optsdata = crypto::encrypt(optsdata, &key, &iv, &algorithm)
+    if algorithm == Algorithm::A256CTR {
+        warn!("A256CTR does not detect tampering, and may be rejected by the attestation agent. Use A256CTR-HS256 instead.");
+    }
+    let aad = input_params.aad.unwrap_or_default();
+    let encrypt_optsdata = crypto::encrypt(optsdata, &key, &iv, aad.as_bytes(), &algorithm)
         .map_err(|e| anyhow!("Encrypt failed: {:?}", e))?;
 
+    let key_shares = if input_params.shares.is_empty() {
+        None
+    } else {
+        let (Some(addr), Some(private_key)) = kbs_parameter else {
+            bail!("Splitting the KEK needs --kbs and --auth-private-key");
+        };
+
+        // The KBSes are expected to trust the same authentication key.
+        let splits = split_key(&key, input_params.threshold, input_params.shares.len());
+        let mut shares = Vec::new();
+        for (share, uri) in splits.into_iter().zip(&input_params.shares) {
+            let (share_addr, share_path) = normalize_path(uri)?;
+            let url = share_kbs_url(addr, &share_addr)?;
+            register_kek(http_client, private_key, &url, share, &share_path)
+                .await
+                .context(format!("register key share {uri} failed"))?;
+            shares.push(format!(
+                "{KBS_RESOURCE_URL_PREFIX}{share_addr}/{share_path}"
+            ));
+        }
+        info!("register {} key shares succeeded.", shares.len());
+        Some(KeyShares {
+            threshold: input_params.threshold,
+            shares,
+        })
+    };
+
     if let (Some(addr), Some(private_key)) = kbs_parameter {
-        if !input_params.sample {
+        if !input_params.sample && key_shares.is_none() {
             // We do not register KEK for sample kbc
-            register_kek(private_key, addr, key, &k_path)
+            register_kek(http_client, private_key, addr, key, &k_path)
                 .await
                 .context("register KEK failed")?;
             info!("register KEK succeeded.");
@@ -216,6 +325,8 @@ pub async fn enc_optsdata_gen_anno(
         wrapped_data: base64::encode(encrypt_optsdata),
         iv: base64::encode(iv),
         wrap_type: algorithm.to_string(),
+        aad: (!aad.is_empty()).then(|| base64::encode(aad)),
+        key_shares,
     };
 
     serde_json::to_string(&annotation).map_err(|_| anyhow!("Serialize annotation failed"))
@@ -235,4 +346,58 @@ mod tests {
         assert_eq!(res.0, expected.0);
         assert_eq!(res.1, expected.1);
     }
+
+    #[rstest]
+    #[case("keypath=/key::algorithm=C20P", Some(crate::enc_mods::Algorithm::C20P))]
+    #[case("keypath=/key", Some(crate::enc_mods::Algorithm::A256GCM))]
+    #[case("algorithm=A512GCM", None)]
+    fn test_parse_algorithm(
+        #[case] input: &str,
+        #[case] expected: Option<crate::enc_mods::Algorithm>,
+    ) {
+        let res = crate::enc_mods::parse_input_params(input).map(|params| params.algorithm);
+        assert_eq!(res.ok(), expected);
+    }
+
+    #[rstest]
+    #[case("shares=kbs://a:1/b/c/d,kbs://e:1/b/c/d::threshold=2", Some((2, 2)))]
+    #[case("shares=kbs://a:1/b/c/d,kbs://e:1/b/c/d::threshold=1", Some((1, 2)))]
+    #[case("keypath=/key", Some((0, 0)))]
+    #[case("shares=kbs://a:1/b/c/d,kbs://e:1/b/c/d::threshold=3", None)]
+    #[case("shares=kbs://a:1/b/c/d,kbs://e:1/b/c/d", None)]
+    #[case("threshold=2", None)]
+    #[case("shares=kbs://a:1/b/c/d::threshold=one", None)]
+    #[case("shares=kbs://a:1/b/c/d::threshold=1::sample=true", None)]
+    fn test_parse_shares(#[case] input: &str, #[case] expected: Option<(u8, usize)>) {
+        let res = crate::enc_mods::parse_input_params(input)
+            .map(|params| (params.threshold, params.shares.len()));
+        assert_eq!(res.ok(), expected);
+    }
+
+    #[rstest]
+    #[case(2, &[0, 2], true)]
+    #[case(2, &[1, 0, 2], true)]
+    #[case(3, &[0, 1, 2], true)]
+    #[case(3, &[0, 1], false)]
+    fn test_split_key(#[case] threshold: u8, #[case] picked: &[usize], #[case] recovered: bool) {
+        let key: Vec<u8> = (0..32).collect();
+        let shares = crate::enc_mods::split_key(&key, threshold, 3);
+        let picked: Vec<sharks::Share> = picked
+            .iter()
+            .map(|i| sharks::Share::try_from(&shares[*i][..]).unwrap())
+            .collect();
+        let res = sharks::Sharks(threshold)
+            .recover(&picked)
+            .map_err(String::from);
+        assert_eq!(res.ok(), recovered.then_some(key));
+    }
+
+    #[rstest]
+    #[case("", "https://kbs.example.org:8080/")]
+    #[case("kbs2.example.org:9090", "https://kbs2.example.org:9090/")]
+    fn test_share_kbs_url(#[case] kbs_addr: &str, #[case] expected: &str) {
+        let kbs = "https://kbs.example.org:8080".parse().unwrap();
+        let res = crate::enc_mods::share_kbs_url(&kbs, kbs_addr).expect("share KBS URL failed");
+        assert_eq!(res.as_str(), expected);
+    }
 }
@@ -3,7 +3,7 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
-use crate::enc_mods;
+use crate::enc_mods::{self, ProxyConfig};
 use anyhow::*;
 use jwt_simple::prelude::Ed25519KeyPair;
 use log::*;
@@ -28,10 +28,15 @@ pub mod key_provider {
 pub struct KeyProvider {
     auth_private_key: Option<Ed25519KeyPair>,
     kbs: Option<Url>,
+    http_client: reqwest::Client,
 }
 
 impl KeyProvider {
-    pub fn new(auth_private_key: Option<Ed25519KeyPair>, kbs: Option<String>) -> Result<Self> {
+    pub fn new(
+        auth_private_key: Option<Ed25519KeyPair>,
+        kbs: Option<String>,
+        proxy: &ProxyConfig,
+    ) -> Result<Self> {
         let kbs = match kbs {
             Some(addr) => addr.parse().ok(),
             None => None,
@@ -40,6 +45,7 @@ impl KeyProvider {
         Ok(Self {
             auth_private_key,
             kbs,
+            http_client: enc_mods::build_http_client(proxy)?,
         })
     }
 }
@@ -91,6 +97,7 @@ impl KeyProviderService for KeyProvider {
 
         let annotation: String = enc_mods::enc_optsdata_gen_anno(
             (&self.kbs, &self.auth_private_key),
+            &self.http_client,
             &base64::decode(optsdata).map_err(|_| Status::aborted("base64 decode"))?,
             params,
         )
@@ -135,6 +142,7 @@ pub async fn start_service(
     socket: SocketAddr,
     auth_private_key: Option<PathBuf>,
     kbs: Option<String>,
+    proxy: ProxyConfig,
 ) -> Result<()> {
     let auth_private_key = match auth_private_key {
         Some(key_path) => {
@@ -151,6 +159,7 @@ pub async fn start_service(
         .add_service(KeyProviderServiceServer::new(KeyProvider::new(
             auth_private_key,
             kbs,
+            &proxy,
         )?))
         .serve(socket)
         .await?;
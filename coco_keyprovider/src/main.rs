@@ -4,19 +4,21 @@
 //
 
 use anyhow::*;
-use clap::{arg, command, Parser};
+use clap::{arg, command, Parser, Subcommand};
 use log::*;
 use std::{net::SocketAddr, path::PathBuf};
 
 pub mod enc_mods;
 pub mod grpc;
+pub mod seal;
 
 #[derive(Debug, Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
-    /// Socket address (IP:port) to listen to, e.g. 127.0.0.1:50000.
-    #[arg(required = true, short, long)]
-    socket: SocketAddr,
+    /// Socket address (IP:port) to listen to, e.g. 127.0.0.1:50000. Required
+    /// unless a subcommand is run.
+    #[arg(short, long)]
+    socket: Option<SocketAddr>,
 
     /// Private key used to authenticate the resource registration endpoint token (JWT)
     /// to Key Broker Service. This key can sign legal JWTs. If both `kbs`
@@ -30,6 +32,19 @@ struct Cli {
     /// will be automatically registered into the KBS.
     #[arg(long)]
     kbs: Option<String>,
+
+    #[command(flatten)]
+    proxy: enc_mods::ProxyConfig,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Seal a key or resource file of the offline file system KBC, so
+    /// that it is encrypted at rest.
+    SealFile(seal::SealFile),
 }
 
 #[tokio::main]
@@ -38,8 +53,13 @@ async fn main() -> Result<()> {
 
     let cli = Cli::parse();
 
+    if let Some(Command::SealFile(seal_file)) = &cli.command {
+        return seal_file.run().await;
+    }
+
+    let socket = cli.socket.ok_or_else(|| anyhow!("--socket is required"))?;
     debug!("starting keyprovider gRPC service...");
-    info!("listening to socket addr: {:?}", cli.socket);
+    info!("listening to socket addr: {:?}", socket);
 
     if cli.auth_private_key.is_some() && cli.kbs.is_some() {
         info!(
@@ -48,7 +68,7 @@ async fn main() -> Result<()> {
         );
     }
 
-    grpc::start_service(cli.socket, cli.auth_private_key, cli.kbs).await?;
+    grpc::start_service(socket, cli.auth_private_key, cli.kbs, cli.proxy).await?;
 
     Ok(())
 }
@@ -0,0 +1,245 @@
+// Copyright (c) 2023 Alibaba Cloud
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Seal the key and resource files of the offline file system KBC, so that
+//! they are encrypted at rest. A file is sealed either with a key derived
+//! from a passphrase with Argon2id and A256GCM, or for age X25519
+//! recipients.
+
+use std::{io::Write, path::PathBuf};
+
+use aes_gcm::{aead::Aead, Aes256Gcm, KeyInit};
+use anyhow::*;
+use argon2::{Argon2, Params};
+use clap::Args;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+/// `sealed` of a file sealed with a passphrase.
+pub const SEALED_ARGON2ID_A256GCM: &str = "argon2id-A256GCM";
+
+/// A file sealed with a key derived from a passphrase.
+#[derive(Serialize, Deserialize)]
+pub struct SealedFile {
+    pub sealed: String,
+    // Argon2id memory size in KiB
+    pub m_cost: u32,
+    // Argon2id number of iterations
+    pub t_cost: u32,
+    // Argon2id degree of parallelism
+    pub p_cost: u32,
+    // Argon2id salt (base64-encoded)
+    pub salt: String,
+    // A256GCM nonce (base64-encoded)
+    pub iv: String,
+    // A256GCM ciphertext and tag of the file (base64-encoded)
+    pub ciphertext: String,
+}
+
+/// Seal a key or resource file of the offline file system KBC.
+#[derive(Args, Debug)]
+pub struct SealFile {
+    /// Plaintext file to seal, s.t. `aa-offline_fs_kbc-keys.json`.
+    #[arg(short, long)]
+    input: PathBuf,
+
+    /// Sealed file to write.
+    #[arg(short, long)]
+    output: PathBuf,
+
+    /// File holding the passphrase to derive the key from with Argon2id.
+    /// One trailing newline of the file is not part of the passphrase.
+    #[arg(long, required_unless_present = "age_recipient")]
+    passphrase_file: Option<PathBuf>,
+
+    /// age X25519 recipient to seal the file for, s.t. `age1...`. May be
+    /// repeated, any of the recipients' identities then unseals the file.
+    #[arg(long, conflicts_with = "passphrase_file")]
+    age_recipient: Vec<String>,
+
+    /// Argon2id memory size in KiB.
+    #[arg(long, default_value_t = 65536)]
+    m_cost: u32,
+
+    /// Argon2id number of iterations.
+    #[arg(long, default_value_t = 3)]
+    t_cost: u32,
+
+    /// Argon2id degree of parallelism.
+    #[arg(long, default_value_t = 1)]
+    p_cost: u32,
+}
+
+impl SealFile {
+    pub async fn run(&self) -> Result<()> {
+        let plaintext = fs::read(&self.input)
+            .await
+            .with_context(|| format!("read {} failed", self.input.display()))?;
+
+        let sealed = match &self.passphrase_file {
+            Some(passphrase_file) => {
+                let content = fs::read(passphrase_file)
+                    .await
+                    .with_context(|| format!("read {} failed", passphrase_file.display()))?;
+                let params = Params::new(self.m_cost, self.t_cost, self.p_cost, Some(32))
+                    .map_err(|e| anyhow!("Illegal Argon2id parameters: {e}"))?;
+                seal_with_passphrase(&plaintext, trim_passphrase(&content), params)?
+            }
+            None => seal_for_age_recipients(&plaintext, &self.age_recipient)?,
+        };
+
+        fs::write(&self.output, sealed)
+            .await
+            .with_context(|| format!("write {} failed", self.output.display()))
+    }
+}
+
+/// The passphrase of a passphrase file, without one trailing `\n` or `\r\n`
+/// as editors and `echo` append, which could not be passed on the kernel
+/// command line.
+fn trim_passphrase(content: &[u8]) -> &[u8] {
+    match content.strip_suffix(b"\n") {
+        Some(line) => line.strip_suffix(b"\r").unwrap_or(line),
+        None => content,
+    }
+}
+
+/// Seal `plaintext` with a key derived from `passphrase` by Argon2id.
+pub fn seal_with_passphrase(
+    plaintext: &[u8],
+    passphrase: &[u8],
+    params: Params,
+) -> Result<Vec<u8>> {
+    let mut salt = [0; 16];
+    let mut iv = [0; 12];
+    rand::rngs::OsRng.fill_bytes(&mut salt);
+    rand::rngs::OsRng.fill_bytes(&mut iv);
+
+    let mut key = [0; 32];
+    Argon2::new(
+        argon2::Algorithm::Argon2id,
+        argon2::Version::V0x13,
+        params.clone(),
+    )
+    .hash_password_into(passphrase, &salt, &mut key)
+    .map_err(|e| anyhow!("Argon2id failed: {e}"))?;
+    let ciphertext = Aes256Gcm::new_from_slice(&key)?
+        .encrypt(&iv.into(), plaintext)
+        .map_err(|e| anyhow!("Seal failed: {e}"))?;
+
+    let sealed = SealedFile {
+        sealed: SEALED_ARGON2ID_A256GCM.to_string(),
+        m_cost: params.m_cost(),
+        t_cost: params.t_cost(),
+        p_cost: params.p_cost(),
+        salt: base64::encode(salt),
+        iv: base64::encode(iv),
+        ciphertext: base64::encode(ciphertext),
+    };
+    Ok(serde_json::to_vec(&sealed)?)
+}
+
+/// Seal `plaintext` in the age format for the X25519 `recipients`.
+pub fn seal_for_age_recipients(plaintext: &[u8], recipients: &[String]) -> Result<Vec<u8>> {
+    let recipients = recipients
+        .iter()
+        .map(|recipient| {
+            recipient
+                .parse::<age::x25519::Recipient>()
+                .map(|recipient| Box::new(recipient) as Box<dyn age::Recipient + Send>)
+                .map_err(|e| anyhow!("Illegal age recipient {recipient}: {e}"))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let encryptor =
+        age::Encryptor::with_recipients(recipients).ok_or_else(|| anyhow!("No age recipient"))?;
+
+    let mut sealed = Vec::new();
+    let mut writer = encryptor.wrap_output(&mut sealed)?;
+    writer.write_all(plaintext)?;
+    writer.finish()?;
+    Ok(sealed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use age::secrecy::ExposeSecret;
+    use rstest::rstest;
+
+    use super::*;
+
+    const PLAINTEXT: &[u8] = br#"{"default/key/1":"MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY="}"#;
+
+    fn unseal_with_passphrase(sealed: &[u8], passphrase: &[u8]) -> Result<Vec<u8>> {
+        let sealed: SealedFile = serde_json::from_slice(sealed)?;
+        assert_eq!(sealed.sealed, SEALED_ARGON2ID_A256GCM);
+        let params = Params::new(sealed.m_cost, sealed.t_cost, sealed.p_cost, Some(32)).unwrap();
+        let mut key = [0; 32];
+        Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+            .hash_password_into(passphrase, &base64::decode(sealed.salt)?, &mut key)
+            .unwrap();
+        Aes256Gcm::new_from_slice(&key)?
+            .decrypt(
+                base64::decode(sealed.iv)?.as_slice().into(),
+                base64::decode(sealed.ciphertext)?.as_slice(),
+            )
+            .map_err(|e| anyhow!("{e}"))
+    }
+
+    #[test]
+    fn passphrase() {
+        let params = Params::new(1024, 1, 1, Some(32)).unwrap();
+        let sealed = seal_with_passphrase(PLAINTEXT, b"passphrase", params).unwrap();
+        assert_eq!(
+            unseal_with_passphrase(&sealed, b"passphrase").unwrap(),
+            PLAINTEXT
+        );
+        assert!(unseal_with_passphrase(&sealed, b"wrong passphrase").is_err());
+    }
+
+    #[rstest]
+    #[case(b"passphrase", b"passphrase")]
+    #[case(b"passphrase\n", b"passphrase")]
+    #[case(b"passphrase\r\n", b"passphrase")]
+    #[case(b"passphrase\n\n", b"passphrase\n")]
+    #[case(b"pass\nphrase", b"pass\nphrase")]
+    #[case(b"\n", b"")]
+    fn passphrase_file(#[case] content: &[u8], #[case] expected: &[u8]) {
+        assert_eq!(trim_passphrase(content), expected);
+    }
+
+    #[test]
+    fn age_recipients() {
+        let identities = [
+            age::x25519::Identity::generate(),
+            age::x25519::Identity::generate(),
+        ];
+        let recipients: Vec<String> = identities
+            .iter()
+            .map(|identity| identity.to_public().to_string())
+            .collect();
+        let sealed = seal_for_age_recipients(PLAINTEXT, &recipients).unwrap();
+
+        for identity in &identities {
+            let identity: age::x25519::Identity =
+                identity.to_string().expose_secret().parse().unwrap();
+            let age::Decryptor::Recipients(decryptor) = age::Decryptor::new(&sealed[..]).unwrap()
+            else {
+                panic!("not sealed for recipients");
+            };
+            let mut plaintext = Vec::new();
+            decryptor
+                .decrypt(std::iter::once(&identity as &dyn age::Identity))
+                .unwrap()
+                .read_to_end(&mut plaintext)
+                .unwrap();
+            assert_eq!(plaintext, PLAINTEXT);
+        }
+
+        assert!(seal_for_age_recipients(PLAINTEXT, &["age1foo".to_string()]).is_err());
+    }
+}
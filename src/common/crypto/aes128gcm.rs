@@ -0,0 +1,72 @@
+// Copyright (c) 2023 Alibaba Cloud
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! This mod implements aes-128-gcm decryption.
+
+use anyhow::*;
+
+#[cfg(all(feature = "rust-crypto", not(feature = "openssl")))]
+use aes_gcm::{
+    aead::{Aead, Payload},
+    Aes128Gcm, KeyInit, Nonce,
+};
+
+#[cfg(all(feature = "rust-crypto", not(feature = "openssl")))]
+pub fn decrypt(encrypted_data: &[u8], key: &[u8], iv: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    if iv.len() != 12 {
+        bail!("Illegal length of aes-128-gcm IV");
+    }
+
+    let cipher = Aes128Gcm::new_from_slice(key).context("Illegal length of aes-128-gcm key")?;
+    cipher
+        .decrypt(
+            Nonce::from_slice(iv),
+            Payload {
+                msg: encrypted_data,
+                aad,
+            },
+        )
+        .map_err(|e| anyhow!("aes-128-gcm decrypt failed: {:?}", e))
+}
+
+#[cfg(feature = "openssl")]
+use openssl::symm::Cipher;
+
+#[cfg(feature = "openssl")]
+const TAG_LENGTH: usize = 16;
+
+#[cfg(feature = "openssl")]
+pub fn decrypt(encrypted_data: &[u8], key: &[u8], iv: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    let cipher = Cipher::aes_128_gcm();
+    if encrypted_data.len() < TAG_LENGTH {
+        bail!("Illegal length of ciphertext");
+    }
+
+    let (data, tag) = encrypted_data.split_at(encrypted_data.len() - TAG_LENGTH);
+    openssl::symm::decrypt_aead(cipher, key, Some(iv), aad, data, tag)
+        .map_err(|e| anyhow!(e.to_string()))
+}
+
+#[cfg(all(feature = "rust-crypto", feature = "openssl"))]
+#[cfg(test)]
+mod tests {
+    use aes_gcm::{
+        aead::{Aead, OsRng},
+        Aes128Gcm, KeyInit, Nonce,
+    };
+
+    #[test]
+    fn compatible_with_openssl() {
+        let plaintext = b"plaintext message";
+        let key = Aes128Gcm::generate_key(&mut OsRng);
+        let nonce = Nonce::from_slice(b"unique nonce");
+        let ciphertext = Aes128Gcm::new(&key)
+            .encrypt(nonce, plaintext.as_ref())
+            .expect("encryption failed");
+
+        let decrypted = super::decrypt(&ciphertext, &key, nonce, &[]).expect("decrypt failed");
+        assert_eq!(decrypted, plaintext);
+    }
+}
@@ -8,7 +8,11 @@
 use anyhow::*;
 
 #[cfg(all(feature = "rust-crypto", not(feature = "openssl")))]
-pub fn decrypt(encrypted_data: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>> {
+pub fn decrypt(encrypted_data: &[u8], key: &[u8], iv: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    if !aad.is_empty() {
+        bail!("aes-256-ctr does not authenticate AAD");
+    }
+
     use aes_gcm::aes::Aes256;
     use ctr::{
         cipher::{KeyIvInit, StreamCipher},
@@ -28,7 +32,11 @@ pub fn decrypt(encrypted_data: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>>
 use openssl::symm::Cipher;
 
 #[cfg(feature = "openssl")]
-pub fn decrypt(encrypted_data: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>> {
+pub fn decrypt(encrypted_data: &[u8], key: &[u8], iv: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    if !aad.is_empty() {
+        bail!("aes-256-ctr does not authenticate AAD");
+    }
+
     let cipher = Cipher::aes_256_ctr();
 
     openssl::symm::decrypt(cipher, key, Some(iv), encrypted_data)
@@ -57,7 +65,7 @@ mod tests {
             .apply_keystream_b2b(plaintext, &mut cipher_text)
             .expect("encryption failed");
 
-        let decrypted = super::decrypt(&cipher_text, &key, &iv).expect("decrypt failed");
+        let decrypted = super::decrypt(&cipher_text, &key, &iv, &[]).expect("decrypt failed");
         assert_eq!(decrypted, plaintext);
     }
 }
@@ -0,0 +1,98 @@
+// Copyright (c) 2023 Alibaba Cloud
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! This mod implements aes-256-ctr with an HMAC-SHA-256 tag, encrypt-then-MAC
+//! like the `A128CBC-HS256` composition of RFC 7518 section 5.2.2:
+//!
+//! - The 64 bytes key is `MAC_KEY | ENC_KEY`, 32 bytes each.
+//! - `wrapped_data := ciphertext | Tag`, where `ciphertext` is aes-256-ctr
+//!   under `ENC_KEY` and the 16 bytes `iv`, and `Tag` is the first 16 bytes
+//!   of HMAC-SHA-256 under `MAC_KEY` of `AAD | iv | ciphertext | AL`, with
+//!   the length in bits `AL` of `AAD` as a 64-bit big endian integer.
+
+use anyhow::*;
+
+const KEY_LENGTH: usize = 64;
+const IV_LENGTH: usize = 16;
+const TAG_LENGTH: usize = 16;
+
+pub fn decrypt(encrypted_data: &[u8], key: &[u8], iv: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    if key.len() != KEY_LENGTH {
+        bail!("Illegal length of aes-256-ctr-hs256 key");
+    }
+    if iv.len() != IV_LENGTH {
+        bail!("Illegal length of aes-256-ctr-hs256 IV");
+    }
+    if encrypted_data.len() < TAG_LENGTH {
+        bail!("Illegal length of ciphertext");
+    }
+
+    let (mac_key, enc_key) = key.split_at(KEY_LENGTH / 2);
+    let (data, tag) = encrypted_data.split_at(encrypted_data.len() - TAG_LENGTH);
+    let aad_bits = (aad.len() as u64 * 8).to_be_bytes();
+    let mac_input = [aad, iv, data, &aad_bits].concat();
+    if !verify(mac_key, &mac_input, tag)? {
+        bail!("aes-256-ctr-hs256 decrypt failed: tag mismatch");
+    }
+
+    super::aes256ctr::decrypt(data, enc_key, iv, &[])
+}
+
+#[cfg(all(feature = "rust-crypto", not(feature = "openssl")))]
+fn verify(mac_key: &[u8], mac_input: &[u8], tag: &[u8]) -> Result<bool> {
+    use hmac::{Hmac, Mac};
+
+    let mut mac = Hmac::<sha2::Sha256>::new_from_slice(mac_key)?;
+    mac.update(mac_input);
+    Ok(mac.verify_truncated_left(tag).is_ok())
+}
+
+#[cfg(feature = "openssl")]
+fn verify(mac_key: &[u8], mac_input: &[u8], tag: &[u8]) -> Result<bool> {
+    use openssl::{hash::MessageDigest, pkey::PKey, sign::Signer};
+
+    let key = PKey::hmac(mac_key)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(mac_input)?;
+    let expected = signer.sign_to_vec()?;
+    Ok(openssl::memcmp::eq(&expected[..TAG_LENGTH], tag))
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    const PLAINTEXT: &[u8] = br#"{"optsdata":"plaintext message"}"#;
+    const CIPHERTEXT: &str = "e5ebb3bedd86d547ce7f5442f1f34522263b6d8b3ac0a986c579a03b3d86b200a09af9c3616e55b5d6ffd1fe26f5f085";
+
+    #[rstest]
+    #[case(None, true)]
+    #[case(Some(0), false)]
+    #[case(Some(31), false)]
+    #[case(Some(40), false)]
+    fn decrypt(#[case] flipped: Option<usize>, #[case] accepted: bool) {
+        let key: Vec<u8> = (0..64).collect();
+        let iv: Vec<u8> = (0x40..0x50).collect();
+        let mut ciphertext = hex::decode(CIPHERTEXT).unwrap();
+        if let Some(i) = flipped {
+            ciphertext[i] ^= 1;
+        }
+
+        let res = super::decrypt(&ciphertext, &key, &iv, &[]);
+        if accepted {
+            assert_eq!(res.expect("decrypt failed"), PLAINTEXT);
+        } else {
+            assert!(res.is_err());
+        }
+    }
+
+    #[test]
+    fn illegal_lengths() {
+        let ciphertext = hex::decode(CIPHERTEXT).unwrap();
+        assert!(super::decrypt(&ciphertext, &[0; 32], &[0; 16], &[]).is_err());
+        assert!(super::decrypt(&ciphertext, &[0; 64], &[0; 12], &[]).is_err());
+        assert!(super::decrypt(&ciphertext[..8], &[0; 64], &[0; 16], &[]).is_err());
+    }
+}
@@ -8,15 +8,27 @@
 use anyhow::*;
 
 #[cfg(all(feature = "rust-crypto", not(feature = "openssl")))]
-use aes_gcm::{aead::Aead, Aes256Gcm, Key, KeyInit, Nonce};
+use aes_gcm::{
+    aead::{Aead, Payload},
+    Aes256Gcm, KeyInit, Nonce,
+};
 
 #[cfg(all(feature = "rust-crypto", not(feature = "openssl")))]
-pub fn decrypt(encrypted_data: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>> {
-    let decrypting_key = Key::<Aes256Gcm>::from_slice(key);
-    let cipher = Aes256Gcm::new(decrypting_key);
+pub fn decrypt(encrypted_data: &[u8], key: &[u8], iv: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    if iv.len() != 12 {
+        bail!("Illegal length of aes-256-gcm IV");
+    }
+
+    let cipher = Aes256Gcm::new_from_slice(key).context("Illegal length of aes-256-gcm key")?;
     let nonce = Nonce::from_slice(iv);
     let plain_text = cipher
-        .decrypt(nonce, encrypted_data)
+        .decrypt(
+            nonce,
+            Payload {
+                msg: encrypted_data,
+                aad,
+            },
+        )
         .map_err(|e| anyhow!("aes-256-gcm decrypt failed: {:?}", e))?;
 
     Ok(plain_text)
@@ -29,14 +41,14 @@ use openssl::symm::Cipher;
 const TAG_LENGTH: usize = 16;
 
 #[cfg(feature = "openssl")]
-pub fn decrypt(encrypted_data: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>> {
+pub fn decrypt(encrypted_data: &[u8], key: &[u8], iv: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
     let cipher = Cipher::aes_256_gcm();
     if encrypted_data.len() < TAG_LENGTH {
         bail!("Illegal length of ciphertext");
     }
 
     let (data, tag) = encrypted_data.split_at(encrypted_data.len() - TAG_LENGTH);
-    openssl::symm::decrypt_aead(cipher, key, Some(iv), &[], data, tag)
+    openssl::symm::decrypt_aead(cipher, key, Some(iv), aad, data, tag)
         .map_err(|e| anyhow!(e.to_string()))
 }
 
@@ -59,7 +71,7 @@ mod tests {
             .encrypt(nonce, plaintext.as_ref())
             .expect("encryption failed");
 
-        let decrypted = super::decrypt(&ciphertext, &keyu8, &nonce).expect("decrypt failed");
+        let decrypted = super::decrypt(&ciphertext, &keyu8, &nonce, &[]).expect("decrypt failed");
         assert_eq!(decrypted, plaintext);
     }
 }
@@ -0,0 +1,96 @@
+// Copyright (c) 2023 Alibaba Cloud
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! This mod implements aes-256-gcm-siv (RFC 8452) decryption, which unlike
+//! aes-256-gcm does not leak the key stream if an IV is reused.
+
+use anyhow::*;
+
+const TAG_LENGTH: usize = 16;
+
+#[cfg(all(feature = "rust-crypto", not(feature = "openssl")))]
+use aes_gcm_siv::{
+    aead::{Aead, Payload},
+    Aes256GcmSiv, KeyInit, Nonce,
+};
+
+#[cfg(all(feature = "rust-crypto", not(feature = "openssl")))]
+pub fn decrypt(encrypted_data: &[u8], key: &[u8], iv: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    if iv.len() != 12 {
+        bail!("Illegal length of aes-256-gcm-siv IV");
+    }
+    if encrypted_data.len() < TAG_LENGTH {
+        bail!("Illegal length of ciphertext");
+    }
+
+    let cipher =
+        Aes256GcmSiv::new_from_slice(key).context("Illegal length of aes-256-gcm-siv key")?;
+    cipher
+        .decrypt(
+            Nonce::from_slice(iv),
+            Payload {
+                msg: encrypted_data,
+                aad,
+            },
+        )
+        .map_err(|e| anyhow!("aes-256-gcm-siv decrypt failed: {:?}", e))
+}
+
+#[cfg(feature = "openssl")]
+use openssl::{cipher::Cipher, cipher_ctx::CipherCtx};
+
+// AES-GCM-SIV is provided by OpenSSL 3.2 and later.
+#[cfg(feature = "openssl")]
+pub fn decrypt(encrypted_data: &[u8], key: &[u8], iv: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    if encrypted_data.len() < TAG_LENGTH {
+        bail!("Illegal length of ciphertext");
+    }
+
+    let (data, tag) = encrypted_data.split_at(encrypted_data.len() - TAG_LENGTH);
+    let decrypt = || -> Result<Vec<u8>, openssl::error::ErrorStack> {
+        let cipher = Cipher::fetch(None, "AES-256-GCM-SIV", None)?;
+        let mut ctx = CipherCtx::new()?;
+        ctx.decrypt_init(Some(&cipher), None, None)?;
+        ctx.set_iv_length(iv.len())?;
+        ctx.decrypt_init(None, Some(key), Some(iv))?;
+        // The tag is checked while decrypting, which is done at once.
+        ctx.set_tag(tag)?;
+        if !aad.is_empty() {
+            ctx.cipher_update(aad, None)?;
+        }
+        let mut plaintext = Vec::new();
+        ctx.cipher_update_vec(data, &mut plaintext)?;
+        ctx.cipher_final_vec(&mut plaintext)?;
+        std::result::Result::Ok(plaintext)
+    };
+
+    decrypt().map_err(|e| anyhow!("aes-256-gcm-siv decrypt failed: {e}"))
+}
+
+#[cfg(all(feature = "rust-crypto", feature = "openssl"))]
+#[cfg(test)]
+mod tests {
+    use aes_gcm_siv::{
+        aead::{Aead, OsRng},
+        Aes256GcmSiv, KeyInit, Nonce,
+    };
+
+    #[test]
+    fn compatible_with_openssl() {
+        let plaintext = b"plaintext message";
+        let key = Aes256GcmSiv::generate_key(&mut OsRng);
+        let nonce = Nonce::from_slice(b"unique nonce");
+        let ciphertext = Aes256GcmSiv::new(&key)
+            .encrypt(nonce, plaintext.as_ref())
+            .expect("encryption failed");
+
+        let decrypted = super::decrypt(&ciphertext, &key, nonce, &[]).expect("decrypt failed");
+        assert_eq!(decrypted, plaintext);
+
+        let mut tampered = ciphertext;
+        tampered[0] ^= 1;
+        assert!(super::decrypt(&tampered, &key, nonce, &[]).is_err());
+    }
+}
@@ -0,0 +1,72 @@
+// Copyright (c) 2023 Alibaba Cloud
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! This mod implements aes-256 key wrap (RFC 3394) unwrapping. It takes no
+//! IV, and the wrapped data is a multiple of 8 bytes, at least 16.
+
+use anyhow::*;
+
+#[cfg(all(feature = "rust-crypto", not(feature = "openssl")))]
+pub fn decrypt(encrypted_data: &[u8], key: &[u8], iv: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    use aes_kw::KekAes256;
+
+    if !iv.is_empty() {
+        bail!("aes-256 key wrap takes no IV");
+    }
+    if !aad.is_empty() {
+        bail!("aes-256 key wrap does not authenticate AAD");
+    }
+
+    let kek = KekAes256::try_from(key).map_err(|_| anyhow!("Illegal length of aes-256 kek"))?;
+    kek.unwrap_vec(encrypted_data)
+        .map_err(|e| anyhow!("aes-256 key unwrap failed: {:?}", e))
+}
+
+#[cfg(feature = "openssl")]
+pub fn decrypt(encrypted_data: &[u8], key: &[u8], iv: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    use openssl::aes::{unwrap_key, AesKey};
+
+    if !iv.is_empty() {
+        bail!("aes-256 key wrap takes no IV");
+    }
+    if !aad.is_empty() {
+        bail!("aes-256 key wrap does not authenticate AAD");
+    }
+    if key.len() != 32 {
+        bail!("Illegal length of aes-256 kek");
+    }
+    // Other lengths are rejected by `unwrap_key`.
+    if encrypted_data.len() < 24 {
+        bail!("Illegal length of ciphertext");
+    }
+
+    let kek = AesKey::new_decrypt(key).map_err(|e| anyhow!("Invalid aes-256 kek: {:?}", e))?;
+    let mut plaintext = vec![0; encrypted_data.len() - 8];
+    unwrap_key(&kek, None, &mut plaintext, encrypted_data)
+        .map_err(|e| anyhow!("aes-256 key unwrap failed: {:?}", e))?;
+    Ok(plaintext)
+}
+
+#[cfg(all(feature = "rust-crypto", feature = "openssl"))]
+#[cfg(test)]
+mod tests {
+    use aes_kw::KekAes256;
+
+    #[test]
+    fn compatible_with_openssl() {
+        let plaintext = b"plaintext message padded to 32 B";
+        let key = [0x42; 32];
+        let ciphertext = KekAes256::from(key)
+            .wrap_vec(plaintext)
+            .expect("encryption failed");
+
+        let decrypted = super::decrypt(&ciphertext, &key, &[], &[]).expect("decrypt failed");
+        assert_eq!(decrypted, plaintext);
+
+        let mut tampered = ciphertext;
+        tampered[0] ^= 1;
+        assert!(super::decrypt(&tampered, &key, &[], &[]).is_err());
+    }
+}
@@ -0,0 +1,73 @@
+// Copyright (c) 2023 Alibaba Cloud
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! This mod implements chacha20-poly1305 (RFC 8439) decryption.
+
+use anyhow::*;
+
+#[cfg(all(feature = "rust-crypto", not(feature = "openssl")))]
+use chacha20poly1305::{
+    aead::{Aead, Payload},
+    ChaCha20Poly1305, KeyInit, Nonce,
+};
+
+#[cfg(all(feature = "rust-crypto", not(feature = "openssl")))]
+pub fn decrypt(encrypted_data: &[u8], key: &[u8], iv: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    if iv.len() != 12 {
+        bail!("Illegal length of chacha20-poly1305 IV");
+    }
+
+    let cipher =
+        ChaCha20Poly1305::new_from_slice(key).context("Illegal length of chacha20-poly1305 key")?;
+    cipher
+        .decrypt(
+            Nonce::from_slice(iv),
+            Payload {
+                msg: encrypted_data,
+                aad,
+            },
+        )
+        .map_err(|e| anyhow!("chacha20-poly1305 decrypt failed: {:?}", e))
+}
+
+#[cfg(feature = "openssl")]
+use openssl::symm::Cipher;
+
+#[cfg(feature = "openssl")]
+const TAG_LENGTH: usize = 16;
+
+#[cfg(feature = "openssl")]
+pub fn decrypt(encrypted_data: &[u8], key: &[u8], iv: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    let cipher = Cipher::chacha20_poly1305();
+    if encrypted_data.len() < TAG_LENGTH {
+        bail!("Illegal length of ciphertext");
+    }
+
+    let (data, tag) = encrypted_data.split_at(encrypted_data.len() - TAG_LENGTH);
+    openssl::symm::decrypt_aead(cipher, key, Some(iv), aad, data, tag)
+        .map_err(|e| anyhow!(e.to_string()))
+}
+
+#[cfg(all(feature = "rust-crypto", feature = "openssl"))]
+#[cfg(test)]
+mod tests {
+    use chacha20poly1305::{
+        aead::{Aead, OsRng},
+        ChaCha20Poly1305, KeyInit, Nonce,
+    };
+
+    #[test]
+    fn compatible_with_openssl() {
+        let plaintext = b"plaintext message";
+        let key = ChaCha20Poly1305::generate_key(&mut OsRng);
+        let nonce = Nonce::from_slice(b"unique nonce");
+        let ciphertext = ChaCha20Poly1305::new(&key)
+            .encrypt(nonce, plaintext.as_ref())
+            .expect("encryption failed");
+
+        let decrypted = super::decrypt(&ciphertext, &key, nonce, &[]).expect("decrypt failed");
+        assert_eq!(decrypted, plaintext);
+    }
+}
@@ -8,12 +8,22 @@ use std::str::FromStr;
 use anyhow::*;
 use zeroize::Zeroizing;
 
+mod aes128gcm;
 mod aes256ctr;
+mod aes256ctrhs256;
 mod aes256gcm;
+mod aes256gcmsiv;
+mod aes256kw;
+mod chacha20poly1305;
+#[cfg(feature = "key_shares")]
+mod shamir;
+mod xchacha20poly1305;
+
+#[cfg(feature = "key_shares")]
+pub use shamir::combine_key_shares;
 
 /// Supported WrapType, s.t. encryption algorithm using to encrypt the
 /// [PLBCO](https://github.com/confidential-containers/attestation-agent/blob/main/docs/IMPLEMENTATION.md#encryption-and-decryption-of-container-image).
-/// TODO: Support more kinds of en/decryption schemes.
 #[derive(EnumString, AsRefStr)]
 pub enum WrapType {
     /// The serialized name follows 5.2.6 section
@@ -21,18 +31,58 @@ pub enum WrapType {
     #[strum(serialize = "A256GCM")]
     Aes256Gcm,
 
-    /// This type is not recommended as it is not AEAD.
+    /// This type is not recommended as it is not AEAD, use `A256CTR-HS256`
+    /// instead. It is rejected if the agent refuses unauthenticated wrap
+    /// types.
     #[strum(serialize = "A256CTR")]
     Aes256Ctr,
+
+    /// AES-256-CTR authenticated with HMAC-SHA-256, with a 64 bytes key.
+    #[strum(serialize = "A256CTR-HS256")]
+    Aes256CtrHs256,
+
+    /// AES-GCM with a 128-bit key.
+    #[strum(serialize = "A128GCM")]
+    Aes128Gcm,
+
+    /// ChaCha20-Poly1305 of RFC 8439, with a 96-bit IV.
+    #[strum(serialize = "C20P")]
+    ChaCha20Poly1305,
+
+    /// XChaCha20-Poly1305, with a 192-bit IV which may be random.
+    #[strum(serialize = "XC20P")]
+    XChaCha20Poly1305,
+
+    /// AES-GCM-SIV of RFC 8452, which resists IV reuse.
+    #[strum(serialize = "A256GCMSIV")]
+    Aes256GcmSiv,
+
+    /// AES Key Wrap of RFC 3394, with an empty IV. The PLBCO must be padded
+    /// to a multiple of 8 bytes.
+    #[strum(serialize = "A256KW")]
+    Aes256Kw,
 }
 
-type DecryptorFunc = Box<dyn Fn(&[u8], &[u8], &[u8]) -> Result<Vec<u8>>>;
+impl WrapType {
+    /// Whether tampering with the wrapped data is detected.
+    pub fn is_authenticated(&self) -> bool {
+        !matches!(self, WrapType::Aes256Ctr)
+    }
+}
+
+type DecryptorFunc = Box<dyn Fn(&[u8], &[u8], &[u8], &[u8]) -> Result<Vec<u8>>>;
 
 impl From<WrapType> for DecryptorFunc {
     fn from(wt: WrapType) -> Self {
         match wt {
             WrapType::Aes256Gcm => Box::new(aes256gcm::decrypt),
             WrapType::Aes256Ctr => Box::new(aes256ctr::decrypt),
+            WrapType::Aes256CtrHs256 => Box::new(aes256ctrhs256::decrypt),
+            WrapType::Aes128Gcm => Box::new(aes128gcm::decrypt),
+            WrapType::ChaCha20Poly1305 => Box::new(chacha20poly1305::decrypt),
+            WrapType::XChaCha20Poly1305 => Box::new(xchacha20poly1305::decrypt),
+            WrapType::Aes256GcmSiv => Box::new(aes256gcmsiv::decrypt),
+            WrapType::Aes256Kw => Box::new(aes256kw::decrypt),
         }
     }
 }
@@ -42,13 +92,77 @@ pub fn decrypt(
     ciphertext: Vec<u8>,
     iv: Vec<u8>,
     wrap_type: &str,
+) -> Result<Vec<u8>> {
+    decrypt_with_aad(key, ciphertext, iv, &[], wrap_type)
+}
+
+/// Decrypt `ciphertext`, authenticating the additional data `aad` with it.
+/// Wrap types which cannot authenticate it, s.t. `A256CTR` and `A256KW`,
+/// fail if `aad` is not empty.
+pub fn decrypt_with_aad(
+    key: Zeroizing<Vec<u8>>,
+    ciphertext: Vec<u8>,
+    iv: Vec<u8>,
+    aad: &[u8],
+    wrap_type: &str,
 ) -> Result<Vec<u8>> {
     let wrap_type = WrapType::from_str(wrap_type).context(format!(
         "Unsupported wrap type {wrap_type} when decrypt image layer",
     ))?;
 
     let decryptor: DecryptorFunc = wrap_type.into();
-    let plaintext = decryptor(&ciphertext, &key, &iv)?;
+    let plaintext = decryptor(&ciphertext, &key, &iv, aad)?;
 
     Ok(plaintext)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    const PLAINTEXT: &[u8] = br#"{"optsdata":"plaintext message"}"#;
+    const AAD: &[u8] = b"sha256:0123456789abcdef";
+
+    #[rstest]
+    #[case("A256GCM", 32, 12, "999bc153524fe362b9a5350cb9147f3a10a82639cf412f4e12343d84f076cfa6c59505c6db72b52858011024ec30fdce")]
+    #[case("A128GCM", 16, 12, "420b06ba19389dd1f8d86125dc5711992513cd0e65a6b5f3de33bb401bb92174fa724ec3cdc358589693d4137baa9734")]
+    #[case("C20P", 32, 12, "837613f106398a602caed43a999f01acdda2177d3c8861029b7fa13a8d421817396888c57478bd35ec9add0c4762000e")]
+    #[case("A256GCMSIV", 32, 12, "aa276887294479e9126e0fb26250dcbcb29617a4d8f3220d85edca28ef8bd097bc3e46eb5b34a52879fdf6f94be0dc1e")]
+    #[case("A256CTR-HS256", 64, 16, "e5ebb3bedd86d547ce7f5442f1f34522263b6d8b3ac0a986c579a03b3d86b200748a2a64f0b1fbd0383c38c8619f4e75")]
+    fn aad_binding(
+        #[case] wrap_type: &str,
+        #[case] key_length: u8,
+        #[case] iv_length: u8,
+        #[case] ciphertext: &str,
+    ) {
+        let key = Zeroizing::new((0..key_length).collect::<Vec<u8>>());
+        let iv: Vec<u8> = (0x40..0x40 + iv_length).collect();
+        let ciphertext = hex::decode(ciphertext).unwrap();
+        let decrypt = |aad: &[u8]| {
+            decrypt_with_aad(key.clone(), ciphertext.clone(), iv.clone(), aad, wrap_type)
+        };
+
+        assert_eq!(decrypt(AAD).expect("decrypt failed"), PLAINTEXT);
+        assert!(decrypt(b"sha256:fedcba9876543210").is_err());
+        assert!(decrypt(&[]).is_err());
+    }
+
+    #[rstest]
+    #[case("A256CTR", 32, 16)]
+    #[case("A256KW", 32, 0)]
+    fn aad_unsupported(
+        #[case] wrap_type: &str,
+        #[case] key_length: usize,
+        #[case] iv_length: usize,
+    ) {
+        let res = decrypt_with_aad(
+            Zeroizing::new(vec![0; key_length]),
+            vec![0; 24],
+            vec![0; iv_length],
+            AAD,
+            wrap_type,
+        );
+        assert!(res.unwrap_err().to_string().contains("AAD"));
+    }
+}
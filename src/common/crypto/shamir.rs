@@ -0,0 +1,74 @@
+// Copyright (c) 2023 Alibaba Cloud
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! This mod implements the reconstruction of a key split with Shamir secret
+//! sharing over GF(256). Each share is `x | y`, where `x` is the non-zero
+//! x-coordinate of the share and `y` has one byte per byte of the key.
+
+use std::collections::HashSet;
+
+use anyhow::*;
+use sharks::{Share, Sharks};
+use zeroize::Zeroizing;
+
+/// Reconstruct the key from `threshold` distinct shares. Extra shares are
+/// ignored.
+pub fn combine_key_shares(
+    threshold: u8,
+    shares: &[Zeroizing<Vec<u8>>],
+) -> Result<Zeroizing<Vec<u8>>> {
+    if threshold == 0 {
+        bail!("Illegal key share threshold 0");
+    }
+
+    let mut xs = HashSet::new();
+    let mut parsed = Vec::new();
+    for share in shares.iter().take(threshold as usize) {
+        let share =
+            Share::try_from(share.as_slice()).map_err(|e| anyhow!("Invalid key share: {e}"))?;
+        if share.x.0 == 0 || !xs.insert(share.x.0) {
+            bail!("Invalid or duplicated key share x-coordinate {}", share.x.0);
+        }
+        parsed.push(share);
+    }
+
+    let key = Sharks(threshold)
+        .recover(&parsed)
+        .map_err(|e| anyhow!("Combine key shares failed: {e}"))?;
+    Ok(Zeroizing::new(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case(2, &[0, 1], true)]
+    #[case(2, &[2, 0], true)]
+    #[case(2, &[0, 1, 2], true)]
+    #[case(2, &[1], false)]
+    #[case(2, &[1, 1], false)]
+    #[case(3, &[0, 2], false)]
+    fn combine(#[case] threshold: u8, #[case] picked: &[usize], #[case] recovered: bool) {
+        let key: Vec<u8> = (0..32).collect();
+        let shares: Vec<Zeroizing<Vec<u8>>> = Sharks(threshold)
+            .dealer(&key)
+            .take(3)
+            .map(|share| Zeroizing::new(Vec::from(&share)))
+            .collect();
+        let picked: Vec<_> = picked.iter().map(|i| shares[*i].clone()).collect();
+
+        let res = combine_key_shares(threshold, &picked);
+        assert_eq!(res.ok().map(|key| key.to_vec()), recovered.then_some(key));
+    }
+
+    #[test]
+    fn illegal_share() {
+        let share = Zeroizing::new(vec![0, 1, 2]);
+        assert!(combine_key_shares(1, &[share]).is_err());
+        assert!(combine_key_shares(0, &[]).is_err());
+    }
+}
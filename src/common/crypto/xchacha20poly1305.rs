@@ -0,0 +1,137 @@
+// Copyright (c) 2023 Alibaba Cloud
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! This mod implements xchacha20-poly1305 decryption, chacha20-poly1305
+//! with a 192-bit IV which can safely be chosen at random.
+
+use anyhow::*;
+
+#[cfg(all(feature = "rust-crypto", not(feature = "openssl")))]
+use chacha20poly1305::{
+    aead::{Aead, Payload},
+    KeyInit, XChaCha20Poly1305, XNonce,
+};
+
+#[cfg(all(feature = "rust-crypto", not(feature = "openssl")))]
+pub fn decrypt(encrypted_data: &[u8], key: &[u8], iv: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    if iv.len() != 24 {
+        bail!("Illegal length of xchacha20-poly1305 IV");
+    }
+
+    let cipher = XChaCha20Poly1305::new_from_slice(key)
+        .context("Illegal length of xchacha20-poly1305 key")?;
+    cipher
+        .decrypt(
+            XNonce::from_slice(iv),
+            Payload {
+                msg: encrypted_data,
+                aad,
+            },
+        )
+        .map_err(|e| anyhow!("xchacha20-poly1305 decrypt failed: {:?}", e))
+}
+
+// OpenSSL has no XChaCha20, which is chacha20-poly1305 with a subkey derived
+// by HChaCha20 from the first 16 bytes of the IV, and the last 8 bytes of the
+// IV as nonce (draft-irtf-cfrg-xchacha section 2.3).
+#[cfg(feature = "openssl")]
+pub fn decrypt(encrypted_data: &[u8], key: &[u8], iv: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    let key: &[u8; 32] = key
+        .try_into()
+        .map_err(|_| anyhow!("Illegal length of xchacha20-poly1305 key"))?;
+    if iv.len() != 24 {
+        bail!("Illegal length of xchacha20-poly1305 IV");
+    }
+
+    let subkey = zeroize::Zeroizing::new(hchacha20(key, iv[..16].try_into()?));
+    let mut nonce = [0; 12];
+    nonce[4..].copy_from_slice(&iv[16..]);
+    super::chacha20poly1305::decrypt(encrypted_data, subkey.as_ref(), &nonce, aad)
+}
+
+#[cfg(feature = "openssl")]
+fn hchacha20(key: &[u8; 32], input: &[u8; 16]) -> [u8; 32] {
+    fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+        state[a] = state[a].wrapping_add(state[b]);
+        state[d] = (state[d] ^ state[a]).rotate_left(16);
+        state[c] = state[c].wrapping_add(state[d]);
+        state[b] = (state[b] ^ state[c]).rotate_left(12);
+        state[a] = state[a].wrapping_add(state[b]);
+        state[d] = (state[d] ^ state[a]).rotate_left(8);
+        state[c] = state[c].wrapping_add(state[d]);
+        state[b] = (state[b] ^ state[c]).rotate_left(7);
+    }
+
+    let word = |bytes: &[u8]| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    let mut state = [0u32; 16];
+    state[..4].copy_from_slice(&[0x61707865, 0x3320646e, 0x79622d32, 0x6b206574]);
+    for (i, bytes) in key.chunks_exact(4).enumerate() {
+        state[4 + i] = word(bytes);
+    }
+    for (i, bytes) in input.chunks_exact(4).enumerate() {
+        state[12 + i] = word(bytes);
+    }
+
+    for _ in 0..10 {
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+
+    let mut subkey = [0; 32];
+    for (i, word) in state[..4].iter().chain(&state[12..]).enumerate() {
+        subkey[4 * i..4 * i + 4].copy_from_slice(&word.to_le_bytes());
+    }
+    subkey
+}
+
+#[cfg(feature = "openssl")]
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn hchacha20() {
+        // draft-irtf-cfrg-xchacha section 2.2.1
+        let key: Vec<u8> = (0..32).collect();
+        let input = hex::decode("000000090000004a0000000031415927").unwrap();
+        let subkey = super::hchacha20(
+            key.as_slice().try_into().unwrap(),
+            input.as_slice().try_into().unwrap(),
+        );
+        assert_eq!(
+            hex::encode(subkey),
+            "82413b4227b27bfed30e42508a877d73a0f9e4d58a74a853c12ec41326d3ecdc"
+        );
+    }
+
+    #[cfg(feature = "rust-crypto")]
+    #[test]
+    fn compatible_with_openssl() {
+        use chacha20poly1305::{
+            aead::{Aead, AeadCore, OsRng, Payload},
+            KeyInit, XChaCha20Poly1305,
+        };
+
+        let plaintext = b"plaintext message";
+        let aad = b"sha256:0123456789abcdef";
+        let key = XChaCha20Poly1305::generate_key(&mut OsRng);
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: plaintext,
+            aad,
+        };
+        let ciphertext = XChaCha20Poly1305::new(&key)
+            .encrypt(&nonce, payload)
+            .expect("encryption failed");
+
+        let decrypted = super::decrypt(&ciphertext, &key, &nonce, aad).expect("decrypt failed");
+        assert_eq!(decrypted, plaintext);
+        assert!(super::decrypt(&ciphertext, &key, &nonce, &[]).is_err());
+    }
+}
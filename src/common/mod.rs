@@ -4,4 +4,5 @@
 //
 
 pub mod crypto;
+#[cfg(any(feature = "offline_sev_kbc", feature = "online_sev_kbc"))]
 pub mod sev;
@@ -0,0 +1,153 @@
+// Copyright (c) 2023 Alibaba Cloud
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Parser of the raw EFI secret area injected into SEV guests, as laid out
+//! by OVMF and read by the `efi_secret` kernel module:
+//!
+//! ```text
+//!   Offset   Length
+//!   (bytes)  (bytes)  Usage
+//!   -------  -------  -----
+//!         0       16  Secret table header GUID
+//!        16        4  Length of bytes of the entire secret table
+//!
+//!        20       16  First secret entry's GUID
+//!        36        4  First secret entry's length in bytes (= 16 + 4 + x)
+//!        40        x  First secret entry's data
+//!       ...
+//! ```
+//!
+//! GUIDs are in the mixed-endian EFI byte order and lengths little-endian.
+//! Entries with the nil GUID were removed, and are skipped.
+
+use anyhow::*;
+use std::collections::HashMap;
+use uuid::{uuid, Uuid};
+use zeroize::Zeroizing;
+
+use super::SecretStore;
+
+pub const SECRET_TABLE_HEADER_GUID: Uuid = uuid!("1e74f542-71dd-4d66-963e-ef4287ff173b");
+
+const GUID_LEN: usize = 16;
+const HEADER_LEN: usize = GUID_LEN + 4;
+
+/// Secrets of an EFI secret area, each taken at most once.
+pub struct EfiSecretArea {
+    secrets: HashMap<Uuid, Zeroizing<Vec<u8>>>,
+}
+
+impl EfiSecretArea {
+    /// Parse the secret table at the start of `area`. Bytes after the table
+    /// are ignored.
+    pub fn parse(area: &[u8]) -> Result<EfiSecretArea> {
+        let (guid, table_len) =
+            read_header(area).ok_or_else(|| anyhow!("EFI secret area too short"))?;
+        if guid != SECRET_TABLE_HEADER_GUID {
+            bail!("No EFI secret table, header GUID {guid}");
+        }
+        if table_len < HEADER_LEN || table_len > area.len() {
+            bail!(
+                "Illegal EFI secret table length {table_len} in area of {} bytes",
+                area.len()
+            );
+        }
+
+        let mut secrets = HashMap::new();
+        let mut offset = HEADER_LEN;
+        while offset < table_len {
+            let (guid, entry_len) = read_header(&area[offset..table_len])
+                .ok_or_else(|| anyhow!("EFI secret entry at offset {offset} truncated"))?;
+            if entry_len < HEADER_LEN || entry_len > table_len - offset {
+                bail!("Illegal EFI secret entry length {entry_len} at offset {offset}");
+            }
+
+            if !guid.is_nil() {
+                let data = area[offset + HEADER_LEN..offset + entry_len].to_vec();
+                if secrets.insert(guid, Zeroizing::new(data)).is_some() {
+                    bail!("Duplicate EFI secret entry {guid}");
+                }
+            }
+            offset += entry_len;
+        }
+
+        Ok(EfiSecretArea { secrets })
+    }
+
+    /// GUIDs of the secrets not taken yet.
+    pub fn guids(&self) -> Vec<Uuid> {
+        self.secrets.keys().copied().collect()
+    }
+}
+
+impl SecretStore for EfiSecretArea {
+    fn take(&mut self, guid: &Uuid) -> Result<Zeroizing<Vec<u8>>> {
+        self.secrets
+            .remove(guid)
+            .ok_or_else(|| anyhow!("No EFI secret {guid}"))
+    }
+}
+
+// GUID and length of the table header or of an entry.
+fn read_header(bytes: &[u8]) -> Option<(Uuid, usize)> {
+    let guid = Uuid::from_bytes_le(bytes.get(..GUID_LEN)?.try_into().ok()?);
+    let len = u32::from_le_bytes(bytes.get(GUID_LEN..HEADER_LEN)?.try_into().ok()?);
+    Some((guid, len as usize))
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+    use crate::common::sev::fixtures::{OFFLINE_SEV_GUID, ONLINE_SEV_GUID, SECRET_AREA};
+
+    #[test]
+    fn parse_fixture() {
+        let mut area = EfiSecretArea::parse(SECRET_AREA).expect("parse failed");
+        let mut guids = area.guids();
+        guids.sort();
+        assert_eq!(guids, vec![ONLINE_SEV_GUID, OFFLINE_SEV_GUID]);
+
+        let secret = area.take(&OFFLINE_SEV_GUID).expect("take failed");
+        assert!(secret.starts_with(br#"{"keys":"#));
+        // Secrets are taken once.
+        assert!(area.take(&OFFLINE_SEV_GUID).is_err());
+        assert!(area.take(&Uuid::nil()).is_err());
+    }
+
+    fn set_u32(area: &mut [u8], offset: usize, value: u32) {
+        area[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    #[rstest]
+    // Not the header GUID
+    #[case(|area: &mut Vec<u8>| area[0] ^= 1)]
+    // Table longer than the area
+    #[case(|area: &mut Vec<u8>| set_u32(area, 16, 4097))]
+    // Table shorter than its header
+    #[case(|area: &mut Vec<u8>| set_u32(area, 16, 19))]
+    // Table ending within the header of an entry
+    #[case(|area: &mut Vec<u8>| set_u32(area, 16, 30))]
+    // Entry shorter than its header
+    #[case(|area: &mut Vec<u8>| set_u32(area, 36, 19))]
+    // Entry leaving the table
+    #[case(|area: &mut Vec<u8>| set_u32(area, 36, 4096))]
+    // Area shorter than a header
+    #[case(|area: &mut Vec<u8>| area.truncate(10))]
+    fn corrupted(#[case] corrupt: fn(&mut Vec<u8>)) {
+        let mut area = SECRET_AREA.to_vec();
+        corrupt(&mut area);
+        assert!(EfiSecretArea::parse(&area).is_err());
+    }
+
+    #[test]
+    fn empty_table() {
+        let mut area = SECRET_AREA[..HEADER_LEN].to_vec();
+        set_u32(&mut area, 16, HEADER_LEN as u32);
+        let area = EfiSecretArea::parse(&area).expect("parse failed");
+        assert!(area.guids().is_empty());
+    }
+}
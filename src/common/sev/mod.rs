@@ -3,47 +3,35 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
-use anyhow::{anyhow, Result};
-use std::process::Command;
+//! Secrets injected into SEV guests at boot. They are read either from
+//! securityfs, where the `efi_secret` kernel module exposes them as files
+//! named by their GUID, or parsed from a raw EFI secret area.
 
-const SECRET_MODULE_NAME: &str = "efi_secret";
-const MODPROBE_PATH: &str = "/sbin/modprobe";
-const MOUNT_PATH: &str = "/bin/mount";
+use anyhow::Result;
+use uuid::Uuid;
+use zeroize::Zeroizing;
 
-pub struct SecretKernelModule;
+mod efi_secret;
+mod securityfs;
 
-impl SecretKernelModule {
-    pub fn new() -> Result<SecretKernelModule> {
-        if !Command::new(MODPROBE_PATH)
-            .arg(SECRET_MODULE_NAME)
-            .status()?
-            .success()
-        {
-            return Err(anyhow!("Failed to load secret module."));
-        }
-        Ok(SecretKernelModule {})
-    }
-}
-impl Drop for SecretKernelModule {
-    fn drop(&mut self) {
-        Command::new(MODPROBE_PATH)
-            .arg("-r")
-            .arg(SECRET_MODULE_NAME)
-            .status()
-            .expect("Failed to unload secret module.");
-    }
+pub use efi_secret::{EfiSecretArea, SECRET_TABLE_HEADER_GUID};
+pub use securityfs::{SecretKernelModule, SecurityFs};
+
+/// Injected secrets, each taken at most once.
+pub trait SecretStore {
+    /// Take the secret `guid`, which is removed from the store even if it
+    /// cannot be read, so that it is not exposed any longer.
+    fn take(&mut self, guid: &Uuid) -> Result<Zeroizing<Vec<u8>>>;
 }
 
-pub fn mount_security_fs() -> Result<()> {
-    if !Command::new(MOUNT_PATH)
-        .arg("-t")
-        .arg("securityfs")
-        .arg("securityfs")
-        .arg("/sys/kernel/security")
-        .status()?
-        .success()
-    {
-        return Err(anyhow!("Failed to mount security fs"));
-    }
-    Ok(())
+/// Secrets shared by the tests of the SEV secret stores and KBCs.
+#[cfg(test)]
+pub mod fixtures {
+    use uuid::{uuid, Uuid};
+
+    /// Secret area with a removed entry, the secret of the offline SEV KBC
+    /// and the connection of the online SEV KBC, padded to a page.
+    pub const SECRET_AREA: &[u8] = include_bytes!("secret_area.bin");
+    pub const OFFLINE_SEV_GUID: Uuid = uuid!("e6f5a162-d67f-4750-a67c-5d065f2a9910");
+    pub const ONLINE_SEV_GUID: Uuid = uuid!("1ee27366-0c87-43a6-af48-28543eaf7cb0");
 }
@@ -0,0 +1,249 @@
+// Copyright (c) 2022 IBM Corp.
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Secrets exposed in securityfs by the `efi_secret` kernel module.
+//! securityfs is mounted and the module loaded with direct syscalls, so that
+//! the guest needs neither `mount` nor `modprobe`.
+
+use anyhow::*;
+use std::{
+    ffi::CString,
+    fs::{self, File},
+    io,
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+};
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+use super::SecretStore;
+
+const SECURITYFS_PATH: &str = "/sys/kernel/security";
+const SECRETS_PATH: &str = "/sys/kernel/security/secrets/coco";
+const SECRET_MODULE_NAME: &str = "efi_secret";
+const SECRET_MODULE_NAME_NUL: &[u8] = b"efi_secret\0";
+const MODULES_PATH: &str = "/lib/modules";
+const OSRELEASE_PATH: &str = "/proc/sys/kernel/osrelease";
+
+// `finit_module` flag of a compressed module file
+const MODULE_INIT_COMPRESSED_FILE: libc::c_uint = 4;
+
+/// Secrets of the files of a securityfs directory.
+pub struct SecurityFs {
+    dir: PathBuf,
+    // Module exposing the secrets, unloaded when the secrets are dropped
+    _module: Option<SecretKernelModule>,
+}
+
+impl SecurityFs {
+    /// Mount securityfs and load the `efi_secret` module, unless the
+    /// secrets are already exposed.
+    pub fn open() -> Result<SecurityFs> {
+        mount_security_fs()?;
+        let module = match Path::new(SECRETS_PATH).is_dir() {
+            true => None,
+            false => Some(SecretKernelModule::new()?),
+        };
+
+        Ok(SecurityFs {
+            dir: PathBuf::from(SECRETS_PATH),
+            _module: module,
+        })
+    }
+
+    /// Secrets of the files of `dir`, with no module to unload.
+    pub fn with_dir(dir: impl Into<PathBuf>) -> SecurityFs {
+        SecurityFs {
+            dir: dir.into(),
+            _module: None,
+        }
+    }
+}
+
+impl SecretStore for SecurityFs {
+    fn take(&mut self, guid: &Uuid) -> Result<Zeroizing<Vec<u8>>> {
+        let path = self.dir.join(guid.hyphenated().to_string());
+        let secret = fs::read(&path).map(Zeroizing::new);
+        // The module wipes the secret from memory when its file is removed.
+        let removed = fs::remove_file(&path);
+
+        let secret = secret.with_context(|| format!("Read secret {} failed", path.display()))?;
+        removed.with_context(|| format!("Remove secret {} failed", path.display()))?;
+        Ok(secret)
+    }
+}
+
+/// The `efi_secret` kernel module, unloaded when dropped if it was loaded
+/// by [`SecretKernelModule::new`].
+pub struct SecretKernelModule {
+    loaded: bool,
+}
+
+impl SecretKernelModule {
+    pub fn new() -> Result<SecretKernelModule> {
+        let release = fs::read_to_string(OSRELEASE_PATH)
+            .with_context(|| format!("Read {OSRELEASE_PATH} failed"))?;
+        let modules_dir = Path::new(MODULES_PATH).join(release.trim());
+        let modules_dep = fs::read_to_string(modules_dir.join("modules.dep"))
+            .with_context(|| format!("Read modules.dep of {} failed", modules_dir.display()))?;
+        let (module, deps) = module_files(&modules_dep, SECRET_MODULE_NAME)
+            .ok_or_else(|| anyhow!("No {SECRET_MODULE_NAME} module in modules.dep"))?;
+
+        // The last dependency is loaded first.
+        for dep in deps.iter().rev() {
+            load_module(&modules_dir.join(dep))?;
+        }
+        let loaded = load_module(&modules_dir.join(module))?;
+        Ok(SecretKernelModule { loaded })
+    }
+}
+
+impl Drop for SecretKernelModule {
+    fn drop(&mut self) {
+        if !self.loaded {
+            return;
+        }
+
+        // SAFETY: the module name is a static NUL-terminated string.
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_delete_module,
+                SECRET_MODULE_NAME_NUL.as_ptr() as *const libc::c_char,
+                libc::O_NONBLOCK as libc::c_uint,
+            )
+        };
+        if ret != 0 {
+            log::error!(
+                "Failed to unload {SECRET_MODULE_NAME} module: {}",
+                io::Error::last_os_error()
+            );
+        }
+    }
+}
+
+fn mount_security_fs() -> Result<()> {
+    let securityfs = CString::new("securityfs")?;
+    let target = CString::new(SECURITYFS_PATH)?;
+    // SAFETY: the strings are NUL-terminated and live across the call, and
+    // securityfs takes no data.
+    let ret = unsafe {
+        libc::mount(
+            securityfs.as_ptr(),
+            target.as_ptr(),
+            securityfs.as_ptr(),
+            0,
+            std::ptr::null(),
+        )
+    };
+    if ret == 0 {
+        return Ok(());
+    }
+
+    let e = io::Error::last_os_error();
+    match e.raw_os_error() {
+        // Mounted already
+        Some(libc::EBUSY) => Ok(()),
+        _ => Err(anyhow!("Failed to mount security fs: {e}")),
+    }
+}
+
+// Load the module of the file `path`, or return false if it is loaded
+// already.
+fn load_module(path: &Path) -> Result<bool> {
+    let file =
+        File::open(path).with_context(|| format!("Open module {} failed", path.display()))?;
+    let flags = match path.extension() {
+        Some(extension) if extension != "ko" => MODULE_INIT_COMPRESSED_FILE,
+        _ => 0,
+    };
+    let params = CString::default();
+    // SAFETY: the file descriptor and `params` live across the call.
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_finit_module,
+            file.as_raw_fd(),
+            params.as_ptr(),
+            flags,
+        )
+    };
+    if ret == 0 {
+        return Ok(true);
+    }
+
+    let e = io::Error::last_os_error();
+    match e.raw_os_error() {
+        Some(libc::EEXIST) => Ok(false),
+        _ => Err(anyhow!("Failed to load module {}: {e}", path.display())),
+    }
+}
+
+// File of the module `name` and those of its dependencies, relative to the
+// modules directory, from the `modules.dep` listing them.
+fn module_files(modules_dep: &str, name: &str) -> Option<(PathBuf, Vec<PathBuf>)> {
+    let file_name = format!("{name}.ko");
+    modules_dep.lines().find_map(|line| {
+        let (module, deps) = line.split_once(':')?;
+        let module = Path::new(module.trim());
+        let module_name = module.file_name()?.to_str()?;
+        if module_name != file_name && !module_name.starts_with(&format!("{file_name}.")) {
+            return None;
+        }
+
+        let deps = deps.split_whitespace().map(PathBuf::from).collect();
+        Some((module.to_path_buf(), deps))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    const MODULES_DEP: &str = "\
+kernel/drivers/virt/coco/efi_secret/efi_secret_extra.ko:
+kernel/fs/efivarfs/efivarfs.ko.zst:
+kernel/drivers/virt/coco/efi_secret/efi_secret.ko.zst: kernel/b.ko kernel/a.ko.xz
+kernel/net/tls/tls.ko:
+";
+
+    #[rstest]
+    #[case("efi_secret", Some(("kernel/drivers/virt/coco/efi_secret/efi_secret.ko.zst", vec!["kernel/b.ko", "kernel/a.ko.xz"])))]
+    #[case("tls", Some(("kernel/net/tls/tls.ko", vec![])))]
+    #[case("efi", None)]
+    fn parse_modules_dep(#[case] name: &str, #[case] expected: Option<(&str, Vec<&str>)>) {
+        let expected = expected.map(|(module, deps)| {
+            (
+                PathBuf::from(module),
+                deps.into_iter().map(PathBuf::from).collect(),
+            )
+        });
+        assert_eq!(module_files(MODULES_DEP, name), expected);
+    }
+
+    #[test]
+    fn take_removes_secret() {
+        let dir = std::env::temp_dir().join("aa-sev-test_take_removes_secret");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let guid = Uuid::new_v4();
+        let path = dir.join(guid.hyphenated().to_string());
+        fs::write(&path, b"secret").unwrap();
+
+        let mut secrets = SecurityFs::with_dir(&dir);
+        assert_eq!(&secrets.take(&guid).expect("take failed")[..], b"secret");
+        assert!(!path.exists());
+
+        // Taken once
+        let err = secrets.take(&guid).unwrap_err();
+        assert!(err.to_string().contains(path.to_str().unwrap()));
+
+        // A secret which cannot be read is reported, not panicked on.
+        fs::create_dir(&path).unwrap();
+        assert!(secrets.take(&guid).is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
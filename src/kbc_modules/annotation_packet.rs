@@ -3,6 +3,7 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
+use anyhow::*;
 use serde::{Deserialize, Serialize};
 
 use super::uri::ResourceUri;
@@ -22,4 +23,108 @@ pub struct AnnotationPacket {
     pub iv: String,
     // Wrap type to specify encryption algorithm and mode
     pub wrap_type: String,
+    // Additional data authenticated with the wrapped data (base64-encoded),
+    // s.t. the digest of the layer, to bind it to its context
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aad: Option<String>,
+    // Shares of the KEK, if it is split across several KBSes. The kid then
+    // only names the split KEK and is not fetched itself
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_shares: Option<KeyShares>,
+}
+
+/// The KEK of an [`AnnotationPacket`] split with Shamir secret sharing, s.t.
+/// no single KBS can release it. Any `threshold` of the `shares` reconstruct
+/// the KEK.
+#[derive(Serialize, Deserialize)]
+pub struct KeyShares {
+    pub threshold: u8,
+    pub shares: Vec<ResourceUri>,
+}
+
+impl AnnotationPacket {
+    /// The additional authenticated data, empty if there is none.
+    pub fn aad(&self) -> Result<Vec<u8>> {
+        match &self.aad {
+            Some(aad) => base64::decode(aad).context("Invalid base64 aad of annotation"),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Whether the additional authenticated data is `expected`, s.t. the
+    /// digest of the layer being decrypted. Any is accepted if nothing is
+    /// expected.
+    pub fn is_bound_to(&self, expected: Option<&[u8]>) -> bool {
+        match expected {
+            Some(expected) => matches!(self.aad(), Result::Ok(aad) if aad == expected),
+            None => true,
+        }
+    }
+}
+
+/// `version` of a [`MultiRecipientAnnotation`].
+pub const MULTI_RECIPIENT_VERSION: u32 = 2;
+
+/// Annotation wrapping the same PLBCO for several recipients, s.t. a KBS in
+/// production and an offline key for disaster recovery, so that the image
+/// can be decrypted in each environment. Each recipient has the fields of an
+/// [`AnnotationPacket`], with its own kid, KBS and wrap type.
+#[derive(Serialize, Deserialize)]
+pub struct MultiRecipientAnnotation {
+    pub version: u32,
+    pub recipients: Vec<AnnotationPacket>,
+}
+
+/// The recipients of `annotation`, which is either a versioned
+/// [`MultiRecipientAnnotation`] or, without `version`, the original single
+/// recipient [`AnnotationPacket`].
+pub fn parse_recipients(annotation: &str) -> Result<Vec<AnnotationPacket>> {
+    // The kid only deserializes from a borrowed string, so that the
+    // annotation is parsed again once its version is known.
+    let value: serde_json::Value = serde_json::from_str(annotation)?;
+    match value.get("version") {
+        None => Ok(vec![serde_json::from_str(annotation)?]),
+        Some(version) if *version == MULTI_RECIPIENT_VERSION => {
+            let annotation: MultiRecipientAnnotation = serde_json::from_str(annotation)?;
+            if annotation.recipients.is_empty() {
+                bail!("Annotation has no recipient");
+            }
+            Ok(annotation.recipients)
+        }
+        Some(version) => bail!("Unsupported annotation version {version}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case(
+        r#"{"kid":"kbs:///default/key/1","wrapped_data":"","iv":"","wrap_type":"A256GCM"}"#,
+        Some(vec!["kbs:///default/key/1"])
+    )]
+    #[case(
+        r#"{"version":2,"recipients":[{"kid":"kbs://kbs.example.org:8080/default/key/1","wrapped_data":"","iv":"","wrap_type":"A256GCM"},{"kid":"kbs:///dr/key/1","wrapped_data":"","iv":"","wrap_type":"C20P"}]}"#,
+        Some(vec!["kbs://kbs.example.org:8080/default/key/1", "kbs:///dr/key/1"])
+    )]
+    #[case(r#"{"version":2,"recipients":[]}"#, None)]
+    #[case(
+        r#"{"version":3,"recipients":[{"kid":"kbs:///default/key/1","wrapped_data":"","iv":"","wrap_type":"A256GCM"}]}"#,
+        None
+    )]
+    #[case(r#"{"version":2,"kid":"kbs:///default/key/1"}"#, None)]
+    fn recipients(#[case] annotation: &str, #[case] kids: Option<Vec<&str>>) {
+        let res = parse_recipients(annotation).map(|recipients| {
+            recipients
+                .iter()
+                .map(|recipient| recipient.kid.whole_uri())
+                .collect::<Vec<_>>()
+        });
+        assert_eq!(
+            res.ok(),
+            kids.map(|kids| kids.iter().map(|kid| kid.to_string()).collect())
+        );
+    }
 }
@@ -0,0 +1,286 @@
+// Copyright (c) 2023 Alibaba Cloud
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! With channel binding, the evidence is bound to the `tls-exporter`
+//! (RFC 9266) of the TLS session that carries it to the KBS. The KBS HTTP
+//! client does not expose its TLS sessions, and may open a new connection
+//! for any request, s.t. once the KBS closed a kept alive one. The `auth`
+//! and `attest` requests are thus sent over a TLS connection owned by a
+//! [`BoundSession`], so that the evidence can only travel over the session
+//! it is bound to.
+
+use super::{config::KbsConfig, tls, KBS_USER_AGENT};
+use anyhow::*;
+use hyper::{
+    client::conn::http1::{self, SendRequest},
+    header::{CONTENT_TYPE, COOKIE, HOST, SET_COOKIE, USER_AGENT},
+    Body, StatusCode,
+};
+use reqwest::cookie::{CookieStore, Jar};
+use serde::Serialize;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio_rustls::{rustls::ServerName, TlsConnector};
+use url::Url;
+
+/// HTTP/1.1 connection over one TLS session with a KBS.
+pub struct BoundSession {
+    sender: SendRequest<Body>,
+    exporter: Vec<u8>,
+    // `Host` of the requests, the `<host>:<port>` of the KBS URL.
+    host: String,
+}
+
+impl BoundSession {
+    /// Connect to the `https://` KBS at `url`, with the TLS settings of
+    /// `config`.
+    pub async fn connect(url: &Url, config: &KbsConfig) -> Result<BoundSession> {
+        if url.scheme() != "https" {
+            bail!("Channel binding needs an https:// KBS, not {url}");
+        }
+
+        let host = url.host_str().unwrap_or_default();
+        let port = url.port_or_known_default().unwrap_or(443);
+        // Without the brackets of an IPv6 address
+        let name = host.trim_start_matches('[').trim_end_matches(']');
+        let server_name =
+            ServerName::try_from(name).map_err(|e| anyhow!("Invalid KBS host {host}: {e}"))?;
+
+        let stream = TcpStream::connect((name, port))
+            .await
+            .with_context(|| format!("Connect to KBS {url} failed"))?;
+        let stream = TlsConnector::from(Arc::new(tls::binding_config(config)?))
+            .connect(server_name, stream)
+            .await
+            .with_context(|| format!("TLS handshake with KBS {url} failed"))?;
+        let exporter = tls::exporter(stream.get_ref().1)?;
+
+        let (sender, connection) = http1::handshake(stream).await?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                log::debug!("CC-KBC: bound TLS session with the KBS failed: {e}");
+            }
+        });
+
+        let host = match url.port() {
+            Some(port) => format!("{host}:{port}"),
+            None => host.to_string(),
+        };
+        Ok(BoundSession {
+            sender,
+            exporter,
+            host,
+        })
+    }
+
+    /// `tls-exporter` channel binding of the session.
+    pub fn channel_binding(&self) -> &[u8] {
+        &self.exporter
+    }
+
+    /// POST `body` as JSON to `url` of the KBS over the session, with the
+    /// cookies of `cookies`, which are updated from the response.
+    pub async fn post_json(
+        &mut self,
+        url: &str,
+        body: &impl Serialize,
+        cookies: &Jar,
+    ) -> Result<(StatusCode, String)> {
+        let url = Url::parse(url)?;
+        let mut request = hyper::Request::post(&url[url::Position::BeforePath..])
+            .header(HOST, &self.host)
+            .header(CONTENT_TYPE, "application/json")
+            .header(USER_AGENT, KBS_USER_AGENT);
+        if let Some(cookie) = cookies.cookies(&url) {
+            request = request.header(COOKIE, cookie);
+        }
+        let request = request.body(Body::from(serde_json::to_vec(body)?))?;
+
+        self.sender.ready().await?;
+        let response = self.sender.send_request(request).await?;
+        cookies.set_cookies(&mut response.headers().get_all(SET_COOKIE).iter(), &url);
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        Ok((status, String::from_utf8_lossy(&body).to_string()))
+    }
+}
+
+/// Whether `error` is the KBS closing the session, after which attestation
+/// may start over on a new session.
+pub fn is_closed(error: &Error) -> bool {
+    error.chain().any(|cause| {
+        cause
+            .downcast_ref::<hyper::Error>()
+            .is_some_and(|e| e.is_closed() || e.is_incomplete_message() || e.is_canceled())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kbc_modules::cc_kbc::{
+        attester::sample::SampleAttester,
+        crypto::{hash_chunks, TeeKey, TeeKeyAlgorithm},
+        tls::tests::{LOCALHOST_CERT, LOCALHOST_KEY},
+        Kbc,
+    };
+    use serde_json::Value;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+        sync::mpsc,
+    };
+    use tokio_rustls::{rustls, TlsAcceptor};
+
+    // Head and body of the next HTTP/1.1 request of `stream`.
+    async fn read_request(stream: &mut (impl AsyncRead + Unpin)) -> Option<(String, Vec<u8>)> {
+        let mut stream = BufReader::new(stream);
+        let mut head = String::new();
+        loop {
+            let mut line = String::new();
+            if stream.read_line(&mut line).await.ok()? == 0 {
+                return None;
+            }
+            if line == "\r\n" {
+                break;
+            }
+            head.push_str(&line);
+        }
+
+        let len = head
+            .lines()
+            .find_map(|line| {
+                let (name, value) = line.split_once(": ")?;
+                name.eq_ignore_ascii_case("content-length")
+                    .then(|| value.trim().parse().ok())?
+            })
+            .unwrap_or(0);
+        let mut body = vec![0; len];
+        stream.read_exact(&mut body).await.ok()?;
+        Some((head, body))
+    }
+
+    fn response(status: &str, headers: &str, body: &str) -> String {
+        format!(
+            "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{headers}\r\n{body}",
+            body.len()
+        )
+    }
+
+    // TLS KBS closing its first connection right after `auth`, and only
+    // accepting evidence bound to the session that carries it. Each
+    // request line is sent back with the index of its connection.
+    async fn kbs() -> (u16, mpsc::UnboundedReceiver<(usize, String)>) {
+        let cert = rustls_pemfile::certs(&mut LOCALHOST_CERT.as_bytes()).unwrap();
+        let key = rustls_pemfile::pkcs8_private_keys(&mut LOCALHOST_KEY.as_bytes()).unwrap();
+        let config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                cert.into_iter().map(rustls::Certificate).collect(),
+                rustls::PrivateKey(key[0].clone()),
+            )
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            for connection in 0.. {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut stream = acceptor.accept(stream).await.unwrap();
+                let exporter = stream
+                    .get_ref()
+                    .1
+                    .export_keying_material(vec![0; 32], b"EXPORTER-Channel-Binding", None)
+                    .unwrap();
+
+                while let Some((head, body)) = read_request(&mut stream).await {
+                    let request_line = head.lines().next().unwrap().to_string();
+                    let _ = sender.send((connection, request_line.clone()));
+
+                    if request_line.starts_with("POST /kbs/v0/auth ") {
+                        let challenge = r#"{"nonce":"42","extra-params":{}}"#;
+                        let cookie = format!("Set-Cookie: kbs-session={connection}\r\n");
+                        let response = response("200 OK", &cookie, challenge);
+                        stream.write_all(response.as_bytes()).await.unwrap();
+                        if connection == 0 {
+                            break;
+                        }
+                        continue;
+                    }
+
+                    let attestation: Value = serde_json::from_slice(&body).unwrap();
+                    let evidence: Value =
+                        serde_json::from_str(attestation["tee-evidence"].as_str().unwrap())
+                            .unwrap();
+                    let pubkey = &attestation["tee-pubkey"];
+                    let bound = hash_chunks(vec![
+                        b"42".to_vec(),
+                        pubkey["x"].as_str().unwrap().as_bytes().to_vec(),
+                        pubkey["y"].as_str().unwrap().as_bytes().to_vec(),
+                        base64::encode_config(&exporter, base64::URL_SAFE_NO_PAD).into_bytes(),
+                    ]);
+                    let same_session = head.contains(&format!("kbs-session={connection}"));
+                    let response = match evidence["report_data"] == bound && same_session {
+                        true => response("200 OK", "", ""),
+                        false => response(
+                            "401 Unauthorized",
+                            "",
+                            r#"{"type":"unbound","detail":"evidence of another session"}"#,
+                        ),
+                    };
+                    stream.write_all(response.as_bytes()).await.unwrap();
+                }
+            }
+        });
+
+        (port, receiver)
+    }
+
+    #[tokio::test]
+    async fn kbs_closes_session_before_attest() {
+        let ca_path =
+            std::env::temp_dir().join(format!("cc-kbc-binding-{}.pem", std::process::id()));
+        std::fs::write(&ca_path, LOCALHOST_CERT).unwrap();
+        let config = KbsConfig {
+            ca_certs: vec![ca_path.clone()],
+            channel_binding: true,
+            tee_key_algorithm: TeeKeyAlgorithm::EcP256,
+            ..Default::default()
+        };
+
+        let (port, mut requests) = kbs().await;
+        let tee_key = TeeKey::new(&config).ok();
+        let mut kbc = Kbc::connect(
+            format!("https://localhost:{port}").parse().unwrap(),
+            config,
+            tee_key,
+            None,
+        )
+        .unwrap();
+        kbc.tee = "sample".to_string();
+        kbc.attester = Some(Box::<SampleAttester>::default());
+
+        let deadline = kbc.config.retry.deadline();
+        kbc.establish_kbs_session(deadline)
+            .await
+            .expect("attestation failed");
+        assert!(kbc.authenticated);
+
+        // The evidence only went over the second session, which it is bound
+        // to.
+        let mut lines = Vec::new();
+        while let Result::Ok(request) = requests.try_recv() {
+            lines.push(request);
+        }
+        let auth = "POST /kbs/v0/auth HTTP/1.1".to_string();
+        let attest = "POST /kbs/v0/attest HTTP/1.1".to_string();
+        assert_eq!(lines, vec![(0, auth.clone()), (1, auth), (1, attest)]);
+
+        std::fs::remove_file(&ca_path).unwrap();
+    }
+}
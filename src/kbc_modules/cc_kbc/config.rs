@@ -0,0 +1,246 @@
+// Copyright (c) 2023 Alibaba Cloud
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Configuration of the CC KBC.
+//!
+//! The configuration is a JSON file read from the path given by the
+//! `CC_KBC_CONFIG` environment variable, or [`DEFAULT_CONFIG_PATH`] if the
+//! variable is not set. A missing file means all defaults. Settings under
+//! `default` apply to every KBS, and the entries under `kbs` (indexed by
+//! `<kbs_host>:<kbs_port>`) override them field by field for one KBS:
+//!
+//! ```json
+//! {
+//!     "default": {
+//!         "tee_key_algorithm": "EC-P256"
+//!     },
+//!     "kbs": {
+//!         "legacy-kbs.example.org:8080": {
+//!             "tee_key_algorithm": "RSA"
+//!         }
+//!     }
+//! }
+//! ```
+
+use anyhow::*;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::{collections::HashMap, path::PathBuf};
+
+use super::crypto::{RsaAlgorithm, TeeKeyAlgorithm};
+use super::kbs_protocol::ProtocolVersion;
+use super::passport::PassportConfig;
+use super::proxy::ProxyConfig;
+use super::retry::RetryPolicy;
+use super::session::SessionCacheConfig;
+use super::tls::TlsVersion;
+use crate::kbc_modules::config;
+
+pub const CONFIG_PATH_ENV: &str = "CC_KBC_CONFIG";
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/aa-cc_kbc-config.json";
+
+/// Settings used when talking to one KBS.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct KbsConfig {
+    /// Algorithm of the key generated inside the TEE to receive secrets.
+    /// Defaults to RSA, the only one older KBS versions understand.
+    pub tee_key_algorithm: TeeKeyAlgorithm,
+
+    /// Key wrapping algorithm advertised with an RSA TEE key. Defaults to
+    /// `RSA1_5` for older KBS, or `RSA-OAEP-256` if `reject_rsa1_5` is set.
+    pub rsa_algorithm: Option<RsaAlgorithm>,
+
+    /// Refuse secrets whose key is wrapped with `RSA1_5`, which is
+    /// vulnerable to padding oracle attacks.
+    pub reject_rsa1_5: bool,
+
+    /// Accept secrets of older KBS whose JWE header is plain JSON, which is
+    /// not authenticated. Always accepted with protocol 0.1.0, and never
+    /// with `reject_rsa1_5`.
+    pub allow_legacy_jwe: bool,
+
+    /// KBS protocol version to use. By default the newest version the KBS
+    /// accepts is negotiated.
+    pub protocol_version: Option<ProtocolVersion>,
+
+    /// Additional `extra-params` sent to the KBS, for example an init-data
+    /// digest. Ignored with protocol 0.1.0.
+    pub extra_params: Map<String, Value>,
+
+    /// PEM files of CA certificates trusted in addition to the default ones.
+    pub ca_certs: Vec<PathBuf>,
+
+    /// Accepted public keys of the KBS certificate, as base64 SHA-256 hashes
+    /// of its SubjectPublicKeyInfo. Any key is accepted if empty.
+    pub spki_pins: Vec<String>,
+
+    /// PEM client certificate chain for mutual TLS.
+    pub client_cert: Option<PathBuf>,
+
+    /// PEM PKCS#8 private key of `client_cert`.
+    pub client_key: Option<PathBuf>,
+
+    /// Minimum TLS version.
+    pub min_tls_version: Option<TlsVersion>,
+
+    /// Retries of transient failures.
+    pub retry: RetryPolicy,
+
+    /// Proxy of the connections to the KBS.
+    pub proxy: Option<ProxyConfig>,
+
+    /// Bind the evidence to the TLS session with the KBS, by hashing its
+    /// RFC 9266 `tls-exporter` value with the nonce and the TEE key.
+    /// Requires an `https://` KBS speaking TLS 1.3.
+    pub channel_binding: bool,
+
+    /// PEM public key or JWKS file of the KBS or attestation service that
+    /// signs the attestation tokens. Tokens are not verified if unset.
+    pub token_key: Option<PathBuf>,
+
+    /// Present the token of an attestation service to the KBS instead of
+    /// attesting to it. Ignored in the settings of the attestation service.
+    pub passport: Option<PassportConfig>,
+
+    /// Persist the session with the KBS and the TEE key across restarts of
+    /// the agent. Ignored in passport mode.
+    pub session_cache: Option<SessionCacheConfig>,
+}
+
+impl KbsConfig {
+    /// Load the settings for the KBS at `kbs_addr` (`<kbs_host>:<kbs_port>`)
+    /// from the configuration file.
+    pub fn load(kbs_addr: &str) -> Result<Self> {
+        let config = config::load(CONFIG_PATH_ENV, DEFAULT_CONFIG_PATH)?;
+        Self::from_value(&config, kbs_addr)
+            .with_context(|| format!("Invalid CC KBC config for {kbs_addr}"))
+    }
+
+    /// Load the settings of the KBSes with their own entry under `kbs` in
+    /// the configuration file, indexed by `<kbs_host>:<kbs_port>`.
+    pub fn load_configured() -> Result<HashMap<String, Self>> {
+        let config = config::load(CONFIG_PATH_ENV, DEFAULT_CONFIG_PATH)?;
+        Self::configured(&config).context("Invalid CC KBC config")
+    }
+
+    /// Key wrapping algorithm to advertise with an RSA TEE key.
+    pub fn rsa_algorithm(&self) -> Result<RsaAlgorithm> {
+        match (self.rsa_algorithm, self.reject_rsa1_5) {
+            (Some(RsaAlgorithm::Rsa1_5), true) => {
+                bail!("rsa_algorithm RSA1_5 conflicts with reject_rsa1_5")
+            }
+            (Some(algorithm), _) => Ok(algorithm),
+            (None, true) => Ok(RsaAlgorithm::RsaOaep256),
+            (None, false) => Ok(RsaAlgorithm::Rsa1_5),
+        }
+    }
+
+    fn configured(config: &Value) -> Result<HashMap<String, Self>> {
+        let Some(Value::Object(kbs)) = config.get("kbs") else {
+            return Ok(HashMap::new());
+        };
+
+        kbs.keys()
+            .map(|name| Ok((name.clone(), Self::from_value(config, name)?)))
+            .collect()
+    }
+
+    fn from_value(config: &Value, kbs_addr: &str) -> Result<Self> {
+        let section = |value: Option<&Value>| -> Result<Map<String, Value>> {
+            match value {
+                None | Some(Value::Null) => Ok(Map::new()),
+                Some(Value::Object(map)) => Ok(map.clone()),
+                Some(_) => bail!("KBS settings must be a JSON object"),
+            }
+        };
+
+        let mut merged = section(config.get("default"))?;
+        let kbs = config.get("kbs").and_then(|kbs| kbs.get(kbs_addr));
+        merged.extend(section(kbs)?);
+
+        serde_json::from_value(Value::Object(merged)).map_err(|e| anyhow!("{e}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A missing file loads as `null`.
+    #[test]
+    fn missing_file_is_default() {
+        let config = KbsConfig::from_value(&Value::Null, "kbs:8080").expect("parse config failed");
+        assert_eq!(config, KbsConfig::default());
+        assert!(KbsConfig::configured(&Value::Null).unwrap().is_empty());
+    }
+
+    #[rstest::rstest]
+    #[case("kbs:8080", TeeKeyAlgorithm::Rsa)]
+    #[case("other-kbs", TeeKeyAlgorithm::EcP256)]
+    fn per_kbs_override(#[case] kbs_addr: &str, #[case] expected: TeeKeyAlgorithm) {
+        let config = serde_json::json!({
+            "default": { "tee_key_algorithm": "EC-P256" },
+            "kbs": { "kbs:8080": { "tee_key_algorithm": "RSA" } },
+        });
+
+        let config = KbsConfig::from_value(&config, kbs_addr).expect("parse config failed");
+        assert_eq!(config.tee_key_algorithm, expected);
+    }
+
+    #[test]
+    fn configured_kbses() {
+        let config = serde_json::json!({
+            "default": { "tee_key_algorithm": "EC-P256" },
+            "kbs": {
+                "kbs1:8080": { "tee_key_algorithm": "RSA" },
+                "kbs2:8080": {},
+            },
+        });
+
+        let kbses = KbsConfig::configured(&config).expect("parse config failed");
+        assert_eq!(kbses.len(), 2);
+        assert_eq!(kbses["kbs1:8080"].tee_key_algorithm, TeeKeyAlgorithm::Rsa);
+        assert_eq!(
+            kbses["kbs2:8080"].tee_key_algorithm,
+            TeeKeyAlgorithm::EcP256
+        );
+
+        let config = serde_json::json!({ "default": { "tee_key_algorithm": "EC-P256" } });
+        let kbses = KbsConfig::configured(&config).expect("parse config failed");
+        assert!(kbses.is_empty());
+    }
+
+    #[rstest::rstest]
+    #[case(serde_json::json!({}), Some(RsaAlgorithm::Rsa1_5))]
+    #[case(serde_json::json!({ "reject_rsa1_5": true }), Some(RsaAlgorithm::RsaOaep256))]
+    #[case(serde_json::json!({ "rsa_algorithm": "RSA-OAEP", "reject_rsa1_5": true }), Some(RsaAlgorithm::RsaOaep))]
+    #[case(serde_json::json!({ "rsa_algorithm": "RSA1_5", "reject_rsa1_5": true }), None)]
+    fn rsa_algorithm(#[case] settings: Value, #[case] expected: Option<RsaAlgorithm>) {
+        let config = serde_json::json!({ "default": settings });
+        let config = KbsConfig::from_value(&config, "kbs:8080").expect("parse config failed");
+        assert_eq!(config.rsa_algorithm().ok(), expected);
+    }
+
+    #[test]
+    fn protocol_settings() {
+        let config = serde_json::json!({
+            "default": {
+                "protocol_version": "0.1.0",
+                "extra_params": { "init-data-digest": "abcd" },
+            },
+        });
+
+        let config = KbsConfig::from_value(&config, "kbs:8080").expect("parse config failed");
+        assert_eq!(config.protocol_version, Some(ProtocolVersion::V0_1_0));
+        assert_eq!(config.extra_params["init-data-digest"], "abcd");
+    }
+
+    #[test]
+    fn unknown_field() {
+        let config = serde_json::json!({ "default": { "no_such_field": 1 } });
+        assert!(KbsConfig::from_value(&config, "kbs:8080").is_err());
+    }
+}
@@ -0,0 +1,446 @@
+// Copyright (c) 2023 Alibaba Cloud
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! JSON Web Encryption (RFC 7516) of the secrets returned by the KBS.
+//!
+//! Both the flattened JSON and the compact serializations are accepted.
+//! The content encryption key is recovered with the TEE key, this module
+//! authenticates and decrypts the content with it.
+//!
+//! Older KBS versions send the protected header as plain JSON instead of
+//! base64url, do not authenticate it, and append the AES-GCM tag to the
+//! ciphertext. Such responses are parsed, and marked with
+//! [`Jwe::legacy_header`] for the KBC to refuse them unless allowed.
+
+use super::EcPublicJwk;
+use aes_gcm::{
+    aead::{generic_array::GenericArray, AeadInPlace},
+    aes::{Aes128, Aes256},
+    Aes128Gcm, Aes256Gcm,
+};
+use anyhow::*;
+use cbc::cipher::{block_padding::Pkcs7, BlockCipher, BlockDecryptMut, KeyInit, KeyIvInit};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::{Sha256, Sha512};
+use std::str::FromStr;
+
+pub const A128GCM: &str = "A128GCM";
+pub const A256GCM: &str = "A256GCM";
+pub const A128CBC_HS256: &str = "A128CBC-HS256";
+pub const A256CBC_HS512: &str = "A256CBC-HS512";
+
+const GCM_IV_LENGTH: usize = 12;
+const GCM_TAG_LENGTH: usize = 16;
+
+/// JOSE header of a JWE, the union of its protected and unprotected headers.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct JoseHeader {
+    // encryption algorithm for encrypted key
+    pub alg: String,
+    // encryption algorithm for payload
+    pub enc: String,
+    // ephemeral public key of the sender for ECDH-ES
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub epk: Option<EcPublicJwk>,
+    // agreement PartyUInfo for ECDH-ES (base64url-encoded)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub apu: Option<String>,
+    // agreement PartyVInfo for ECDH-ES (base64url-encoded)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub apv: Option<String>,
+    // extensions that must be understood
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub crit: Vec<String>,
+}
+
+/// A parsed JWE.
+#[derive(Debug, Clone)]
+pub struct Jwe {
+    pub header: JoseHeader,
+    pub encrypted_key: Vec<u8>,
+    pub iv: Vec<u8>,
+    pub ciphertext: Vec<u8>,
+    pub tag: Vec<u8>,
+    // Whether the header is plain JSON of an older KBS, which is not
+    // authenticated.
+    pub legacy_header: bool,
+    // Additional authenticated data: the protected header as sent, followed
+    // by '.' and the `aad` member of the JSON serialization if any.
+    aad: Vec<u8>,
+}
+
+// Flattened JWE JSON serialization (RFC 7516 section 7.2.2).
+#[derive(Deserialize)]
+struct FlattenedJwe {
+    #[serde(default)]
+    protected: String,
+    #[serde(default)]
+    unprotected: Map<String, Value>,
+    #[serde(default)]
+    header: Map<String, Value>,
+    #[serde(default)]
+    encrypted_key: String,
+    #[serde(default)]
+    iv: String,
+    ciphertext: String,
+    #[serde(default)]
+    tag: String,
+    aad: Option<String>,
+}
+
+impl FromStr for Jwe {
+    type Err = Error;
+
+    fn from_str(jwe: &str) -> Result<Self> {
+        let jwe = jwe.trim();
+        if jwe.starts_with('{') {
+            Self::from_json(jwe)
+        } else {
+            Self::from_compact(jwe)
+        }
+    }
+}
+
+impl Jwe {
+    /// Parse the compact serialization (RFC 7516 section 7.1).
+    pub fn from_compact(jwe: &str) -> Result<Self> {
+        let parts: Vec<&str> = jwe.split('.').collect();
+        let [protected, encrypted_key, iv, ciphertext, tag] = parts[..] else {
+            bail!(
+                "JWE compact serialization must have 5 parts, got {}",
+                parts.len()
+            );
+        };
+
+        Ok(Jwe {
+            header: serde_json::from_slice(&decode(protected)?)
+                .context("Invalid JWE protected header")?,
+            encrypted_key: decode(encrypted_key)?,
+            iv: decode(iv)?,
+            ciphertext: decode(ciphertext)?,
+            tag: decode(tag)?,
+            legacy_header: false,
+            aad: protected.as_bytes().to_vec(),
+        })
+    }
+
+    /// Parse the flattened JSON serialization (RFC 7516 section 7.2.2).
+    pub fn from_json(jwe: &str) -> Result<Self> {
+        let jwe: FlattenedJwe = serde_json::from_str(jwe).context("Invalid JWE JSON")?;
+
+        let mut ciphertext = decode(&jwe.ciphertext)?;
+        let mut tag = decode(&jwe.tag)?;
+
+        let legacy_header = jwe.protected.starts_with('{');
+        let (protected, mut aad) = if legacy_header {
+            // Older KBS: plain JSON header, no AAD and tag appended to
+            // the ciphertext.
+            if tag.is_empty() && ciphertext.len() >= GCM_TAG_LENGTH {
+                tag = ciphertext.split_off(ciphertext.len() - GCM_TAG_LENGTH);
+            }
+            (jwe.protected.as_bytes().to_vec(), Vec::new())
+        } else {
+            (decode(&jwe.protected)?, jwe.protected.as_bytes().to_vec())
+        };
+
+        if let Some(extra) = &jwe.aad {
+            aad.push(b'.');
+            aad.extend(extra.as_bytes());
+        }
+
+        let mut header = if protected.is_empty() {
+            Map::new()
+        } else {
+            serde_json::from_slice(&protected).context("Invalid JWE protected header")?
+        };
+        for (name, value) in jwe.unprotected.into_iter().chain(jwe.header) {
+            if header.insert(name.clone(), value).is_some() {
+                bail!("JWE header parameter {name} is duplicated");
+            }
+        }
+
+        Ok(Jwe {
+            header: serde_json::from_value(Value::Object(header)).context("Invalid JWE header")?,
+            encrypted_key: decode(&jwe.encrypted_key)?,
+            iv: decode(&jwe.iv)?,
+            ciphertext,
+            tag,
+            legacy_header,
+            aad,
+        })
+    }
+
+    /// Authenticate and decrypt the content with the content encryption key.
+    pub fn decrypt(&self, cek: &[u8]) -> Result<Vec<u8>> {
+        if let Some(crit) = self.header.crit.first() {
+            bail!("Unsupported critical JWE header parameter {crit}");
+        }
+
+        match self.header.enc.as_str() {
+            A128GCM => {
+                aes_gcm_decrypt::<Aes128Gcm>(cek, &self.iv, &self.ciphertext, &self.tag, &self.aad)
+            }
+            A256GCM => {
+                aes_gcm_decrypt::<Aes256Gcm>(cek, &self.iv, &self.ciphertext, &self.tag, &self.aad)
+            }
+            A128CBC_HS256 => aes_cbc_hmac_decrypt::<Aes128, Hmac<Sha256>>(
+                cek,
+                &self.iv,
+                &self.ciphertext,
+                &self.tag,
+                &self.aad,
+            ),
+            A256CBC_HS512 => aes_cbc_hmac_decrypt::<Aes256, Hmac<Sha512>>(
+                cek,
+                &self.iv,
+                &self.ciphertext,
+                &self.tag,
+                &self.aad,
+            ),
+            enc => bail!("Unsupported algorithm: {enc}"),
+        }
+    }
+}
+
+/// Length of the content encryption key of a JWE `enc` algorithm.
+pub fn content_key_length(enc: &str) -> Result<usize> {
+    match enc {
+        A128GCM => Ok(16),
+        A256GCM => Ok(32),
+        A128CBC_HS256 => Ok(32),
+        A256CBC_HS512 => Ok(64),
+        _ => bail!("Unsupported algorithm: {enc}"),
+    }
+}
+
+fn decode(data: &str) -> Result<Vec<u8>> {
+    base64::decode_config(data, base64::URL_SAFE_NO_PAD).context("Invalid base64url in JWE")
+}
+
+fn aes_gcm_decrypt<C>(
+    key: &[u8],
+    iv: &[u8],
+    ciphertext: &[u8],
+    tag: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>>
+where
+    C: AeadInPlace + KeyInit,
+{
+    if iv.len() != GCM_IV_LENGTH || tag.len() != GCM_TAG_LENGTH {
+        bail!("Invalid AES-GCM IV or tag length");
+    }
+
+    let cipher = C::new_from_slice(key).map_err(|_| anyhow!("Invalid AES-GCM key length"))?;
+    let mut plaintext = ciphertext.to_vec();
+    cipher
+        .decrypt_in_place_detached(
+            GenericArray::from_slice(iv),
+            aad,
+            &mut plaintext,
+            GenericArray::from_slice(tag),
+        )
+        .map_err(|_| anyhow!("JWE decryption failed"))?;
+
+    Ok(plaintext)
+}
+
+// AES-CBC with HMAC-SHA2 (RFC 7518 section 5.2). The first half of the key
+// authenticates, the second half encrypts, and the tag is the first half of
+// the HMAC output.
+fn aes_cbc_hmac_decrypt<C, M>(
+    key: &[u8],
+    iv: &[u8],
+    ciphertext: &[u8],
+    tag: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>>
+where
+    C: BlockCipher + BlockDecryptMut + KeyInit,
+    M: Mac + KeyInit,
+{
+    if key.len() != 2 * C::key_size() {
+        bail!("Invalid AES-CBC-HMAC key length");
+    }
+    let (mac_key, enc_key) = key.split_at(C::key_size());
+
+    if tag.len() != mac_key.len() {
+        bail!("Invalid AES-CBC-HMAC tag length");
+    }
+
+    let mut mac = <M as KeyInit>::new_from_slice(mac_key)
+        .map_err(|_| anyhow!("Invalid AES-CBC-HMAC key length"))?;
+    mac.update(aad);
+    mac.update(iv);
+    mac.update(ciphertext);
+    mac.update(&((aad.len() as u64) * 8).to_be_bytes());
+    mac.verify_truncated_left(tag)
+        .map_err(|_| anyhow!("JWE decryption failed"))?;
+
+    cbc::Decryptor::<C>::new_from_slices(enc_key, iv)
+        .map_err(|_| anyhow!("Invalid AES-CBC IV length"))?
+        .decrypt_padded_vec_mut::<Pkcs7>(ciphertext)
+        .map_err(|_| anyhow!("JWE decryption failed"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 7516 appendix A.1: RSAES-OAEP and AES GCM. The RSA private key
+    // is left out, the test starts from the content encryption key.
+    const RFC7516_A1_JWE: &str = "eyJhbGciOiJSU0EtT0FFUCIsImVuYyI6IkEyNTZHQ00ifQ.\
+        OKOawDo13gRp2ojaHV7LFpZcgV7T6DVZKTyKOMTYUmKoTCVJRgckCL9kiMT03JGeipsEdY3mx_etLbbWSrFr05kLzcSr4qKAq7YN7e9jwQRb23nfa6c9d-StnImGyFDbSv04uVuxIp5Zms1gNxKKK2Da14B8S4rzVRltdYwam_lDp5XnZAYpQdb76FdIKLaVmqgfwX7XWRxv2322i-vDxRfqNzo_tETKzpVLzfiwQyeyPGLBIO56YJ7eObdv0je81860ppamavo35UgoRdbYaBcoh9QcfylQr66oc6vFWXRcZ_ZT2LawVCWTIy3brGPi6UklfCpIMfIjf7iGdXKHzg.\
+        48V1_ALb6US04U3b.\
+        5eym8TW_c8SuK0ltJ3rpYIzOeDQz7TALvtu6UG9oMo4vpzs9tX_EFShS8iB7j6jiSdiwkIr3ajwQzaBtQD_A.\
+        XFBoMYUZodetZdvTiFvSkQ";
+    const RFC7516_A1_CEK: [u8; 32] = [
+        177, 161, 244, 128, 84, 143, 225, 115, 63, 180, 3, 255, 107, 154, 212, 246, 138, 7, 110,
+        91, 112, 46, 34, 105, 47, 130, 203, 46, 122, 234, 64, 252,
+    ];
+    const RFC7516_A1_PLAINTEXT: &[u8] =
+        b"The true sign of intelligence is not knowledge but imagination.";
+
+    // RFC 7516 appendix A.3: AES Key Wrap and AES_128_CBC_HMAC_SHA_256.
+    const RFC7516_A3_JWE: &str = "eyJhbGciOiJBMTI4S1ciLCJlbmMiOiJBMTI4Q0JDLUhTMjU2In0.\
+        6KB707dM9YTIgHtLvtgWQ8mKwboJW3of9locizkDTHzBC2IlrT1oOQ.\
+        AxY8DCtDaGlsbGljb3RoZQ.\
+        KDlTtXchhZTGufMYmOYGS4HffxPSUrfmqCHXaI9wOGY.\
+        U0m_YmjN04DJvceFICbCVQ";
+    const RFC7516_A3_KEK: &str = "GawgguFyGrWKav7AX4VKUg";
+    const RFC7516_A3_PLAINTEXT: &[u8] = b"Live long and prosper.";
+
+    // RFC 7516 appendix A.7: the A.3 content in flattened JSON serialization,
+    // with unprotected headers.
+    const RFC7516_A7_JWE: &str = r#"{
+        "protected": "eyJlbmMiOiJBMTI4Q0JDLUhTMjU2In0",
+        "unprotected": {"jku": "https://server.example.com/keys.jwks"},
+        "header": {"alg": "A128KW", "kid": "7"},
+        "encrypted_key": "6KB707dM9YTIgHtLvtgWQ8mKwboJW3of9locizkDTHzBC2IlrT1oOQ",
+        "iv": "AxY8DCtDaGlsbGljb3RoZQ",
+        "ciphertext": "KDlTtXchhZTGufMYmOYGS4HffxPSUrfmqCHXaI9wOGY",
+        "tag": "Mz-VPPyU4RlcuYv1IwIvzw"
+    }"#;
+
+    fn a128kw_unwrap(jwe: &Jwe) -> Vec<u8> {
+        let kek = decode(RFC7516_A3_KEK).unwrap();
+        aes_kw::KekAes128::new(kek.as_slice().into())
+            .unwrap_vec(&jwe.encrypted_key)
+            .expect("unwrap failed")
+    }
+
+    #[test]
+    fn rfc7516_a1() {
+        let jwe: Jwe = RFC7516_A1_JWE.parse().expect("parse failed");
+        assert_eq!(jwe.header.alg, "RSA-OAEP");
+        assert_eq!(jwe.header.enc, A256GCM);
+
+        let plaintext = jwe.decrypt(&RFC7516_A1_CEK).expect("decrypt failed");
+        assert_eq!(plaintext, RFC7516_A1_PLAINTEXT);
+    }
+
+    #[rstest::rstest]
+    #[case(RFC7516_A3_JWE)]
+    #[case(RFC7516_A7_JWE)]
+    fn rfc7516_a3(#[case] jwe: &str) {
+        let jwe: Jwe = jwe.parse().expect("parse failed");
+        assert_eq!(jwe.header.alg, "A128KW");
+        assert_eq!(jwe.header.enc, A128CBC_HS256);
+
+        let plaintext = jwe.decrypt(&a128kw_unwrap(&jwe)).expect("decrypt failed");
+        assert_eq!(plaintext, RFC7516_A3_PLAINTEXT);
+    }
+
+    // RFC 7518 appendix B.3: AES_256_CBC_HMAC_SHA_512.
+    #[test]
+    fn rfc7518_b3() {
+        let key: Vec<u8> = (0..64).collect();
+        let iv = hex::decode("1af38c2dc2b96ffdd86694092341bc04").unwrap();
+        let aad = b"The second principle of Auguste Kerckhoffs";
+        let ciphertext = hex::decode(
+            "4affaaadb78c31c5da4b1b590d10ffbd3dd8d5d302423526912da037ecbcc7bd\
+             822c301dd67c373bccb584ad3e9279c2e6d12a1374b77f077553df829410446b\
+             36ebd97066296ae6427ea75c2e0846a11a09ccf5370dc80bfecbad28c73f09b3\
+             a3b75e662a2594410ae496b2e2e6609e31e6e02cc837f053d21f37ff4f51950b\
+             be2638d09dd7a4930930806d0703b1f6",
+        )
+        .unwrap();
+        let tag = hex::decode("4dd3b4c088a7f45c216839645b2012bf2e6269a8c56a816dbc1b267761955bc5")
+            .unwrap();
+
+        let jwe = Jwe {
+            header: JoseHeader {
+                alg: "dir".to_string(),
+                enc: A256CBC_HS512.to_string(),
+                ..Default::default()
+            },
+            encrypted_key: Vec::new(),
+            iv,
+            ciphertext,
+            tag,
+            legacy_header: false,
+            aad: aad.to_vec(),
+        };
+
+        let plaintext = jwe.decrypt(&key).expect("decrypt failed");
+        assert_eq!(
+            plaintext,
+            b"A cipher system must not be required to be secret, and it must be able \
+              to fall into the hands of the enemy without inconvenience"
+        );
+    }
+
+    // Replace the protected header of the A.3 JWE.
+    fn with_protected(header: &str) -> String {
+        let (_, rest) = RFC7516_A3_JWE.split_once('.').unwrap();
+        let protected = base64::encode_config(header, base64::URL_SAFE_NO_PAD);
+        format!("{protected}.{rest}")
+    }
+
+    #[rstest::rstest]
+    #[case(with_protected(r#"{"alg":"A128KW","enc":"A128CBC-HS256","kid":"1"}"#))]
+    #[case(RFC7516_A3_JWE.replace("U0m_YmjN04DJvceFICbCVQ", "U0m_YmjN04DJvceFICbCVA"))]
+    #[case(RFC7516_A7_JWE.replace("\"tag\"", "\"aad\": \"YQ\", \"tag\""))]
+    fn tampered(#[case] jwe: String) {
+        let jwe: Jwe = jwe.parse().expect("parse failed");
+        assert!(jwe.decrypt(&a128kw_unwrap(&jwe)).is_err());
+    }
+
+    #[test]
+    fn duplicated_header() {
+        let jwe = RFC7516_A7_JWE.replace("\"kid\"", "\"enc\": \"A256GCM\", \"kid\"");
+        assert!(jwe.parse::<Jwe>().is_err());
+    }
+
+    #[test]
+    fn legacy_kbs_response() {
+        let cek = [0x24; 32];
+        let iv = [0x42; GCM_IV_LENGTH];
+        let mut ciphertext = b"confidential resource".to_vec();
+        let tag = Aes256Gcm::new_from_slice(&cek)
+            .unwrap()
+            .encrypt_in_place_detached(GenericArray::from_slice(&iv), b"", &mut ciphertext)
+            .unwrap();
+        ciphertext.extend(tag);
+
+        let jwe = serde_json::json!({
+            "protected": r#"{"alg":"RSA1_5","enc":"A256GCM"}"#,
+            "encrypted_key": "",
+            "iv": base64::encode_config(iv, base64::URL_SAFE_NO_PAD),
+            "ciphertext": base64::encode_config(ciphertext, base64::URL_SAFE_NO_PAD),
+            "tag": "",
+        });
+
+        let jwe: Jwe = jwe.to_string().parse().expect("parse failed");
+        assert!(jwe.legacy_header);
+        assert_eq!(jwe.header.alg, "RSA1_5");
+        assert_eq!(
+            jwe.decrypt(&cek).expect("decrypt failed"),
+            b"confidential resource"
+        );
+    }
+}
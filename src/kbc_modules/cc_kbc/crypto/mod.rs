@@ -3,99 +3,433 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
-use crate::common::crypto::{self, WrapType};
-use crate::kbc_modules::cc_kbc::kbs_protocol::message::Response;
+use crate::kbc_modules::cc_kbc::config::KbsConfig;
 use anyhow::*;
-use kbs_types::TeePubKey;
-use rsa::{PaddingScheme, PublicKeyParts, RsaPrivateKey, RsaPublicKey};
+use jwe::{content_key_length, JoseHeader, Jwe};
+use p256::ecdsa::signature::Signer as _;
+use p256::elliptic_curve::{
+    ecdh::diffie_hellman,
+    sec1::{FromEncodedPoint, ModulusSize, ToEncodedPoint},
+    AffinePoint, Curve, FieldSize, ProjectiveArithmetic, PublicKey, SecretKey,
+};
+use rsa::{
+    pkcs8::{DecodePrivateKey, EncodePrivateKey},
+    Hash, PaddingScheme, PublicKeyParts, RsaPrivateKey, RsaPublicKey,
+};
 use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha384};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256, Sha384};
+use std::str::FromStr;
 use zeroize::Zeroizing;
 
-const RSA_ALGORITHM: &str = "RSA1_5";
+pub mod jwe;
+
 const RSA_PUBKEY_LENGTH: usize = 2048;
-const NEW_PADDING: fn() -> PaddingScheme = PaddingScheme::new_pkcs1v15_encrypt;
 
-pub const AES_256_GCM_ALGORITHM: &str = "A256GCM";
+const EC_KEY_TYPE: &str = "EC";
+const P256_CURVE: &str = "P-256";
+const P384_CURVE: &str = "P-384";
+
+pub const ECDH_ES_ALGORITHM: &str = "ECDH-ES";
+pub const ECDH_ES_A256KW_ALGORITHM: &str = "ECDH-ES+A256KW";
+
+/// Algorithm of the key generated inside the TEE.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TeeKeyAlgorithm {
+    /// 2048-bit RSA key, unwrapping with one of [`RsaAlgorithm`].
+    #[default]
+    #[serde(rename = "RSA")]
+    Rsa,
+
+    /// NIST P-256 key, unwrapping with `ECDH-ES` key agreement.
+    #[serde(rename = "EC-P256")]
+    EcP256,
+
+    /// NIST P-384 key, unwrapping with `ECDH-ES` key agreement.
+    #[serde(rename = "EC-P384")]
+    EcP384,
+}
+
+/// Key wrapping algorithm of RSA TEE keys.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, EnumString, AsRefStr)]
+pub enum RsaAlgorithm {
+    /// RSAES-PKCS1-v1_5. It is vulnerable to padding oracle attacks and is
+    /// only kept for older KBS.
+    #[serde(rename = "RSA1_5")]
+    #[strum(serialize = "RSA1_5")]
+    Rsa1_5,
+
+    /// RSAES-OAEP with SHA-1 and MGF1 with SHA-1.
+    #[serde(rename = "RSA-OAEP")]
+    #[strum(serialize = "RSA-OAEP")]
+    RsaOaep,
+
+    /// RSAES-OAEP with SHA-256 and MGF1 with SHA-256.
+    #[serde(rename = "RSA-OAEP-256")]
+    #[strum(serialize = "RSA-OAEP-256")]
+    RsaOaep256,
+}
+
+impl RsaAlgorithm {
+    fn padding(&self) -> PaddingScheme {
+        match self {
+            RsaAlgorithm::Rsa1_5 => PaddingScheme::new_pkcs1v15_encrypt(),
+            RsaAlgorithm::RsaOaep => PaddingScheme::new_oaep::<sha1::Sha1>(),
+            RsaAlgorithm::RsaOaep256 => PaddingScheme::new_oaep::<Sha256>(),
+        }
+    }
+}
 
 // The key inside TEE to decrypt confidential data.
 #[derive(Debug, Clone)]
-pub struct TeeKey {
-    private_key: RsaPrivateKey,
-    public_key: RsaPublicKey,
+pub enum TeeKey {
+    Rsa {
+        private_key: Box<RsaPrivateKey>,
+        // algorithm advertised to the KBS
+        algorithm: RsaAlgorithm,
+        // whether to still accept keys wrapped with RSA1_5
+        allow_rsa1_5: bool,
+    },
+    EcP256(SecretKey<p256::NistP256>),
+    EcP384(SecretKey<p384::NistP384>),
+}
+
+/// TEE public key sent to the KBS. RSA keys keep the layout of
+/// [`kbs_types::TeePubKey`] so that older KBS can still parse them,
+/// EC keys are sent as a JWK.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum TeePubKey {
+    Rsa(kbs_types::TeePubKey),
+    Ec(EcPublicJwk),
+}
+
+/// Public part of an EC key in JWK format (RFC 7518 section 6.2.1).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct EcPublicJwk {
+    pub kty: String,
+    pub crv: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub alg: String,
+    pub x: String,
+    pub y: String,
+}
+
+impl EcPublicJwk {
+    fn from_public_key<C>(public_key: &PublicKey<C>, crv: &str, alg: &str) -> Result<Self>
+    where
+        C: Curve + ProjectiveArithmetic,
+        AffinePoint<C>: FromEncodedPoint<C> + ToEncodedPoint<C>,
+        FieldSize<C>: ModulusSize,
+    {
+        let point = public_key.to_encoded_point(false);
+        let x = point.x().ok_or_else(|| anyhow!("EC point has no x"))?;
+        let y = point.y().ok_or_else(|| anyhow!("EC point has no y"))?;
+
+        Ok(EcPublicJwk {
+            kty: EC_KEY_TYPE.to_string(),
+            crv: crv.to_string(),
+            alg: alg.to_string(),
+            x: base64::encode_config(x, base64::URL_SAFE_NO_PAD),
+            y: base64::encode_config(y, base64::URL_SAFE_NO_PAD),
+        })
+    }
+
+    fn to_public_key<C>(&self, crv: &str) -> Result<PublicKey<C>>
+    where
+        C: Curve + ProjectiveArithmetic,
+        AffinePoint<C>: FromEncodedPoint<C> + ToEncodedPoint<C>,
+        FieldSize<C>: ModulusSize,
+    {
+        if self.kty != EC_KEY_TYPE || self.crv != crv {
+            bail!(
+                "Ephemeral key {}/{} does not match TEE key curve {crv}",
+                self.kty,
+                self.crv
+            );
+        }
+
+        // Uncompressed SEC1 encoding: 0x04 || x || y
+        let mut sec1 = vec![0x04];
+        sec1.extend(base64::decode_config(&self.x, base64::URL_SAFE_NO_PAD)?);
+        sec1.extend(base64::decode_config(&self.y, base64::URL_SAFE_NO_PAD)?);
+
+        PublicKey::<C>::from_sec1_bytes(&sec1).map_err(|_| anyhow!("Invalid ephemeral EC key"))
+    }
 }
 
 impl TeeKey {
-    pub fn new() -> Result<TeeKey> {
+    pub fn new(config: &KbsConfig) -> Result<TeeKey> {
         let mut rng = rand::thread_rng();
 
-        let private_key = RsaPrivateKey::new(&mut rng, RSA_PUBKEY_LENGTH)?;
-        let public_key = RsaPublicKey::from(&private_key);
+        match config.tee_key_algorithm {
+            TeeKeyAlgorithm::Rsa => {
+                let algorithm = config.rsa_algorithm()?;
+                let private_key = RsaPrivateKey::new(&mut rng, RSA_PUBKEY_LENGTH)?;
+                Ok(TeeKey::Rsa {
+                    private_key: Box::new(private_key),
+                    algorithm,
+                    allow_rsa1_5: !config.reject_rsa1_5,
+                })
+            }
+            TeeKeyAlgorithm::EcP256 => Ok(TeeKey::EcP256(SecretKey::random(&mut rng))),
+            TeeKeyAlgorithm::EcP384 => Ok(TeeKey::EcP384(SecretKey::random(&mut rng))),
+        }
+    }
 
-        Ok(TeeKey {
-            private_key,
-            public_key,
-        })
+    /// Private key material, as PKCS#8 DER for RSA keys and as the big
+    /// endian scalar for EC keys, to persist the key inside the TEE.
+    pub fn export_private_key(&self) -> Result<Zeroizing<Vec<u8>>> {
+        match self {
+            TeeKey::Rsa { private_key, .. } => {
+                let der = private_key
+                    .to_pkcs8_der()
+                    .map_err(|e| anyhow!("Export TEE RSA key failed: {e}"))?;
+                Ok(Zeroizing::new(der.as_ref().to_vec()))
+            }
+            TeeKey::EcP256(key) => Ok(Zeroizing::new(key.to_be_bytes().to_vec())),
+            TeeKey::EcP384(key) => Ok(Zeroizing::new(key.to_be_bytes().to_vec())),
+        }
+    }
+
+    /// Import a key exported by [`Self::export_private_key`], which must be
+    /// of the algorithm of `config`.
+    pub fn import_private_key(private_key: &[u8], config: &KbsConfig) -> Result<TeeKey> {
+        match config.tee_key_algorithm {
+            TeeKeyAlgorithm::Rsa => Ok(TeeKey::Rsa {
+                private_key: Box::new(
+                    RsaPrivateKey::from_pkcs8_der(private_key)
+                        .map_err(|e| anyhow!("Invalid TEE RSA key: {e}"))?,
+                ),
+                algorithm: config.rsa_algorithm()?,
+                allow_rsa1_5: !config.reject_rsa1_5,
+            }),
+            TeeKeyAlgorithm::EcP256 => Ok(TeeKey::EcP256(
+                SecretKey::from_be_bytes(private_key).map_err(|_| anyhow!("Invalid TEE EC key"))?,
+            )),
+            TeeKeyAlgorithm::EcP384 => Ok(TeeKey::EcP384(
+                SecretKey::from_be_bytes(private_key).map_err(|_| anyhow!("Invalid TEE EC key"))?,
+            )),
+        }
     }
 
     // Export TEE public key as specific structure.
     pub fn export_pubkey(&self) -> Result<TeePubKey> {
-        let k_mod = base64::encode(self.public_key.n().to_bytes_be());
-        let k_exp = base64::encode(self.public_key.e().to_bytes_be());
+        match self {
+            TeeKey::Rsa {
+                private_key,
+                algorithm,
+                ..
+            } => {
+                let public_key = RsaPublicKey::from(private_key.as_ref());
+                let k_mod = base64::encode(public_key.n().to_bytes_be());
+                let k_exp = base64::encode(public_key.e().to_bytes_be());
 
-        Ok(TeePubKey {
-            alg: RSA_ALGORITHM.to_string(),
-            k_mod,
-            k_exp,
-        })
+                Ok(TeePubKey::Rsa(kbs_types::TeePubKey {
+                    alg: algorithm.as_ref().to_string(),
+                    k_mod,
+                    k_exp,
+                }))
+            }
+            TeeKey::EcP256(key) => Ok(TeePubKey::Ec(EcPublicJwk::from_public_key(
+                &key.public_key(),
+                P256_CURVE,
+                ECDH_ES_A256KW_ALGORITHM,
+            )?)),
+            TeeKey::EcP384(key) => Ok(TeePubKey::Ec(EcPublicJwk::from_public_key(
+                &key.public_key(),
+                P384_CURVE,
+                ECDH_ES_A256KW_ALGORITHM,
+            )?)),
+        }
     }
 
-    // Use TEE private key to decrypt cipher text.
-    pub fn decrypt(&self, cipher_text: Vec<u8>) -> Result<Vec<u8>> {
-        let padding = NEW_PADDING();
+    // Key wrapping algorithm advertised with the public key.
+    pub fn algorithm(&self) -> &str {
+        match self {
+            TeeKey::Rsa { algorithm, .. } => algorithm.as_ref(),
+            TeeKey::EcP256(_) | TeeKey::EcP384(_) => ECDH_ES_A256KW_ALGORITHM,
+        }
+    }
+
+    // All key wrapping algorithms the TEE key can unwrap.
+    pub fn supported_algorithms(&self) -> Vec<String> {
+        match self {
+            TeeKey::Rsa { allow_rsa1_5, .. } => {
+                let mut algorithms = vec![RsaAlgorithm::RsaOaep256, RsaAlgorithm::RsaOaep];
+                if *allow_rsa1_5 {
+                    algorithms.push(RsaAlgorithm::Rsa1_5);
+                }
+                algorithms.iter().map(|a| a.as_ref().to_string()).collect()
+            }
+            TeeKey::EcP256(_) | TeeKey::EcP384(_) => vec![
+                ECDH_ES_A256KW_ALGORITHM.to_string(),
+                ECDH_ES_ALGORITHM.to_string(),
+            ],
+        }
+    }
+
+    /// Public key as a JWK without algorithm, the form it takes in the
+    /// header of the JWS proving its possession.
+    pub fn public_jwk(&self) -> Result<Value> {
+        match self {
+            TeeKey::Rsa { private_key, .. } => Ok(json!({
+                "kty": "RSA",
+                "n": base64::encode_config(private_key.n().to_bytes_be(), base64::URL_SAFE_NO_PAD),
+                "e": base64::encode_config(private_key.e().to_bytes_be(), base64::URL_SAFE_NO_PAD),
+            })),
+            TeeKey::EcP256(key) => Ok(serde_json::to_value(EcPublicJwk::from_public_key(
+                &key.public_key(),
+                P256_CURVE,
+                "",
+            )?)?),
+            TeeKey::EcP384(key) => Ok(serde_json::to_value(EcPublicJwk::from_public_key(
+                &key.public_key(),
+                P384_CURVE,
+                "",
+            )?)?),
+        }
+    }
+
+    /// JWS algorithm of the signatures of the TEE key.
+    pub fn signature_algorithm(&self) -> &'static str {
+        match self {
+            TeeKey::Rsa { .. } => "RS256",
+            TeeKey::EcP256(_) => "ES256",
+            TeeKey::EcP384(_) => "ES384",
+        }
+    }
 
-        self.private_key
-            .decrypt(padding, &cipher_text)
-            .map_err(|e| anyhow!("TEE RSA key decrypt failed: {:?}", e))
+    /// Sign `message` with the TEE key, with its [`Self::signature_algorithm`].
+    pub fn sign(&self, message: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            TeeKey::Rsa { private_key, .. } => {
+                let padding = PaddingScheme::new_pkcs1v15_sign(Some(Hash::SHA2_256));
+                private_key
+                    .sign(padding, &Sha256::digest(message))
+                    .map_err(|e| anyhow!("TEE RSA key sign failed: {:?}", e))
+            }
+            TeeKey::EcP256(key) => {
+                let signature: p256::ecdsa::Signature =
+                    p256::ecdsa::SigningKey::from(key).sign(message);
+                Ok(signature.as_ref().to_vec())
+            }
+            TeeKey::EcP384(key) => {
+                let signature: p384::ecdsa::Signature =
+                    p384::ecdsa::SigningKey::from(key).sign(message);
+                Ok(signature.as_ref().to_vec())
+            }
+        }
+    }
+
+    // Use TEE private key to recover the content encryption key of a response.
+    fn unwrap_key(&self, protected: &JoseHeader, encrypted_key: &[u8]) -> Result<Vec<u8>> {
+        match (self, protected.alg.as_str()) {
+            (
+                TeeKey::Rsa {
+                    private_key,
+                    allow_rsa1_5,
+                    ..
+                },
+                alg,
+            ) => {
+                let algorithm = RsaAlgorithm::from_str(alg)
+                    .map_err(|_| anyhow!("Algorithm mismatch for wrapped key."))?;
+                if algorithm == RsaAlgorithm::Rsa1_5 && !allow_rsa1_5 {
+                    bail!("Key wrapped with {alg} is refused, use RSA-OAEP instead");
+                }
+
+                private_key
+                    .decrypt(algorithm.padding(), encrypted_key)
+                    .map_err(|e| anyhow!("TEE RSA key decrypt failed: {:?}", e))
+            }
+            (TeeKey::EcP256(_) | TeeKey::EcP384(_), ECDH_ES_ALGORITHM) => {
+                if !encrypted_key.is_empty() {
+                    bail!("Encrypted key must be empty for {ECDH_ES_ALGORITHM}");
+                }
+
+                // With direct key agreement the derived key is the content
+                // encryption key, and the algorithm ID is the `enc` value.
+                let key_length = content_key_length(&protected.enc)?;
+                let key = self.agree_key(protected, &protected.enc, key_length)?;
+                Ok(key.to_vec())
+            }
+            (TeeKey::EcP256(_) | TeeKey::EcP384(_), ECDH_ES_A256KW_ALGORITHM) => {
+                let kek = self.agree_key(protected, &protected.alg, 32)?;
+                aes_kw::KekAes256::new(kek.as_slice().into())
+                    .unwrap_vec(encrypted_key)
+                    .map_err(|e| anyhow!("TEE key unwrap failed: {:?}", e))
+            }
+            _ => bail!("Algorithm mismatch for wrapped key."),
+        }
+    }
+
+    // Derive a key from ECDH-ES key agreement with the ephemeral key
+    // in the protected header (RFC 7518 section 4.6).
+    fn agree_key(
+        &self,
+        protected: &JoseHeader,
+        algorithm_id: &str,
+        key_length: usize,
+    ) -> Result<Zeroizing<Vec<u8>>> {
+        let epk = protected
+            .epk
+            .as_ref()
+            .ok_or_else(|| anyhow!("Ephemeral public key missing in protected header"))?;
+
+        let shared_secret = match self {
+            TeeKey::EcP256(key) => ecdh(key, &epk.to_public_key(P256_CURVE)?),
+            TeeKey::EcP384(key) => ecdh(key, &epk.to_public_key(P384_CURVE)?),
+            TeeKey::Rsa { .. } => bail!("ECDH is not supported by RSA TEE key"),
+        };
+
+        let apu = base64::decode_config(
+            protected.apu.as_deref().unwrap_or_default(),
+            base64::URL_SAFE_NO_PAD,
+        )?;
+        let apv = base64::decode_config(
+            protected.apv.as_deref().unwrap_or_default(),
+            base64::URL_SAFE_NO_PAD,
+        )?;
+
+        concat_kdf(&shared_secret, algorithm_id, &apu, &apv, key_length)
     }
 }
 
-#[derive(Serialize, Deserialize)]
-struct ProtectedHeader {
-    // enryption algorithm for encrypted key
-    alg: String,
-    // encryption algorithm for payload
-    enc: String,
+fn ecdh<C>(secret_key: &SecretKey<C>, public_key: &PublicKey<C>) -> Zeroizing<Vec<u8>>
+where
+    C: Curve + ProjectiveArithmetic,
+{
+    let shared_secret = diffie_hellman(secret_key.to_nonzero_scalar(), public_key.as_affine());
+    Zeroizing::new(shared_secret.raw_secret_bytes().to_vec())
 }
 
-pub fn decrypt_response(response: &Response, tee_key: TeeKey) -> Result<Vec<u8>> {
-    // deserialize the jose header and check that the key type matches
-    let protected: ProtectedHeader = serde_json::from_str(&response.protected)?;
-    if protected.alg != RSA_ALGORITHM {
-        return Err(anyhow!("Algorithm mismatch for wrapped key."));
-    }
-
-    // unwrap the wrapped key
-    let wrapped_symkey: Vec<u8> =
-        base64::decode_config(&response.encrypted_key, base64::URL_SAFE_NO_PAD)?;
-    let symkey: Vec<u8> = tee_key.decrypt(wrapped_symkey)?;
-
-    let iv = base64::decode_config(&response.iv, base64::URL_SAFE_NO_PAD)?;
-    let ciphertext = base64::decode_config(&response.ciphertext, base64::URL_SAFE_NO_PAD)?;
-
-    let plaintext = match protected.enc.as_str() {
-        AES_256_GCM_ALGORITHM => crypto::decrypt(
-            Zeroizing::new(symkey),
-            ciphertext,
-            iv,
-            WrapType::Aes256Gcm.as_ref(),
-        )?,
-        _ => {
-            bail!("Unsupported algorithm: {}", protected.enc.clone());
-        }
-    };
+// Concat KDF with SHA-256 as used by JWA (RFC 7518 section 4.6.2).
+fn concat_kdf(
+    shared_secret: &[u8],
+    algorithm_id: &str,
+    apu: &[u8],
+    apv: &[u8],
+    key_length: usize,
+) -> Result<Zeroizing<Vec<u8>>> {
+    let mut other_info = Vec::new();
+    for field in [algorithm_id.as_bytes(), apu, apv] {
+        other_info.extend((field.len() as u32).to_be_bytes());
+        other_info.extend(field);
+    }
+    other_info.extend(((key_length * 8) as u32).to_be_bytes());
 
-    Ok(plaintext)
+    let mut key = Zeroizing::new(vec![0; key_length]);
+    concat_kdf::derive_key_into::<Sha256>(shared_secret, &other_info, &mut key)
+        .map_err(|e| anyhow!("Concat KDF failed: {:?}", e))?;
+    Ok(key)
+}
+
+// Recover the content encryption key with the TEE key, then authenticate
+// and decrypt the content.
+pub fn decrypt_response(response: &Jwe, tee_key: TeeKey) -> Result<Vec<u8>> {
+    let cek = Zeroizing::new(tee_key.unwrap_key(&response.header, &response.encrypted_key)?);
+    response.decrypt(&cek)
 }
 
 // Returns a base64 of the sha384 of all chunks.
@@ -110,3 +444,196 @@ pub fn hash_chunks(chunks: Vec<Vec<u8>>) -> String {
 
     base64::encode(res)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aes_gcm::{aead::AeadInPlace, Aes256Gcm, KeyInit, Nonce};
+    use p256::elliptic_curve::ecdh::EphemeralSecret;
+    use rsa::PublicKey as _;
+    use rstest::rstest;
+
+    const PLAINTEXT: &[u8] = b"confidential resource";
+    const IV: [u8; 12] = [0x42; 12];
+
+    // Encrypt `PLAINTEXT` with `cek` the way a KBS would.
+    fn response(protected: JoseHeader, encrypted_key: &[u8], cek: &[u8]) -> Jwe {
+        let encode = |data: &[u8]| base64::encode_config(data, base64::URL_SAFE_NO_PAD);
+        let protected = encode(&serde_json::to_vec(&protected).unwrap());
+
+        let mut ciphertext = PLAINTEXT.to_vec();
+        let tag = Aes256Gcm::new(cek.into())
+            .encrypt_in_place_detached(
+                Nonce::from_slice(&IV),
+                protected.as_bytes(),
+                &mut ciphertext,
+            )
+            .expect("encrypt failed");
+
+        let compact = [
+            protected,
+            encode(encrypted_key),
+            encode(&IV),
+            encode(&ciphertext),
+            encode(&tag),
+        ]
+        .join(".");
+        compact.parse().expect("parse JWE failed")
+    }
+
+    fn ecdh_es_response<C>(recipient: &PublicKey<C>, crv: &str, alg: &str) -> Jwe
+    where
+        C: Curve + ProjectiveArithmetic,
+        AffinePoint<C>: FromEncodedPoint<C> + ToEncodedPoint<C>,
+        FieldSize<C>: ModulusSize,
+    {
+        let ephemeral = EphemeralSecret::<C>::random(&mut rand::thread_rng());
+        let shared_secret = ephemeral.diffie_hellman(recipient);
+        let protected = JoseHeader {
+            alg: alg.to_string(),
+            enc: jwe::A256GCM.to_string(),
+            epk: Some(EcPublicJwk::from_public_key(&ephemeral.public_key(), crv, "").unwrap()),
+            apu: Some(base64::encode_config("kbs", base64::URL_SAFE_NO_PAD)),
+            ..Default::default()
+        };
+
+        let cek = [0x24; 32];
+        match alg {
+            ECDH_ES_ALGORITHM => {
+                let cek = concat_kdf(
+                    shared_secret.raw_secret_bytes(),
+                    &protected.enc,
+                    b"kbs",
+                    b"",
+                    32,
+                )
+                .unwrap();
+                response(protected, &[], &cek)
+            }
+            _ => {
+                let kek =
+                    concat_kdf(shared_secret.raw_secret_bytes(), alg, b"kbs", b"", 32).unwrap();
+                let encrypted_key = aes_kw::KekAes256::new(kek.as_slice().into())
+                    .wrap_vec(&cek)
+                    .unwrap();
+                response(protected, &encrypted_key, &cek)
+            }
+        }
+    }
+
+    fn tee_key(tee_key_algorithm: TeeKeyAlgorithm) -> TeeKey {
+        let config = KbsConfig {
+            tee_key_algorithm,
+            ..Default::default()
+        };
+        TeeKey::new(&config).expect("generate TEE key failed")
+    }
+
+    #[rstest]
+    #[case(TeeKeyAlgorithm::EcP256, ECDH_ES_ALGORITHM)]
+    #[case(TeeKeyAlgorithm::EcP256, ECDH_ES_A256KW_ALGORITHM)]
+    #[case(TeeKeyAlgorithm::EcP384, ECDH_ES_ALGORITHM)]
+    #[case(TeeKeyAlgorithm::EcP384, ECDH_ES_A256KW_ALGORITHM)]
+    fn decrypt_ecdh_es(#[case] algorithm: TeeKeyAlgorithm, #[case] alg: &str) {
+        let tee_key = tee_key(algorithm);
+        let response = match &tee_key {
+            TeeKey::EcP256(key) => ecdh_es_response(&key.public_key(), P256_CURVE, alg),
+            TeeKey::EcP384(key) => ecdh_es_response(&key.public_key(), P384_CURVE, alg),
+            TeeKey::Rsa { .. } => unreachable!(),
+        };
+
+        let plaintext = decrypt_response(&response, tee_key).expect("decrypt failed");
+        assert_eq!(plaintext, PLAINTEXT);
+    }
+
+    fn rsa_response(tee_key: &TeeKey, algorithm: RsaAlgorithm) -> Jwe {
+        let TeeKey::Rsa { private_key, .. } = tee_key else {
+            unreachable!()
+        };
+
+        let cek = [0x24; 32];
+        let encrypted_key = RsaPublicKey::from(private_key.as_ref())
+            .encrypt(&mut rand::thread_rng(), algorithm.padding(), &cek)
+            .unwrap();
+        let protected = JoseHeader {
+            alg: algorithm.as_ref().to_string(),
+            enc: jwe::A256GCM.to_string(),
+            ..Default::default()
+        };
+
+        response(protected, &encrypted_key, &cek)
+    }
+
+    #[rstest]
+    #[case(RsaAlgorithm::Rsa1_5)]
+    #[case(RsaAlgorithm::RsaOaep)]
+    #[case(RsaAlgorithm::RsaOaep256)]
+    fn decrypt_rsa(#[case] algorithm: RsaAlgorithm) {
+        let tee_key = tee_key(TeeKeyAlgorithm::Rsa);
+        let response = rsa_response(&tee_key, algorithm);
+
+        let plaintext = decrypt_response(&response, tee_key).expect("decrypt failed");
+        assert_eq!(plaintext, PLAINTEXT);
+    }
+
+    #[test]
+    fn reject_rsa1_5() {
+        let config = KbsConfig {
+            reject_rsa1_5: true,
+            ..Default::default()
+        };
+        let tee_key = TeeKey::new(&config).expect("generate TEE key failed");
+
+        let TeePubKey::Rsa(pubkey) = tee_key.export_pubkey().unwrap() else {
+            panic!("RSA TEE key exported as EC")
+        };
+        assert_eq!(pubkey.alg, RsaAlgorithm::RsaOaep256.as_ref());
+
+        let response = rsa_response(&tee_key, RsaAlgorithm::Rsa1_5);
+        assert!(decrypt_response(&response, tee_key.clone()).is_err());
+
+        let response = rsa_response(&tee_key, RsaAlgorithm::RsaOaep);
+        assert!(decrypt_response(&response, tee_key).is_ok());
+    }
+
+    #[test]
+    fn export_ec_pubkey() {
+        let tee_key = tee_key(TeeKeyAlgorithm::EcP384);
+        let TeePubKey::Ec(jwk) = tee_key.export_pubkey().unwrap() else {
+            panic!("EC TEE key exported as RSA")
+        };
+
+        assert_eq!(jwk.kty, EC_KEY_TYPE);
+        assert_eq!(jwk.crv, P384_CURVE);
+        assert_eq!(jwk.alg, ECDH_ES_A256KW_ALGORITHM);
+        assert!(jwk.to_public_key::<p384::NistP384>(P384_CURVE).is_ok());
+    }
+
+    #[rstest]
+    #[case(TeeKeyAlgorithm::Rsa)]
+    #[case(TeeKeyAlgorithm::EcP256)]
+    #[case(TeeKeyAlgorithm::EcP384)]
+    fn import_private_key(#[case] tee_key_algorithm: TeeKeyAlgorithm) {
+        let config = KbsConfig {
+            tee_key_algorithm,
+            ..Default::default()
+        };
+        let key = TeeKey::new(&config).unwrap();
+        let exported = key.export_private_key().unwrap();
+        let imported = TeeKey::import_private_key(&exported, &config).unwrap();
+        assert_eq!(
+            serde_json::to_value(imported.export_pubkey().unwrap()).unwrap(),
+            serde_json::to_value(key.export_pubkey().unwrap()).unwrap()
+        );
+
+        let other = match tee_key_algorithm {
+            TeeKeyAlgorithm::Rsa => TeeKeyAlgorithm::EcP384,
+            _ => TeeKeyAlgorithm::Rsa,
+        };
+        let config = KbsConfig {
+            tee_key_algorithm: other,
+            ..Default::default()
+        };
+        assert!(TeeKey::import_private_key(&exported, &config).is_err());
+    }
+}
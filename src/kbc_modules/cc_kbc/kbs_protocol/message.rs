@@ -3,29 +3,39 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
-use crate::kbc_modules::cc_kbc::crypto::*;
+use super::ProtocolVersion;
+use crate::kbc_modules::cc_kbc::crypto::TeePubKey;
 use anyhow::*;
 use serde::{Deserialize, Serialize};
-
-pub const KBS_PROTOCOL_VERSION: &str = "0.1.0";
+use serde_json::{Map, Value};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Request {
     version: String,
     tee: String,
 
-    // Reserved field.
+    // Empty string with protocol 0.1.0, an object afterwards.
     #[serde(rename = "extra-params")]
-    pub extra_params: String,
+    pub extra_params: Value,
 }
 
 impl Request {
-    pub fn new(tee: String) -> Request {
-        Request {
-            version: KBS_PROTOCOL_VERSION.to_string(),
+    pub fn new(
+        version: ProtocolVersion,
+        tee: String,
+        extra_params: &ExtraParams,
+    ) -> Result<Request> {
+        let extra_params = if version.has_extra_params() {
+            serde_json::to_value(extra_params)?
+        } else {
+            Value::String(String::new())
+        };
+
+        Ok(Request {
+            version: version.to_string(),
             tee,
-            extra_params: "".to_string(),
-        }
+            extra_params,
+        })
     }
 }
 
@@ -34,25 +44,57 @@ pub struct Challenge {
     // Nonce from KBS to prevent replay attack.
     pub nonce: String,
 
-    // Reserved field.
-    #[serde(rename = "extra-params")]
-    pub extra_params: String,
+    // Empty string with protocol 0.1.0, an object afterwards.
+    #[serde(default, rename = "extra-params")]
+    pub extra_params: Value,
+}
+
+impl Challenge {
+    pub fn extra_params(&self) -> Result<ExtraParams> {
+        match &self.extra_params {
+            Value::Object(_) => serde_json::from_value(self.extra_params.clone())
+                .map_err(|e| anyhow!("Invalid challenge extra-params: {e}")),
+            _ => Ok(ExtraParams::default()),
+        }
+    }
+}
+
+/// Structured `extra-params` of the request and the challenge.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExtraParams {
+    /// Key wrapping algorithms (JWE `alg`) supported by the sender.
+    #[serde(
+        default,
+        rename = "supported-key-algorithms",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub supported_key_algorithms: Vec<String>,
+
+    /// Channel binding of the evidence to the TLS session, `tls-exporter`.
+    #[serde(
+        default,
+        rename = "channel-binding",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub channel_binding: Option<String>,
+
+    /// Any other parameter, for example an init-data digest.
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct Response {
-    pub protected: String,
-    pub encrypted_key: String,
-    pub iv: String,
-    pub ciphertext: String,
-    pub tag: String,
+pub struct Attestation {
+    #[serde(rename = "tee-pubkey")]
+    pub tee_pubkey: TeePubKey,
+    #[serde(rename = "tee-evidence")]
+    pub tee_evidence: String,
 }
 
-impl Response {
-    // Use TEE's private key to decrypt output of Response.
-    pub fn decrypt_output(&self, tee_key: TeeKey) -> Result<Vec<u8>> {
-        decrypt_response(self, tee_key)
-    }
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AttestationResponse {
+    // Token to send as bearer token on resource requests.
+    pub token: String,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -70,3 +112,40 @@ impl ErrorResponse {
         self.error.info.clone()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[rstest::rstest]
+    #[case(
+        ProtocolVersion::V0_1_0,
+        r#"{"version":"0.1.0","tee":"tdx","extra-params":""}"#
+    )]
+    #[case(
+        ProtocolVersion::V0_1_1,
+        r#"{"version":"0.1.1","tee":"tdx","extra-params":{"supported-key-algorithms":["RSA-OAEP-256"],"channel-binding":"tls-exporter","init-data-digest":"abcd"}}"#
+    )]
+    fn request(#[case] version: ProtocolVersion, #[case] expected: &str) {
+        let extra_params = ExtraParams {
+            supported_key_algorithms: vec!["RSA-OAEP-256".to_string()],
+            channel_binding: Some("tls-exporter".to_string()),
+            other: Map::from_iter([("init-data-digest".to_string(), json!("abcd"))]),
+        };
+
+        let request = Request::new(version, "tdx".to_string(), &extra_params).unwrap();
+        let expected: Value = serde_json::from_str(expected).unwrap();
+        assert_eq!(serde_json::to_value(request).unwrap(), expected);
+    }
+
+    #[rstest::rstest]
+    #[case(r#"{"nonce":"42","extra-params":""}"#, vec![])]
+    #[case(r#"{"nonce":"42"}"#, vec![])]
+    #[case(r#"{"nonce":"42","extra-params":{"supported-key-algorithms":["RSA1_5"]}}"#, vec!["RSA1_5"])]
+    fn challenge(#[case] json: &str, #[case] expected: Vec<&str>) {
+        let challenge: Challenge = serde_json::from_str(json).unwrap();
+        let extra_params = challenge.extra_params().unwrap();
+        assert_eq!(extra_params.supported_key_algorithms, expected);
+    }
+}
@@ -4,3 +4,37 @@
 //
 
 pub mod message;
+
+use serde::Deserialize;
+
+/// Versions of the KBS protocol spoken by the CC KBC.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, EnumString, AsRefStr, Display)]
+pub enum ProtocolVersion {
+    /// First version, `extra-params` are reserved and left empty.
+    #[serde(rename = "0.1.0")]
+    #[strum(serialize = "0.1.0")]
+    V0_1_0,
+
+    /// `extra-params` of the request and the challenge are JSON objects.
+    #[serde(rename = "0.1.1")]
+    #[strum(serialize = "0.1.1")]
+    V0_1_1,
+}
+
+/// Supported protocol versions, in the order they are tried.
+pub const SUPPORTED_PROTOCOL_VERSIONS: [ProtocolVersion; 2] =
+    [ProtocolVersion::V0_1_1, ProtocolVersion::V0_1_0];
+
+impl ProtocolVersion {
+    /// Path prefix of the KBS endpoints.
+    pub fn url_prefix(&self) -> &'static str {
+        match self {
+            ProtocolVersion::V0_1_0 | ProtocolVersion::V0_1_1 => "kbs/v0",
+        }
+    }
+
+    /// Whether `extra-params` are structured.
+    pub fn has_extra_params(&self) -> bool {
+        *self != ProtocolVersion::V0_1_0
+    }
+}
@@ -4,79 +4,207 @@
 //
 
 use crate::{
-    common::crypto::decrypt,
-    kbc_modules::{KbcCheckInfo, KbcInterface},
+    common::crypto::decrypt_with_aad,
+    kbc_modules::{kbs_address::KbsAddress, KbcCheckInfo, KbcInterface},
 };
 
 mod attester;
+mod binding;
+mod config;
 mod crypto;
 mod kbs_protocol;
+mod passport;
+mod proxy;
+mod relay;
+mod retry;
+mod session;
+mod tls;
+mod token;
 
 use anyhow::*;
 use async_trait::async_trait;
 use attester::{detect_tee_type, Attester};
+use binding::BoundSession;
+use config::KbsConfig;
 use core::time::Duration;
-use crypto::{hash_chunks, TeeKey};
-use kbs_protocol::message::*;
-use kbs_types::{Attestation, ErrorInformation};
+use crypto::{decrypt_response, hash_chunks, jwe::Jwe, TeeKey, TeePubKey};
+use kbs_protocol::{message::*, ProtocolVersion, SUPPORTED_PROTOCOL_VERSIONS};
+use kbs_types::ErrorInformation;
+use passport::Passport;
+use relay::Relay;
+use reqwest::cookie::{CookieStore, Jar};
+use serde_json::{Map, Value};
+use session::{keyring, Session};
+use std::collections::{hash_map::Entry, HashMap};
+use std::sync::Arc;
+use std::time::Instant;
+use token::{Token, TokenKeys};
 use url::Url;
 use zeroize::Zeroizing;
 
 use super::{uri::ResourceUri, AnnotationPacket};
 
 const KBS_REQ_TIMEOUT_SEC: u64 = 60;
+const KBS_USER_AGENT: &str = concat!("attestation-agent-cc-kbc/", env!("CARGO_PKG_VERSION"));
 const KBS_GET_RESOURCE_MAX_ATTEMPT: u64 = 3;
 
-pub const KBS_URL_PREFIX: &str = "kbs/v0";
+// Re-attest when the KBS token expires within this margin.
+const KBS_TOKEN_EXPIRY_MARGIN_SEC: u64 = 30;
 
 pub struct Kbc {
     tee: String,
+    kbs_address: KbsAddress,
+    // Base URL of the requests, which is the one of the relay for a KBS
+    // over a Unix socket or vsock.
     kbs_uri: Url,
-    token: Option<String>,
+    relay: Option<Relay>,
+    // TLS session the evidence is bound to, while attesting with channel
+    // binding.
+    bound: Option<BoundSession>,
+    config: KbsConfig,
+    protocol_version: Option<ProtocolVersion>,
+    token: Option<Token>,
+    token_keys: Option<TokenKeys>,
     nonce: String,
     tee_key: Option<TeeKey>,
     attester: Option<Box<dyn Attester + Send + Sync>>,
     http_client: reqwest::Client,
+    cookies: Arc<Jar>,
     authenticated: bool,
+    // Token of the attestation service presented instead of attesting.
+    passport: Option<Arc<Passport>>,
+    // Settings of the KBSes with their own entry in the configuration, the
+    // only other KBSes key shares are fetched from.
+    configured_kbses: HashMap<String, KbsConfig>,
+    // Other KBSes holding the shares of a split KEK.
+    resource_kbses: HashMap<String, Kbc>,
 }
 
 #[async_trait]
 impl KbcInterface for Kbc {
     fn check(&self) -> Result<KbcCheckInfo> {
-        Err(anyhow!("Check API of this KBC is unimplemented."))
+        let mut kbs_info = HashMap::new();
+        kbs_info.insert("kbs_addr".to_string(), self.kbs_address.to_string());
+        if let Some(version) = self.protocol_version {
+            kbs_info.insert("protocol_version".to_string(), version.to_string());
+        }
+
+        if let Some(passport) = &self.passport {
+            kbs_info.insert(
+                "attestation_service".to_string(),
+                passport.service().to_string(),
+            );
+        }
+
+        // Claims of the attestation token, e.g. `token.tcb-status`.
+        let (token, verified) = self.current_token();
+        if let Some(token) = token {
+            kbs_info.insert("token_verified".to_string(), verified.to_string());
+            for (name, value) in token.claims() {
+                kbs_info.insert(format!("token.{name}"), value.to_string());
+            }
+        }
+
+        Ok(KbcCheckInfo { kbs_info })
     }
 
     async fn decrypt_payload(&mut self, annotation_packet: AnnotationPacket) -> Result<Vec<u8>> {
-        let key_url = self.resource_to_kbs_uri(&annotation_packet.kid)?;
-
-        let response = self.request_kbs_resource(key_url).await?;
+        let response = self.request_kbs_resource(&annotation_packet.kid).await?;
         let key = Zeroizing::new(self.decrypt_response_output(response)?);
 
-        decrypt(
+        decrypt_with_aad(
             key,
-            base64::decode(annotation_packet.wrapped_data)?,
-            base64::decode(annotation_packet.iv)?,
+            base64::decode(&annotation_packet.wrapped_data)?,
+            base64::decode(&annotation_packet.iv)?,
+            &annotation_packet.aad()?,
             &annotation_packet.wrap_type,
         )
     }
 
     #[allow(unused_assignments)]
     async fn get_resource(&mut self, desc: ResourceUri) -> Result<Vec<u8>> {
-        let resource_url = self.resource_to_kbs_uri(&desc)?;
-        let response = self.request_kbs_resource(resource_url).await?;
+        let response = self.request_kbs_resource(&desc).await?;
 
         self.decrypt_response_output(response)
     }
+
+    async fn get_key_share(&mut self, rid: ResourceUri) -> Result<Vec<u8>> {
+        let other_kbs = !rid.kbs_addr.is_empty() && rid.kbs_addr != kbs_addr(&self.kbs_address);
+        if !other_kbs {
+            return self.get_resource(rid).await;
+        }
+
+        // The KBS of the share is reached by its own client, which presents
+        // the token in passport mode and attests itself otherwise.
+        let kbc = self.resource_kbs(&rid.kbs_addr)?;
+        let response = kbc.request_kbs_resource(&rid).await?;
+        kbc.decrypt_response_output(response)
+    }
 }
 
 impl Kbc {
     pub fn new(kbs_uri: String) -> Result<Kbc> {
-        // Check the KBS URI validity
-        let url = Url::parse(&kbs_uri).map_err(|e| anyhow!("Invalid URI {kbs_uri}: {e}"))?;
-        if !url.has_host() {
-            bail!("{kbs_uri} is missing a host");
+        let kbs_address = kbs_uri.parse::<KbsAddress>()?;
+        let config = KbsConfig::load(&kbs_addr(&kbs_address))?;
+        let tee_key = TeeKey::new(&config).ok();
+
+        let passport = match &config.passport {
+            Some(passport) => {
+                let address = passport.attestation_service.parse::<KbsAddress>()?;
+                let config = KbsConfig::load(&kbs_addr(&address))?;
+                // The token is issued for the TEE key used with the KBSes.
+                let service = Kbc::connect(address, config, tee_key.clone(), None)?;
+                Some(Arc::new(Passport::start(service)?))
+            }
+            None => None,
+        };
+
+        let mut kbc = Kbc::connect(kbs_address, config, tee_key, passport)?;
+        kbc.configured_kbses = KbsConfig::load_configured()?;
+        if kbc.passport.is_none() && kbc.config.session_cache.is_some() {
+            if let Err(e) = kbc.restore_session() {
+                log::info!("CC-KBC: no KBS session restored: {e}");
+            }
+        }
+
+        Ok(kbc)
+    }
+
+    fn connect(
+        kbs_address: KbsAddress,
+        config: KbsConfig,
+        tee_key: Option<TeeKey>,
+        passport: Option<Arc<Passport>>,
+    ) -> Result<Kbc> {
+        let https = matches!(&kbs_address, KbsAddress::Http(url) if url.scheme() == "https");
+        if !config.spki_pins.is_empty() && !https {
+            bail!("KBS certificate is pinned but {kbs_address} is not an https:// KBS");
         }
 
+        let (url, relay) = match &kbs_address {
+            KbsAddress::Http(url) if config.channel_binding && url.scheme() != "https" => {
+                bail!("Channel binding needs an https:// KBS, not {url}")
+            }
+            KbsAddress::Http(url) => (url.clone(), None),
+            address if config.channel_binding => {
+                bail!("Channel binding needs an https:// KBS, not {address}")
+            }
+            address => {
+                let relay = Relay::start(address.clone())?;
+                (Url::parse(&relay.url())?, Some(relay))
+            }
+        };
+
+        let cookies = Arc::new(Jar::default());
+        // The bound TLS sessions connect to the KBS directly.
+        let direct = relay.is_some() || config.channel_binding;
+        let http_client = build_http_client(&config, direct, cookies.clone())?;
+        let token_keys = config
+            .token_key
+            .as_deref()
+            .map(TokenKeys::load)
+            .transpose()?;
+
         // Detect TEE type of the current platform.
         let tee_type = detect_tee_type();
 
@@ -85,13 +213,23 @@ impl Kbc {
 
         Ok(Kbc {
             tee: tee_type.to_string(),
+            kbs_address,
             kbs_uri: url,
+            relay,
+            bound: None,
             token: None,
+            token_keys,
             nonce: String::default(),
-            tee_key: TeeKey::new().ok(),
+            tee_key,
+            config,
+            protocol_version: None,
             attester,
-            http_client: build_http_client().unwrap(),
+            http_client,
+            cookies,
             authenticated: false,
+            passport,
+            configured_kbses: HashMap::new(),
+            resource_kbses: HashMap::new(),
         })
     }
 
@@ -109,11 +247,27 @@ impl Kbc {
             .export_pubkey()
             .map_err(|e| anyhow!("Export TEE pubkey failed: {:?}", e))?;
 
-        let ehd_chunks = vec![
-            self.nonce.clone().into_bytes(),
-            tee_pubkey.k_mod.clone().into_bytes(),
-            tee_pubkey.k_exp.clone().into_bytes(),
-        ];
+        let mut ehd_chunks = vec![self.nonce.clone().into_bytes()];
+        match &tee_pubkey {
+            TeePubKey::Rsa(key) => {
+                ehd_chunks.push(key.k_mod.clone().into_bytes());
+                ehd_chunks.push(key.k_exp.clone().into_bytes());
+            }
+            TeePubKey::Ec(key) => {
+                ehd_chunks.push(key.x.clone().into_bytes());
+                ehd_chunks.push(key.y.clone().into_bytes());
+            }
+        }
+
+        // Bind the evidence to the TLS session the KBS receives it over.
+        if self.config.channel_binding {
+            let exporter = self
+                .bound
+                .as_ref()
+                .map(BoundSession::channel_binding)
+                .ok_or_else(|| anyhow!("No TLS session with the KBS to bind the evidence to"))?;
+            ehd_chunks.push(base64::encode_config(exporter, base64::URL_SAFE_NO_PAD).into_bytes());
+        }
 
         let ehd = hash_chunks(ehd_chunks);
 
@@ -127,12 +281,28 @@ impl Kbc {
         })
     }
 
-    fn decrypt_response_output(&self, response: Response) -> Result<Vec<u8>> {
+    fn decrypt_response_output(&self, response: Jwe) -> Result<Vec<u8>> {
+        self.check_legacy_jwe(&response)?;
         let key = self
             .tee_key
             .clone()
-            .ok_or_else(|| anyhow!("TEE rsa key missing"))?;
-        response.decrypt_output(key)
+            .ok_or_else(|| anyhow!("TEE key missing"))?;
+        decrypt_response(&response, key)
+    }
+
+    // Refuse a JWE whose header is not authenticated, so that it cannot be
+    // stripped of its authentication, unless the KBS is known to be older.
+    fn check_legacy_jwe(&self, response: &Jwe) -> Result<()> {
+        if !response.legacy_header {
+            return Ok(());
+        }
+
+        let v0_1_0 =
+            self.protocol_version.or(self.config.protocol_version) == Some(ProtocolVersion::V0_1_0);
+        if self.config.reject_rsa1_5 || !(v0_1_0 || self.config.allow_legacy_jwe) {
+            bail!("KBS response with an unauthenticated JWE header is rejected");
+        }
+        Ok(())
     }
 
     fn tee(&self) -> &str {
@@ -147,63 +317,291 @@ impl Kbc {
         &mut self.http_client
     }
 
-    async fn establish_kbs_session(&mut self) -> Result<()> {
+    // Send a request to the KBS, retrying transient failures until the
+    // deadline. The certificate pins are checked in the TLS handshakes.
+    async fn send(
+        &self,
+        request: reqwest::RequestBuilder,
+        deadline: Instant,
+    ) -> Result<reqwest::Response> {
+        let timeout = Duration::from_secs(KBS_REQ_TIMEOUT_SEC);
+        retry::send(request, &self.config.retry, deadline, timeout).await
+    }
+
+    // POST `body` as JSON to the KBS, over the bound TLS session while
+    // attesting with channel binding, and return the status and body of
+    // the response.
+    async fn post_json(
+        &mut self,
+        url: String,
+        body: &impl serde::Serialize,
+        deadline: Instant,
+    ) -> Result<(reqwest::StatusCode, String)> {
+        let Some(session) = &mut self.bound else {
+            let request = self
+                .http_client()
+                .post(url)
+                .header("Content-Type", "application/json")
+                .json(body);
+            let response = self.send(request, deadline).await?;
+            return Ok((response.status(), response.text().await?));
+        };
+
+        let timeout = Duration::from_secs(KBS_REQ_TIMEOUT_SEC)
+            .min(deadline.saturating_duration_since(Instant::now()));
+        tokio::time::timeout(timeout, session.post_json(&url, body, &self.cookies))
+            .await
+            .map_err(|_| anyhow!("KBS request {url} timed out"))?
+    }
+
+    // Path prefix of the KBS endpoints for the negotiated protocol version.
+    fn url_prefix(&self) -> &'static str {
+        self.protocol_version
+            .or(self.config.protocol_version)
+            .unwrap_or(SUPPORTED_PROTOCOL_VERSIONS[0])
+            .url_prefix()
+    }
+
+    fn extra_params(&self) -> ExtraParams {
+        ExtraParams {
+            supported_key_algorithms: self
+                .tee_key
+                .as_ref()
+                .map(TeeKey::supported_algorithms)
+                .unwrap_or_default(),
+            channel_binding: self
+                .config
+                .channel_binding
+                .then(|| "tls-exporter".to_string()),
+            other: self.config.extra_params.clone(),
+        }
+    }
+
+    // Send the auth request with each protocol version, newest first, until
+    // the KBS accepts one. A pinned version is the only one tried.
+    async fn request_challenge(&mut self, deadline: Instant) -> Result<Challenge> {
         let kbs_uri = self.kbs_uri().to_string();
+        let versions = match self.config.protocol_version {
+            Some(version) => vec![version],
+            None => SUPPORTED_PROTOCOL_VERSIONS.to_vec(),
+        };
+        let extra_params = self.extra_params();
+
+        let mut refusals = Vec::new();
+        for version in versions {
+            let request = Request::new(version, self.tee().to_string(), &extra_params)?;
+            let url = format!("{kbs_uri}{}/auth", version.url_prefix());
+            let (status, body) = self.post_json(url, &request, deadline).await?;
+
+            if status == reqwest::StatusCode::OK {
+                self.protocol_version = Some(version);
+                return Ok(serde_json::from_str::<Challenge>(&body)?);
+            }
+            if !status.is_client_error() {
+                bail!("KBS Server Internal Failed, Response: {:?}", body)
+            }
 
-        let challenge = self
-            .http_client()
-            .post(format!("{kbs_uri}{KBS_URL_PREFIX}/auth"))
-            .header("Content-Type", "application/json")
-            .json(&Request::new(self.tee().to_string()))
-            .send()
-            .await?
-            .json::<Challenge>()
-            .await?;
+            log::info!("CC-KBC: KBS refused protocol version {version} ({status})");
+            refusals.push(format!("{version}: {status} {body}"));
+        }
+
+        bail!(
+            "KBS refused all protocol versions of this client: {}",
+            refusals.join(", ")
+        )
+    }
+
+    // Fail early if the KBS tells it cannot wrap keys for our TEE key.
+    fn check_kbs_params(&self, kbs_params: &ExtraParams) -> Result<()> {
+        let (Some(key), false) = (
+            &self.tee_key,
+            kbs_params.supported_key_algorithms.is_empty(),
+        ) else {
+            return Ok(());
+        };
+
+        let supported = &kbs_params.supported_key_algorithms;
+        if !supported.iter().any(|alg| alg == key.algorithm()) {
+            bail!(
+                "KBS does not support TEE key algorithm {}, only {}",
+                key.algorithm(),
+                supported.join(", ")
+            );
+        }
+
+        Ok(())
+    }
+
+    async fn establish_kbs_session(&mut self, deadline: Instant) -> Result<()> {
+        if !self.config.channel_binding {
+            return self.attest(deadline).await;
+        }
+
+        // `auth` and `attest` go over one TLS session, which starts over
+        // if the KBS closes it in between.
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            self.bound = Some(BoundSession::connect(&self.kbs_uri, &self.config).await?);
+            let result = self.attest(deadline).await;
+            self.bound = None;
+
+            match result {
+                Err(e)
+                    if binding::is_closed(&e)
+                        && attempt < self.config.retry.max_attempts
+                        && Instant::now() < deadline =>
+                {
+                    log::info!("CC-KBC: KBS closed the bound TLS session ({e}), attesting again");
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn attest(&mut self, deadline: Instant) -> Result<()> {
+        let challenge = self.request_challenge(deadline).await?;
         self.nonce = challenge.nonce.clone();
+        self.check_kbs_params(&challenge.extra_params()?)?;
 
-        let attest_response = self
-            .http_client()
-            .post(format!("{kbs_uri}{KBS_URL_PREFIX}/attest"))
-            .header("Content-Type", "application/json")
-            .json(&self.generate_evidence()?)
-            .send()
-            .await?;
+        let attest_url = format!("{}{}/attest", self.kbs_uri(), self.url_prefix());
+        let evidence = self.generate_evidence()?;
+        let (status, body) = self.post_json(attest_url, &evidence, deadline).await?;
 
-        match attest_response.status() {
+        match status {
             reqwest::StatusCode::OK => {
+                // Older KBS only set a session cookie and return no token.
+                self.token = match body.trim() {
+                    "" if self.token_keys.is_some() => {
+                        bail!("KBS returned no attestation token to verify")
+                    }
+                    "" => None,
+                    body => {
+                        let response = serde_json::from_str::<AttestationResponse>(body)
+                            .map_err(|e| anyhow!("Invalid KBS attest response: {e}"))?;
+                        Some(self.accept_token(response.token)?)
+                    }
+                };
                 self.authenticated = true;
                 Ok(())
             }
             reqwest::StatusCode::UNAUTHORIZED => {
-                let error_info = attest_response.json::<ErrorInformation>().await?;
+                let error_info = serde_json::from_str::<ErrorInformation>(&body)?;
                 bail!("KBS attest unauthorized, Error Info: {:?}", error_info)
             }
             _ => {
-                bail!(
-                    "KBS Server Internal Failed, Response: {:?}",
-                    attest_response.text().await?
-                )
+                bail!("KBS Server Internal Failed, Response: {:?}", body)
+            }
+        }
+    }
+
+    // Verify the attestation token if a token key is configured.
+    fn accept_token(&self, raw: String) -> Result<Token> {
+        let Some(keys) = &self.token_keys else {
+            return Ok(Token::new(raw));
+        };
+
+        let tee_pubkey = self
+            .tee_key
+            .as_ref()
+            .ok_or_else(|| anyhow!("TEE key missing"))?
+            .export_pubkey()?;
+        Token::verify(raw, keys, &tee_pubkey)
+    }
+
+    // Token presented to the KBS, and whether it was verified.
+    fn current_token(&self) -> (Option<Token>, bool) {
+        match &self.passport {
+            Some(passport) => (passport.current(), passport.is_verified()),
+            None => (self.token.clone(), self.token_keys.is_some()),
+        }
+    }
+
+    /// Claims of the attestation token of the current session, or of the
+    /// attestation service in passport mode, such as its `tcb-status`, if
+    /// the token was verified.
+    pub fn token_claims(&self) -> Option<Map<String, Value>> {
+        match self.current_token() {
+            (Some(token), true) => Some(token.claims().clone()),
+            _ => None,
+        }
+    }
+
+    // KBS of a key share, reached with the scheme of the configured KBS. Only
+    // the KBSes with their own entry in the configuration are connected to.
+    fn resource_kbs(&mut self, name: &str) -> Result<&mut Kbc> {
+        match self.resource_kbses.entry(name.to_string()) {
+            Entry::Occupied(entry) => Ok(entry.into_mut()),
+            Entry::Vacant(entry) => {
+                let Some(config) = self.configured_kbses.get(name) else {
+                    bail!(
+                        "The resource KBS host {name} differs from the KBS URL one {} and is not configured",
+                        kbs_addr(&self.kbs_address)
+                    );
+                };
+                let scheme = match &self.kbs_address {
+                    KbsAddress::Http(url) => url.scheme(),
+                    _ => "https",
+                };
+                let mut kbc = Kbc::connect(
+                    format!("{scheme}://{name}").parse()?,
+                    config.clone(),
+                    self.tee_key.clone(),
+                    self.passport.clone(),
+                )?;
+                if kbc.passport.is_none() && kbc.config.session_cache.is_some() {
+                    if let Err(e) = kbc.restore_session() {
+                        log::info!("CC-KBC: no KBS session restored for {name}: {e}");
+                    }
+                }
+                Ok(entry.insert(kbc))
             }
         }
     }
 
-    async fn request_kbs_resource(&mut self, resource_url: String) -> Result<Response> {
+    async fn request_kbs_resource(&mut self, resource: &ResourceUri) -> Result<Jwe> {
+        // Check the resource belongs to this KBS before attesting.
+        self.resource_to_kbs_uri(resource)?;
+
+        let deadline = self.config.retry.deadline();
+
         for attempt in 1..=KBS_GET_RESOURCE_MAX_ATTEMPT {
             log::info!("CC-KBC: trying to get resource, attempt {attempt}");
 
-            if !self.authenticated {
-                self.establish_kbs_session().await?;
+            // No session is set up with the KBS in passport mode.
+            if self.passport.is_none() {
+                let expiry_margin = Duration::from_secs(KBS_TOKEN_EXPIRY_MARGIN_SEC);
+                if matches!(&self.token, Some(token) if token.expires_within(expiry_margin)) {
+                    log::info!("CC-KBC: KBS token is about to expire, re-attesting");
+                    self.authenticated = false;
+                }
+
+                if !self.authenticated {
+                    self.establish_kbs_session(deadline).await?;
+                    self.save_session(deadline).await;
+                }
             }
 
-            let res = self.http_client().get(&resource_url).send().await?;
+            // The path prefix depends on the negotiated protocol version.
+            let resource_url = self.resource_to_kbs_uri(resource)?;
+
+            let request = self.resource_request(&resource_url, deadline).await?;
+            let res = self.send(request, deadline).await?;
 
             match res.status() {
                 reqwest::StatusCode::OK => {
-                    let response = res.json::<Response>().await?;
+                    // The KBS may answer in flattened JSON or compact serialization.
+                    let response = res.text().await?.parse::<Jwe>()?;
                     return Ok(response);
                 }
                 reqwest::StatusCode::UNAUTHORIZED => {
-                    self.authenticated = false;
+                    match &self.passport {
+                        Some(passport) => passport.refresh(),
+                        None => {
+                            self.authenticated = false;
+                            self.token = None;
+                        }
+                    }
                     continue;
                 }
                 reqwest::StatusCode::NOT_FOUND => {
@@ -221,18 +619,149 @@ impl Kbc {
         bail!("Request KBS resource: Attested but KBS still return Unauthorized")
     }
 
+    // Resource request presenting the token of the session, or in passport
+    // mode the token of the attestation service with a DPoP proof of
+    // possession of the TEE key.
+    async fn resource_request(
+        &self,
+        url: &str,
+        deadline: Instant,
+    ) -> Result<reqwest::RequestBuilder> {
+        let request = self.http_client.get(url);
+        let Some(passport) = &self.passport else {
+            return Ok(match &self.token {
+                Some(token) => request.bearer_auth(token.as_str()),
+                None => request,
+            });
+        };
+
+        let token = passport.token(deadline).await?;
+        let key = self
+            .tee_key
+            .as_ref()
+            .ok_or_else(|| anyhow!("TEE key missing"))?;
+        let proof = passport::dpop_proof(key, "GET", &self.public_url(url), &token)?;
+        Ok(request
+            .header(
+                reqwest::header::AUTHORIZATION,
+                format!("DPoP {}", token.as_str()),
+            )
+            .header("DPoP", proof))
+    }
+
+    // URL of a request to the KBS at its own address rather than through
+    // the relay, which DPoP proofs are signed over.
+    fn public_url(&self, url: &str) -> String {
+        match url.strip_prefix(self.kbs_uri()) {
+            Some(path) if self.relay.is_some() => format!("{}/{path}", self.kbs_address),
+            _ => url.to_string(),
+        }
+    }
+
+    // Name of the secret the session cache key is derived from in the user
+    // keyring.
+    fn session_secret_name(&self) -> String {
+        format!("attestation-agent:cc_kbc:{}", kbs_addr(&self.kbs_address))
+    }
+
+    // Restore the session saved by a former run of the agent, unless it has
+    // expired.
+    fn restore_session(&mut self) -> Result<()> {
+        let Some(cache) = &self.config.session_cache else {
+            return Ok(());
+        };
+        let secret = keyring::find(&self.session_secret_name())
+            .ok_or_else(|| anyhow!("no session key in the user keyring"))?;
+        let session = session::load(&cache.path, &secret)?;
+        if session.kbs != kbs_addr(&self.kbs_address) {
+            bail!("saved session is for KBS {}", session.kbs);
+        }
+
+        let tee_key = Zeroizing::new(base64::decode(&session.tee_key)?);
+        self.tee_key = Some(TeeKey::import_private_key(&tee_key, &self.config)?);
+        let token = session
+            .token
+            .map(|token| self.accept_token(token))
+            .transpose()?;
+        let expiry_margin = Duration::from_secs(KBS_TOKEN_EXPIRY_MARGIN_SEC);
+        if matches!(&token, Some(token) if token.expires_within(expiry_margin)) {
+            bail!("saved session has expired");
+        }
+
+        self.protocol_version = session
+            .protocol_version
+            .map(|version| version.parse())
+            .transpose()?;
+        for cookie in session
+            .cookies
+            .iter()
+            .flat_map(|cookies| cookies.split("; "))
+        {
+            self.cookies.add_cookie_str(cookie, &self.kbs_uri);
+        }
+        self.token = token;
+        self.authenticated = true;
+        log::info!("CC-KBC: restored the session with KBS {}", self.kbs_address);
+        Ok(())
+    }
+
+    // Save the session just set up, if configured. A failure only costs an
+    // attestation at the next start of the agent.
+    async fn save_session(&mut self, deadline: Instant) {
+        if let Err(e) = self.try_save_session(deadline).await {
+            log::warn!("CC-KBC: save KBS session failed: {e:?}");
+        }
+    }
+
+    async fn try_save_session(&mut self, deadline: Instant) -> Result<()> {
+        let Some(cache) = self.config.session_cache.clone() else {
+            return Ok(());
+        };
+
+        let secret_name = self.session_secret_name();
+        let secret = match keyring::find(&secret_name) {
+            Some(secret) => secret,
+            None => {
+                let resource = ResourceUri::new(&kbs_addr(&self.kbs_address), &cache.key_resource)?;
+                let url = self.resource_to_kbs_uri(&resource)?;
+                let request = self.resource_request(&url, deadline).await?;
+                let response = self.send(request, deadline).await?;
+                if response.status() != reqwest::StatusCode::OK {
+                    bail!(
+                        "Get session key resource {} failed: {}",
+                        cache.key_resource,
+                        response.status()
+                    );
+                }
+                let secret = Zeroizing::new(
+                    self.decrypt_response_output(response.text().await?.parse::<Jwe>()?)?,
+                );
+                keyring::store(&secret_name, &secret)?;
+                secret
+            }
+        };
+
+        let tee_key = self
+            .tee_key
+            .as_ref()
+            .ok_or_else(|| anyhow!("TEE key missing"))?
+            .export_private_key()?;
+        let session = Session {
+            kbs: kbs_addr(&self.kbs_address),
+            tee_key: base64::encode(tee_key),
+            protocol_version: self.protocol_version.map(|version| version.to_string()),
+            token: self.token.as_ref().map(|token| token.as_str().to_string()),
+            cookies: self
+                .cookies
+                .cookies(&self.kbs_uri)
+                .and_then(|cookies| cookies.to_str().ok().map(String::from)),
+        };
+        session::save(&cache.path, &secret, &session)
+    }
+
     /// Convert a [`ResourceUri`] to a KBS URL.
     pub fn resource_to_kbs_uri(&self, resource: &ResourceUri) -> Result<String> {
-        let kbs_host = self
-            .kbs_uri
-            .host_str()
-            .ok_or_else(|| anyhow!("Invalid URL: {}", self.kbs_uri))?;
-
-        let kbs_addr = if let Some(port) = self.kbs_uri.port() {
-            format!("{kbs_host}:{port}")
-        } else {
-            kbs_host.to_string()
-        };
+        let kbs_addr = kbs_addr(&self.kbs_address);
 
         if !resource.kbs_addr.is_empty() && resource.kbs_addr != kbs_addr {
             bail!(
@@ -242,22 +771,49 @@ impl Kbc {
         }
 
         let kbs_addr = &self.kbs_uri();
+        let prefix = self.url_prefix();
         let repo = &resource.repository;
         let r#type = &resource.r#type;
         let tag = &resource.tag;
-        Ok(format!(
-            "{kbs_addr}{KBS_URL_PREFIX}/resource/{repo}/{type}/{tag}"
-        ))
+        Ok(format!("{kbs_addr}{prefix}/resource/{repo}/{type}/{tag}"))
     }
 }
 
-fn build_http_client() -> Result<reqwest::Client> {
-    reqwest::Client::builder()
-        .cookie_store(true)
-        .user_agent(format!(
-            "attestation-agent-cc-kbc/{}",
-            env!("CARGO_PKG_VERSION")
-        ))
+/// Get the `<kbs_host>:<kbs_port>` address of a KBS URL, which is how
+/// a KBS is named in KBS Resource URIs and in the configuration. A KBS
+/// over a Unix socket or vsock is named by its whole address, and only
+/// matches resource URIs without host.
+fn kbs_addr(kbs_address: &KbsAddress) -> String {
+    let KbsAddress::Http(kbs_uri) = kbs_address else {
+        return kbs_address.to_string();
+    };
+
+    let kbs_host = kbs_uri.host_str().unwrap_or_default();
+    if let Some(port) = kbs_uri.port() {
+        format!("{kbs_host}:{port}")
+    } else {
+        kbs_host.to_string()
+    }
+}
+
+fn build_http_client(
+    config: &KbsConfig,
+    direct: bool,
+    cookies: Arc<Jar>,
+) -> Result<reqwest::Client> {
+    let builder = tls::configure(reqwest::Client::builder(), config)?;
+    let builder = match direct {
+        // The relay listens on a loopback port, which no proxy can reach.
+        true if config.proxy.is_some() => {
+            bail!("No proxy can be used with a KBS over a Unix socket or vsock, or with channel binding")
+        }
+        true => builder.no_proxy(),
+        false => proxy::configure(builder, config.proxy.as_ref())?,
+    };
+
+    builder
+        .cookie_provider(cookies)
+        .user_agent(KBS_USER_AGENT)
         .timeout(Duration::from_secs(KBS_REQ_TIMEOUT_SEC))
         .build()
         .map_err(|e| anyhow!("Build KBS http client failed: {:?}", e))
@@ -265,8 +821,8 @@ fn build_http_client() -> Result<reqwest::Client> {
 
 #[cfg(test)]
 mod tests {
-    use super::ResourceUri;
-    use crate::kbc_modules::cc_kbc::Kbc;
+    use super::{Jwe, ProtocolVersion, ResourceUri};
+    use crate::kbc_modules::cc_kbc::{config::KbsConfig, Kbc};
 
     const RESOURCE_URL_PORT: &str = "kbs://127.0.0.1:8081/alice/cosign-key/213";
     const RESOURCE_URL_NO_PORT: &str = "kbs://127.0.0.1/alice/cosign-key/213";
@@ -328,4 +884,55 @@ mod tests {
     fn resource_no_host_to_kbs_uri() {
         to_kbs_uri(KBS_URL_PORT, RESOURCE_NO_HOST_URL, RESOURCE_KBS_URL_PORT);
     }
+
+    #[tokio::test]
+    async fn unix_socket_kbs() {
+        let address = "unix:///run/kbs.sock".parse().unwrap();
+        let kbc = Kbc::connect(address, KbsConfig::default(), None, None).expect("connect failed");
+        let relay_url = kbc.relay.as_ref().unwrap().url();
+
+        let resource: ResourceUri =
+            serde_json::from_str(&format!("\"{RESOURCE_NO_HOST_URL}\"")).unwrap();
+        assert_eq!(
+            kbc.resource_to_kbs_uri(&resource).unwrap(),
+            format!("{relay_url}/kbs/v0/resource/alice/cosign-key/213")
+        );
+
+        let resource: ResourceUri =
+            serde_json::from_str(&format!("\"{RESOURCE_URL_PORT}\"")).unwrap();
+        assert!(kbc.resource_to_kbs_uri(&resource).is_err());
+    }
+
+    #[rstest::rstest]
+    #[case(None, false, false, false)]
+    #[case(Some(ProtocolVersion::V0_1_1), false, false, false)]
+    #[case(Some(ProtocolVersion::V0_1_0), false, false, true)]
+    #[case(None, true, false, true)]
+    #[case(Some(ProtocolVersion::V0_1_0), false, true, false)]
+    #[case(None, true, true, false)]
+    fn legacy_jwe(
+        #[case] protocol_version: Option<ProtocolVersion>,
+        #[case] allow_legacy_jwe: bool,
+        #[case] reject_rsa1_5: bool,
+        #[case] accepted: bool,
+    ) {
+        let config = KbsConfig {
+            allow_legacy_jwe,
+            reject_rsa1_5,
+            ..Default::default()
+        };
+        let mut kbc = Kbc::connect(KBS_URL_PORT.parse().unwrap(), config, None, None).unwrap();
+        kbc.protocol_version = protocol_version;
+
+        let legacy: Jwe =
+            r#"{"protected":"{\"alg\":\"RSA1_5\",\"enc\":\"A256GCM\"}","iv":"","ciphertext":""}"#
+                .parse()
+                .unwrap();
+        assert_eq!(kbc.check_legacy_jwe(&legacy).is_ok(), accepted);
+
+        let jwe: Jwe = r#"{"protected":"eyJhbGciOiJSU0ExXzUiLCJlbmMiOiJBMjU2R0NNIn0","iv":"","ciphertext":""}"#
+            .parse()
+            .unwrap();
+        assert!(kbc.check_legacy_jwe(&jwe).is_ok());
+    }
 }
@@ -0,0 +1,478 @@
+// Copyright (c) 2023 Alibaba Cloud
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Passport mode: the TEE attests once to an attestation service, and
+//! presents the token it issues to the resource KBSes instead of evidence,
+//! with an RFC 9449 DPoP proof of possession of the TEE key bound to the
+//! token. The token is refreshed in the background before it expires.
+
+use super::{crypto::TeeKey, token::Token, Kbc};
+use anyhow::*;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    sync::{watch, Notify},
+    task::JoinHandle,
+};
+
+// Refresh the token this long before it expires, so that it is still
+// valid when a resource KBS checks it.
+const REFRESH_MARGIN_SEC: u64 = 120;
+
+// Refresh interval of tokens without expiry.
+const REFRESH_INTERVAL_SEC: u64 = 3600;
+
+/// Attestation service issuing the token presented to the resource KBSes.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct PassportConfig {
+    /// Address of the attestation service, in any form of KBS address.
+    /// It is configured like a KBS, under its own name.
+    pub attestation_service: String,
+}
+
+/// Token of the attestation service, kept fresh by a background task which
+/// is stopped when dropped.
+pub struct Passport {
+    service: String,
+    verified: bool,
+    token: Arc<watch::Sender<Option<Token>>>,
+    refresh: Arc<Notify>,
+    task: JoinHandle<()>,
+}
+
+impl Passport {
+    /// Start attesting to the attestation service of `service`. Must be
+    /// called within a tokio runtime.
+    pub fn start(service: Kbc) -> Result<Passport> {
+        let runtime = tokio::runtime::Handle::try_current().map_err(|_| {
+            anyhow!(
+                "Passport mode with attestation service {} needs a tokio runtime",
+                service.kbs_address
+            )
+        })?;
+
+        let (address, verified) = (
+            service.kbs_address.to_string(),
+            service.token_keys.is_some(),
+        );
+        let token = Arc::new(watch::Sender::new(None));
+        let refresh = Arc::new(Notify::new());
+        let task = runtime.spawn(run(service, token.clone(), refresh.clone()));
+
+        Ok(Passport {
+            service: address,
+            verified,
+            token,
+            refresh,
+            task,
+        })
+    }
+
+    /// Wait until a valid token is available, at most until `deadline`.
+    pub async fn token(&self, deadline: Instant) -> Result<Token> {
+        let mut receiver = self.token.subscribe();
+        let valid = receiver.wait_for(
+            |token| matches!(token, Some(token) if !token.expires_within(Duration::ZERO)),
+        );
+
+        let token = match tokio::time::timeout_at(deadline.into(), valid).await {
+            Result::Ok(Result::Ok(token)) => token.clone(),
+            Result::Ok(Err(_)) => bail!("Passport attestation stopped"),
+            Err(_) => bail!("No token from the attestation service before the deadline"),
+        };
+        token.ok_or_else(|| anyhow!("No token from the attestation service"))
+    }
+
+    /// Address of the attestation service.
+    pub fn service(&self) -> &str {
+        &self.service
+    }
+
+    /// Whether the tokens are verified with a key of the attestation service.
+    pub fn is_verified(&self) -> bool {
+        self.verified
+    }
+
+    /// Latest token of the attestation service.
+    pub fn current(&self) -> Option<Token> {
+        self.token.borrow().clone()
+    }
+
+    /// Drop the token refused by a KBS, and attest again now.
+    pub fn refresh(&self) {
+        self.token.send_replace(None);
+        self.refresh.notify_one();
+    }
+}
+
+impl Drop for Passport {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn run(mut service: Kbc, token: Arc<watch::Sender<Option<Token>>>, refresh: Arc<Notify>) {
+    let mut failures = 0;
+    loop {
+        let delay = match attest(&mut service).await {
+            Result::Ok(issued) => {
+                failures = 0;
+                let delay = match issued.expires_in() {
+                    // Short-lived tokens are refreshed halfway through.
+                    Some(lifetime) => lifetime
+                        .saturating_sub(Duration::from_secs(REFRESH_MARGIN_SEC))
+                        .max(lifetime / 2),
+                    None => Duration::from_secs(REFRESH_INTERVAL_SEC),
+                };
+                log::info!(
+                    "CC-KBC: token of attestation service {} refreshed, next refresh in {delay:?}",
+                    service.kbs_address
+                );
+                token.send_replace(Some(issued));
+                delay
+            }
+            Err(e) => {
+                failures += 1;
+                let delay = service.config.retry.backoff(failures);
+                log::warn!(
+                    "CC-KBC: attestation to {} failed, retrying in {delay:?}: {e:?}",
+                    service.kbs_address
+                );
+                delay
+            }
+        };
+
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = refresh.notified() => {}
+        }
+    }
+}
+
+async fn attest(service: &mut Kbc) -> Result<Token> {
+    let deadline = service.config.retry.deadline();
+    service.establish_kbs_session(deadline).await?;
+    service
+        .token
+        .take()
+        .ok_or_else(|| anyhow!("Attestation service returned no token"))
+}
+
+/// DPoP proof (RFC 9449) of a `method` request to `url` presenting `token`,
+/// signed with the TEE key the token was issued for.
+pub fn dpop_proof(key: &TeeKey, method: &str, url: &str, token: &Token) -> Result<String> {
+    let encode = |data: &[u8]| base64::encode_config(data, base64::URL_SAFE_NO_PAD);
+
+    let claims = serde_json::json!({
+        "jti": encode(&rand::random::<[u8; 16]>()),
+        "htm": method,
+        "htu": url,
+        "iat": SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        "ath": encode(&Sha256::digest(token.as_str())),
+    });
+
+    let header = serde_json::json!({
+        "typ": "dpop+jwt",
+        "alg": key.signature_algorithm(),
+        "jwk": key.public_jwk()?,
+    });
+
+    let signing_input = format!(
+        "{}.{}",
+        encode(&serde_json::to_vec(&header)?),
+        encode(&serde_json::to_vec(&claims)?)
+    );
+    let signature = key.sign(signing_input.as_bytes())?;
+    Ok(format!("{signing_input}.{}", encode(&signature)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kbc_modules::{
+        cc_kbc::{attester::sample::SampleAttester, config::KbsConfig, crypto::TeeKeyAlgorithm},
+        uri::ResourceUri,
+        KbcInterface,
+    };
+    use p256::ecdsa::signature::Verifier;
+    use std::path::Path;
+    use tokio::{
+        io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+        net::{TcpListener, UnixListener},
+        sync::mpsc,
+    };
+
+    fn decode(part: &str) -> serde_json::Value {
+        let json = base64::decode_config(part, base64::URL_SAFE_NO_PAD).unwrap();
+        serde_json::from_slice(&json).unwrap()
+    }
+
+    fn ec_key() -> TeeKey {
+        TeeKey::new(&KbsConfig {
+            tee_key_algorithm: TeeKeyAlgorithm::EcP256,
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn proof() {
+        let key = ec_key();
+        let token = Token::new("header.e30.signature".to_string());
+        let url = "https://kbs.example.org/kbs/v0/resource/default/key/1";
+
+        let proof = dpop_proof(&key, "GET", url, &token).unwrap();
+        let parts: Vec<&str> = proof.split('.').collect();
+        let [header, claims, signature] = parts[..] else {
+            panic!("DPoP proof is not a JWS");
+        };
+
+        let header = decode(header);
+        assert_eq!(header["typ"], "dpop+jwt");
+        assert_eq!(header["alg"], "ES256");
+        assert_eq!(header["jwk"], key.public_jwk().unwrap());
+        assert!(header["jwk"].get("d").is_none());
+
+        let claims = decode(claims);
+        assert_eq!(claims["htm"], "GET");
+        assert_eq!(claims["htu"], url);
+        assert_eq!(
+            claims["ath"],
+            base64::encode_config(
+                Sha256::digest("header.e30.signature"),
+                base64::URL_SAFE_NO_PAD
+            )
+        );
+
+        let TeeKey::EcP256(secret) = &key else {
+            unreachable!()
+        };
+        let verifying_key = p256::ecdsa::VerifyingKey::from(secret.public_key());
+        let signature = p256::ecdsa::Signature::try_from(
+            base64::decode_config(signature, base64::URL_SAFE_NO_PAD)
+                .unwrap()
+                .as_slice(),
+        )
+        .unwrap();
+        let signing_input = &proof[..proof.rfind('.').unwrap()];
+        assert!(verifying_key
+            .verify(signing_input.as_bytes(), &signature)
+            .is_ok());
+    }
+
+    #[test]
+    fn no_runtime() {
+        let address = "http://as.example.org:8080".parse().unwrap();
+        let service = Kbc::connect(address, KbsConfig::default(), None, None).unwrap();
+        assert!(Passport::start(service).is_err());
+    }
+
+    // Answer each request with `respond(request head)`, and send back the
+    // request heads.
+    async fn serve(respond: fn(&str) -> String) -> (String, mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(answer(stream, respond, sender.clone()));
+            }
+        });
+
+        (format!("http://{addr}"), receiver)
+    }
+
+    // The same over the Unix socket `path`.
+    async fn serve_unix(
+        path: &Path,
+        respond: fn(&str) -> String,
+    ) -> (String, mpsc::UnboundedReceiver<String>) {
+        let _ = std::fs::remove_file(path);
+        let listener = UnixListener::bind(path).unwrap();
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(answer(stream, respond, sender.clone()));
+            }
+        });
+
+        (format!("unix://{}", path.display()), receiver)
+    }
+
+    async fn answer(
+        mut stream: impl AsyncRead + AsyncWrite + Unpin,
+        respond: fn(&str) -> String,
+        sender: mpsc::UnboundedSender<String>,
+    ) {
+        let mut request = Vec::new();
+        let mut buf = [0; 4096];
+        while !String::from_utf8_lossy(&request).contains("\r\n\r\n") {
+            let len = stream.read(&mut buf).await.unwrap();
+            if len == 0 {
+                return;
+            }
+            request.extend(&buf[..len]);
+        }
+
+        let head = String::from_utf8_lossy(&request).to_string();
+        let response = respond(&head);
+        stream.write_all(response.as_bytes()).await.unwrap();
+        let _ = sender.send(head);
+    }
+
+    fn response(status: &str, body: &str) -> String {
+        format!(
+            "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )
+    }
+
+    fn attestation_service(request: &str) -> String {
+        if request.starts_with("POST /kbs/v0/auth ") {
+            return response("200 OK", r#"{"nonce":"42","extra-params":{}}"#);
+        }
+
+        let exp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 3600;
+        let claims = base64::encode_config(format!(r#"{{"exp":{exp}}}"#), base64::URL_SAFE_NO_PAD);
+        response(
+            "200 OK",
+            &format!(r#"{{"token":"eyJhbGciOiJFUzI1NiJ9.{claims}.c2ln"}}"#),
+        )
+    }
+
+    fn kbs(_: &str) -> String {
+        response("404 Not Found", "")
+    }
+
+    fn header<'a>(request: &'a str, name: &str) -> Option<&'a str> {
+        request.lines().find_map(|line| {
+            let (header, value) = line.split_once(": ")?;
+            header.eq_ignore_ascii_case(name).then_some(value)
+        })
+    }
+
+    #[tokio::test]
+    async fn present_token() {
+        let (as_url, mut as_requests) = serve(attestation_service).await;
+        let (kbs_url, mut kbs_requests) = serve(kbs).await;
+        let (other_kbs_url, mut other_kbs_requests) = serve(kbs).await;
+
+        let key = Some(ec_key());
+        let mut service = Kbc::connect(
+            as_url.parse().unwrap(),
+            KbsConfig::default(),
+            key.clone(),
+            None,
+        )
+        .unwrap();
+        service.tee = "sample".to_string();
+        service.attester = Some(Box::<SampleAttester>::default());
+        let passport = Arc::new(Passport::start(service).unwrap());
+        let mut kbc = Kbc::connect(
+            kbs_url.parse().unwrap(),
+            KbsConfig::default(),
+            key,
+            Some(passport),
+        )
+        .unwrap();
+
+        let other_kbs = other_kbs_url.trim_start_matches("http://");
+        let other_resource: ResourceUri =
+            serde_json::from_str(&format!("\"kbs://{other_kbs}/default/key/1\"")).unwrap();
+        // Other KBSes are only reached for key shares, and if configured.
+        let error = kbc.request_kbs_resource(&other_resource).await.unwrap_err();
+        assert!(error.to_string().contains("differs"));
+        let error = kbc.get_key_share(other_resource.clone()).await.unwrap_err();
+        assert!(error.to_string().contains("not configured"));
+        kbc.configured_kbses
+            .insert(other_kbs.to_string(), KbsConfig::default());
+
+        for (url, requests) in [
+            (&kbs_url, &mut kbs_requests),
+            (&other_kbs_url, &mut other_kbs_requests),
+        ] {
+            let resource: ResourceUri = serde_json::from_str(&format!(
+                "\"kbs://{}/default/key/1\"",
+                url.trim_start_matches("http://")
+            ))
+            .unwrap();
+            let error = kbc.get_key_share(resource).await.unwrap_err();
+            assert!(error.to_string().contains("Not Found"));
+
+            // The KBS only receives the resource request, with the token and
+            // a proof of possession of the TEE key.
+            let request = requests.recv().await.unwrap();
+            assert!(request.starts_with("GET /kbs/v0/resource/default/key/1 "));
+            let token = header(&request, "authorization").unwrap();
+            assert!(token.starts_with("DPoP eyJhbGciOiJFUzI1NiJ9."));
+            let proof = header(&request, "dpop").unwrap();
+            let claims = decode(proof.split('.').nth(1).unwrap());
+            assert_eq!(
+                claims["htu"],
+                format!("{url}/kbs/v0/resource/default/key/1")
+            );
+        }
+
+        // Attested once, to the attestation service only.
+        assert!(as_requests.recv().await.unwrap().contains("/auth "));
+        assert!(as_requests.recv().await.unwrap().contains("/attest "));
+        assert!(as_requests.try_recv().is_err());
+        assert!(kbc.check().unwrap().kbs_info.contains_key("token.exp"));
+    }
+
+    #[tokio::test]
+    async fn present_token_to_relayed_kbs() {
+        let (as_url, _) = serve(attestation_service).await;
+        let path =
+            std::env::temp_dir().join(format!("cc-kbc-passport-{}.sock", std::process::id()));
+        let (kbs_address, mut kbs_requests) = serve_unix(&path, kbs).await;
+
+        let key = Some(ec_key());
+        let mut service = Kbc::connect(
+            as_url.parse().unwrap(),
+            KbsConfig::default(),
+            key.clone(),
+            None,
+        )
+        .unwrap();
+        service.tee = "sample".to_string();
+        service.attester = Some(Box::<SampleAttester>::default());
+        let passport = Arc::new(Passport::start(service).unwrap());
+        let mut kbc = Kbc::connect(
+            kbs_address.parse().unwrap(),
+            KbsConfig::default(),
+            key,
+            Some(passport),
+        )
+        .unwrap();
+
+        let resource: ResourceUri = serde_json::from_str("\"kbs:///default/key/1\"").unwrap();
+        let error = kbc.request_kbs_resource(&resource).await.unwrap_err();
+        assert!(error.to_string().contains("Not Found"));
+
+        // The proof is signed over the address of the KBS, not the one of
+        // the relay the request went through.
+        let request = kbs_requests.recv().await.unwrap();
+        let proof = header(&request, "dpop").unwrap();
+        let claims = decode(proof.split('.').nth(1).unwrap());
+        assert_eq!(
+            claims["htu"],
+            format!("{kbs_address}/kbs/v0/resource/default/key/1")
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
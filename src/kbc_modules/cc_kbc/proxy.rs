@@ -0,0 +1,130 @@
+// Copyright (c) 2023 Alibaba Cloud
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Proxy of the connections to a KBS. Without proxy settings the
+//! `HTTP_PROXY`, `HTTPS_PROXY` and `NO_PROXY` environment variables apply.
+
+use anyhow::*;
+use reqwest::{ClientBuilder, NoProxy, Proxy};
+use serde::Deserialize;
+
+/// HTTP(S) proxy of the connections to a KBS.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ProxyConfig {
+    /// URL of the proxy, e.g. `http://proxy.example.org:3128`.
+    pub url: String,
+
+    /// User name for basic authentication to the proxy.
+    #[serde(default)]
+    pub username: Option<String>,
+
+    /// Password for basic authentication to the proxy.
+    #[serde(default)]
+    pub password: Option<String>,
+
+    /// Hosts, domains (with their subdomains) and IP networks reached
+    /// without the proxy, e.g. `kbs.internal` or `10.0.0.0/8`.
+    #[serde(default)]
+    pub no_proxy: Vec<String>,
+}
+
+/// Route the connections of a KBS HTTP client through the proxy.
+pub fn configure(builder: ClientBuilder, proxy: Option<&ProxyConfig>) -> Result<ClientBuilder> {
+    let Some(config) = proxy else {
+        return Ok(builder);
+    };
+
+    let mut proxy =
+        Proxy::all(&config.url).map_err(|e| anyhow!("Invalid proxy {}: {e}", config.url))?;
+    match (&config.username, &config.password) {
+        (Some(username), password) => {
+            proxy = proxy.basic_auth(username, password.as_deref().unwrap_or_default())
+        }
+        (None, None) => {}
+        (None, Some(_)) => bail!("Proxy password is set without username"),
+    }
+    proxy = proxy.no_proxy(NoProxy::from_string(&config.no_proxy.join(",")));
+
+    // An explicit proxy replaces the one from the environment.
+    Ok(builder.proxy(proxy))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        sync::oneshot,
+    };
+
+    // Answer one request with `body`, and send back the request head.
+    async fn stand_in(body: &'static str) -> (String, oneshot::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (sender, receiver) = oneshot::channel();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0; 4096];
+            let len = stream.read(&mut buf).await.unwrap();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+            let _ = sender.send(String::from_utf8_lossy(&buf[..len]).to_string());
+        });
+
+        (format!("http://{addr}"), receiver)
+    }
+
+    fn client(url: &str, no_proxy: Vec<String>) -> reqwest::Client {
+        let config = ProxyConfig {
+            url: url.to_string(),
+            username: Some("agent".to_string()),
+            password: Some("secret".to_string()),
+            no_proxy,
+        };
+        configure(reqwest::Client::builder(), Some(&config))
+            .unwrap()
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn through_proxy() {
+        let (proxy_url, request) = stand_in("proxied").await;
+        let client = client(&proxy_url, vec!["kbs.internal".to_string()]);
+
+        let response = client
+            .get("http://kbs.example.org/kbs/v0/auth")
+            .send()
+            .await
+            .expect("request through proxy failed");
+        assert_eq!(response.text().await.unwrap(), "proxied");
+
+        let request = request.await.unwrap();
+        assert!(request.starts_with("GET http://kbs.example.org/kbs/v0/auth HTTP/1.1\r\n"));
+        // base64("agent:secret")
+        assert!(request
+            .to_lowercase()
+            .contains("proxy-authorization: basic ywdlbnq6c2vjcmv0\r\n"));
+    }
+
+    #[tokio::test]
+    async fn bypass_proxy() {
+        let (proxy_url, _) = stand_in("proxied").await;
+        let (kbs_url, _) = stand_in("direct").await;
+        let client = client(&proxy_url, vec!["127.0.0.0/8".to_string()]);
+
+        let response = client
+            .get(format!("{kbs_url}/kbs/v0/auth"))
+            .send()
+            .await
+            .expect("direct request failed");
+        assert_eq!(response.text().await.unwrap(), "direct");
+    }
+}
@@ -0,0 +1,181 @@
+// Copyright (c) 2023 Alibaba Cloud
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! The KBS HTTP client only connects over TCP. A KBS behind a Unix socket
+//! or a vsock is reached through a relay from a loopback port of the agent.
+//! Any process of the guest can connect to that port, so only the
+//! connections of processes running as the user of the agent are relayed,
+//! as told by the owners of both ends of the connection in `/proc/net/tcp`.
+
+use crate::kbc_modules::kbs_address::KbsAddress;
+use anyhow::*;
+use std::net::{Ipv4Addr, SocketAddr};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+};
+
+const TCP_TABLE_PATH: &str = "/proc/net/tcp";
+
+/// Relay of the loopback connections to a KBS, stopped when dropped.
+pub struct Relay {
+    local_addr: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+impl Relay {
+    /// Start relaying to `address` the connections of processes of the same
+    /// user. Must be called within a tokio runtime.
+    pub fn start(address: KbsAddress) -> Result<Relay> {
+        let runtime = tokio::runtime::Handle::try_current()
+            .map_err(|_| anyhow!("KBS address {address} needs a tokio runtime"))?;
+        let _guard = runtime.enter();
+
+        let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .and_then(|listener| {
+                listener.set_nonblocking(true)?;
+                TcpListener::from_std(listener)
+            })
+            .map_err(|e| anyhow!("Start relay to KBS {address} failed: {e}"))?;
+        let local_addr = listener.local_addr()?;
+
+        let task = runtime.spawn(async move {
+            loop {
+                let (inbound, peer) = match listener.accept().await {
+                    Result::Ok(accepted) => accepted,
+                    Err(e) => {
+                        log::warn!("CC-KBC: relay to KBS {address} stopped: {e}");
+                        return;
+                    }
+                };
+                if let Err(e) = check_same_user(local_addr, peer) {
+                    log::warn!("CC-KBC: relay to KBS {address} refused {peer}: {e}");
+                    continue;
+                }
+
+                let address = address.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = relay(inbound, &address).await {
+                        log::warn!("CC-KBC: relay to KBS {address} failed: {e}");
+                    }
+                });
+            }
+        });
+
+        Ok(Relay { local_addr, task })
+    }
+
+    /// Base URL of the KBS through the relay.
+    pub fn url(&self) -> String {
+        format!("http://{}", self.local_addr)
+    }
+}
+
+impl Drop for Relay {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Check that the process connected from `peer` to the relay listening on
+/// `local` runs as the same user as the agent.
+fn check_same_user(local: SocketAddr, peer: SocketAddr) -> Result<()> {
+    let table = std::fs::read_to_string(TCP_TABLE_PATH)
+        .map_err(|e| anyhow!("Read {TCP_TABLE_PATH} failed: {e}"))?;
+    let uid =
+        |from, to| socket_uid(&table, from, to).ok_or_else(|| anyhow!("No socket {from} -> {to}"));
+    let (agent, client) = (uid(local, peer)?, uid(peer, local)?);
+    if agent != client {
+        bail!("Connection of uid {client} is not relayed");
+    }
+    Ok(())
+}
+
+/// Owner of the IPv4 socket from `local` to `remote` in the `table` of
+/// `/proc/net/tcp`.
+fn socket_uid(table: &str, local: SocketAddr, remote: SocketAddr) -> Option<u32> {
+    // Addresses are the hexadecimal `u32` in host byte order and port.
+    let hex = |addr: SocketAddr| match addr {
+        SocketAddr::V4(addr) => Some(format!(
+            "{:08X}:{:04X}",
+            u32::from_ne_bytes(addr.ip().octets()),
+            addr.port()
+        )),
+        SocketAddr::V6(_) => None,
+    };
+    let (local, remote) = (hex(local)?, hex(remote)?);
+
+    table.lines().skip(1).find_map(|line| {
+        let fields: Vec<_> = line.split_whitespace().collect();
+        if fields.get(1)? != &local || fields.get(2)? != &remote {
+            return None;
+        }
+        fields.get(7)?.parse().ok()
+    })
+}
+
+async fn relay(mut inbound: TcpStream, address: &KbsAddress) -> Result<()> {
+    let mut outbound = address.connect().await?;
+    tokio::io::copy_bidirectional(&mut inbound, &mut outbound).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddrV4;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::UnixListener,
+    };
+
+    #[tokio::test]
+    async fn relay_to_unix_socket() {
+        let path = std::env::temp_dir().join(format!("cc-kbc-relay-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0; 4096];
+            let _ = stream.read(&mut buf).await.unwrap();
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\nConnection: close\r\n\r\nkbs")
+                .await
+                .unwrap();
+        });
+
+        let relay = Relay::start(format!("unix://{}", path.display()).parse().unwrap()).unwrap();
+        let response = reqwest::Client::builder()
+            .no_proxy()
+            .build()
+            .unwrap()
+            .get(format!("{}/kbs/v0/auth", relay.url()))
+            .send()
+            .await
+            .expect("request through relay failed");
+        assert_eq!(response.text().await.unwrap(), "kbs");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn socket_owner() {
+        let table = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 0100007F:9C40 00000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 101 1 0000000000000000 100 0 0 10 0
+   1: 0100007F:9C40 0100007F:D431 01 00000000:00000000 00:00000000 00000000     0        0 102 1 0000000000000000 20 4 30 10 -1
+   2: 0100007F:D431 0100007F:9C40 01 00000000:00000000 00:00000000 00000000  1000        0 103 1 0000000000000000 20 4 30 10 -1";
+        let relay = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 40000).into();
+        let client = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 54321).into();
+        let other = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 54322).into();
+        assert_eq!(socket_uid(table, relay, client), Some(0));
+        assert_eq!(socket_uid(table, client, relay), Some(1000));
+        assert_eq!(socket_uid(table, other, relay), None);
+    }
+
+    #[test]
+    fn no_runtime() {
+        assert!(Relay::start("unix:///run/kbs.sock".parse().unwrap()).is_err());
+    }
+}
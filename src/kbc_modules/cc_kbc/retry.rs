@@ -0,0 +1,207 @@
+// Copyright (c) 2023 Alibaba Cloud
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Retries of the requests to a KBS. Connection errors, timeouts and
+//! 408, 429 and 5xx responses are retried with jittered exponential
+//! backoff, or after the delay of a `Retry-After` header, until the
+//! attempts or the deadline are exhausted.
+
+use anyhow::*;
+use rand::Rng;
+use reqwest::{header::RETRY_AFTER, RequestBuilder, Response, StatusCode};
+use serde::Deserialize;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Retry policy of the requests to a KBS.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryPolicy {
+    /// Maximum number of attempts of one request.
+    pub max_attempts: u32,
+
+    /// Backoff before the first retry, doubled at every retry.
+    pub initial_backoff_ms: u64,
+
+    /// Upper bound of the backoff.
+    pub max_backoff_ms: u64,
+
+    /// Deadline of a whole KBS operation (authentication, attestation
+    /// and resource retrieval) including all retries.
+    pub deadline_sec: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_backoff_ms: 500,
+            max_backoff_ms: 10_000,
+            deadline_sec: 120,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn deadline(&self) -> Instant {
+        Instant::now() + Duration::from_secs(self.deadline_sec)
+    }
+
+    // Backoff before retry `retry` (starting at 1): a random delay in the
+    // upper half of the exponential backoff, so that agents started at the
+    // same time spread their retries.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let backoff = self
+            .initial_backoff_ms
+            .saturating_mul(1u64 << retry.saturating_sub(1).min(32))
+            .min(self.max_backoff_ms);
+        Duration::from_millis(rand::thread_rng().gen_range(backoff / 2..=backoff))
+    }
+}
+
+fn is_transient(status: StatusCode) -> bool {
+    status == StatusCode::REQUEST_TIMEOUT
+        || status == StatusCode::TOO_MANY_REQUESTS
+        || status.is_server_error()
+}
+
+// Delay requested by a `Retry-After` header, in seconds or as an HTTP date.
+fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Result::Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(
+        date.duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+/// Send `request`, retrying transient failures. The last response is
+/// returned if it is still a transient failure once retries are exhausted,
+/// so that the caller can report it.
+pub async fn send(
+    request: RequestBuilder,
+    policy: &RetryPolicy,
+    deadline: Instant,
+    timeout: Duration,
+) -> Result<Response> {
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            bail!(
+                "KBS request deadline exceeded after {} attempts",
+                attempt - 1
+            );
+        }
+
+        let result = request
+            .try_clone()
+            .ok_or_else(|| anyhow!("KBS request cannot be retried"))?
+            .timeout(timeout.min(remaining))
+            .send()
+            .await;
+
+        let (failure, delay) = match &result {
+            Result::Ok(response) if is_transient(response.status()) => (
+                response.status().to_string(),
+                retry_after(response).unwrap_or_else(|| policy.backoff(attempt)),
+            ),
+            Err(e) if e.is_connect() || e.is_timeout() => (e.to_string(), policy.backoff(attempt)),
+            _ => return Ok(result?),
+        };
+
+        if attempt >= policy.max_attempts || Instant::now() + delay >= deadline {
+            log::warn!("CC-KBC: KBS request attempt {attempt} failed ({failure}), giving up");
+            return result.map_err(|e| anyhow!("KBS request failed after {attempt} attempts: {e}"));
+        }
+
+        log::warn!(
+            "CC-KBC: KBS request attempt {attempt} failed ({failure}), retrying in {delay:?}"
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    #[test]
+    fn backoff() {
+        let policy = RetryPolicy::default();
+        for (retry, max) in [(1, 500), (2, 1000), (3, 2000), (6, 10_000), (100, 10_000)] {
+            let backoff = policy.backoff(retry);
+            assert!(backoff >= Duration::from_millis(max / 2));
+            assert!(backoff <= Duration::from_millis(max));
+        }
+    }
+
+    // Serve one canned HTTP response per connection.
+    async fn serve(responses: Vec<&'static str>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            for response in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0; 4096];
+                let _ = stream.read(&mut buf).await;
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        format!("http://{addr}/")
+    }
+
+    const UNAVAILABLE: &str =
+        "HTTP/1.1 503 Service Unavailable\r\nRetry-After: 0\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+    const OK: &str = "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok";
+
+    #[rstest::rstest]
+    #[case(vec![UNAVAILABLE, UNAVAILABLE, OK], 5, StatusCode::OK)]
+    #[case(vec![UNAVAILABLE, UNAVAILABLE, OK], 2, StatusCode::SERVICE_UNAVAILABLE)]
+    #[tokio::test]
+    async fn retry_unavailable(
+        #[case] responses: Vec<&'static str>,
+        #[case] max_attempts: u32,
+        #[case] expected: StatusCode,
+    ) {
+        let url = serve(responses).await;
+        let policy = RetryPolicy {
+            max_attempts,
+            ..Default::default()
+        };
+
+        let request = reqwest::Client::new().get(url);
+        let response = send(request, &policy, policy.deadline(), Duration::from_secs(5))
+            .await
+            .expect("send failed");
+        assert_eq!(response.status(), expected);
+    }
+
+    #[tokio::test]
+    async fn deadline() {
+        let url = serve(vec![
+            "HTTP/1.1 503 Service Unavailable\r\nRetry-After: 3600\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        ])
+        .await;
+        let policy = RetryPolicy::default();
+
+        let started = Instant::now();
+        let request = reqwest::Client::new().get(url);
+        let response = send(request, &policy, policy.deadline(), Duration::from_secs(5))
+            .await
+            .expect("send failed");
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,250 @@
+// Copyright (c) 2023 Alibaba Cloud
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Persistence of the KBS session and of the TEE key across restarts of the
+//! agent, so that they do not force a new attestation. The session is saved
+//! in a file on a tmpfs inside the TEE, encrypted with AES-256-GCM under a
+//! key derived from a secret resource of the KBS. The secret itself is kept
+//! in the kernel user keyring, which outlives the agent but not the TEE, and
+//! is never written to a file.
+
+use aes_gcm::{aead::Aead, Aes256Gcm, KeyInit, Nonce};
+use anyhow::*;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{
+    fs,
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+use zeroize::Zeroizing;
+
+const SESSION_FILE_VERSION: u32 = 1;
+const SALT_LENGTH: usize = 32;
+const IV_LENGTH: usize = 12;
+const KDF_INFO: &[u8] = b"attestation-agent cc_kbc session";
+
+/// Where and how the KBS session is persisted.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct SessionCacheConfig {
+    /// File of the session, which should be on a tmpfs inside the TEE.
+    pub path: PathBuf,
+
+    /// Path `/<repository>/<type>/<tag>` of the KBS resource the key of the
+    /// file is derived from.
+    pub key_resource: String,
+}
+
+/// State of a KBS session, restored at the next start of the agent.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Session {
+    /// `<kbs_host>:<kbs_port>` of the KBS.
+    pub kbs: String,
+    /// Base64 private key material of the TEE key.
+    pub tee_key: String,
+    pub protocol_version: Option<String>,
+    pub token: Option<String>,
+    /// `Cookie` header of the session with the KBS.
+    pub cookies: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SessionFile {
+    version: u32,
+    salt: String,
+    iv: String,
+    ciphertext: String,
+}
+
+fn session_key(secret: &[u8], salt: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+    let mut key = Zeroizing::new(vec![0; 32]);
+    let other_info = [KDF_INFO, salt].concat();
+    concat_kdf::derive_key_into::<Sha256>(secret, &other_info, &mut key)
+        .map_err(|e| anyhow!("Derive session key failed: {e:?}"))?;
+    Ok(key)
+}
+
+/// Encrypt `session` with a key derived from `secret`, and save it to
+/// `path`, readable by the agent only.
+pub fn save(path: &Path, secret: &[u8], session: &Session) -> Result<()> {
+    let salt: [u8; SALT_LENGTH] = rand::random();
+    let iv: [u8; IV_LENGTH] = rand::random();
+    let key = session_key(secret, &salt)?;
+    let plaintext = Zeroizing::new(serde_json::to_vec(session)?);
+    let ciphertext = Aes256Gcm::new_from_slice(&key)?
+        .encrypt(Nonce::from_slice(&iv), plaintext.as_slice())
+        .map_err(|_| anyhow!("Encrypt KBS session failed"))?;
+
+    let file = SessionFile {
+        version: SESSION_FILE_VERSION,
+        salt: base64::encode(salt),
+        iv: base64::encode(iv),
+        ciphertext: base64::encode(ciphertext),
+    };
+
+    // Replace the file at once, so that a crash leaves the former session.
+    let tmp_path = path.with_extension("tmp");
+    let write = || -> std::io::Result<()> {
+        let mut tmp = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp_path)?;
+        tmp.write_all(&serde_json::to_vec(&file)?)?;
+        fs::rename(&tmp_path, path)
+    };
+    write().with_context(|| format!("Save KBS session to {} failed", path.display()))
+}
+
+/// Load and decrypt the session saved to `path`.
+pub fn load(path: &Path, secret: &[u8]) -> Result<Session> {
+    let content =
+        fs::read(path).with_context(|| format!("Read KBS session {} failed", path.display()))?;
+    let file: SessionFile = serde_json::from_slice(&content)
+        .with_context(|| format!("Invalid KBS session {}", path.display()))?;
+    if file.version != SESSION_FILE_VERSION {
+        bail!(
+            "KBS session {} has unsupported version {}",
+            path.display(),
+            file.version
+        );
+    }
+
+    let iv = base64::decode(file.iv)?;
+    if iv.len() != IV_LENGTH {
+        bail!("Invalid KBS session IV length {}", iv.len());
+    }
+    let key = session_key(secret, &base64::decode(file.salt)?)?;
+    let plaintext = Zeroizing::new(
+        Aes256Gcm::new_from_slice(&key)?
+            .decrypt(
+                Nonce::from_slice(&iv),
+                base64::decode(file.ciphertext)?.as_slice(),
+            )
+            .map_err(|_| anyhow!("Decrypt KBS session {} failed", path.display()))?,
+    );
+
+    serde_json::from_slice(&plaintext)
+        .with_context(|| format!("Invalid KBS session {}", path.display()))
+}
+
+/// Secrets of the kernel user keyring, see keyrings(7).
+pub mod keyring {
+    use anyhow::*;
+    use std::ffi::CString;
+    use zeroize::Zeroizing;
+
+    const KEY_TYPE: &str = "user";
+    const KEY_SPEC_USER_KEYRING: libc::c_long = -4;
+    const KEYCTL_SEARCH: libc::c_long = 10;
+    const KEYCTL_READ: libc::c_long = 11;
+
+    /// Add or update the secret named `description`.
+    pub fn store(description: &str, secret: &[u8]) -> Result<()> {
+        let key_type = CString::new(KEY_TYPE)?;
+        let description = CString::new(description)?;
+        // SAFETY: the strings are NUL terminated and the payload is valid
+        // for its length.
+        let serial = unsafe {
+            libc::syscall(
+                libc::SYS_add_key,
+                key_type.as_ptr(),
+                description.as_ptr(),
+                secret.as_ptr(),
+                secret.len(),
+                KEY_SPEC_USER_KEYRING,
+            )
+        };
+        if serial < 0 {
+            bail!(
+                "Add {} to the user keyring failed: {}",
+                description.to_string_lossy(),
+                std::io::Error::last_os_error()
+            );
+        }
+
+        Ok(())
+    }
+
+    /// The secret named `description`, if there is one.
+    pub fn find(description: &str) -> Option<Zeroizing<Vec<u8>>> {
+        let key_type = CString::new(KEY_TYPE).ok()?;
+        let description = CString::new(description).ok()?;
+        // SAFETY: the strings are NUL terminated.
+        let serial = unsafe {
+            libc::syscall(
+                libc::SYS_keyctl,
+                KEYCTL_SEARCH,
+                KEY_SPEC_USER_KEYRING,
+                key_type.as_ptr(),
+                description.as_ptr(),
+                0,
+            )
+        };
+        if serial < 0 {
+            return None;
+        }
+
+        let mut secret = Zeroizing::new(vec![0u8; 4096]);
+        // SAFETY: the buffer is valid for its length.
+        let len = unsafe {
+            libc::syscall(
+                libc::SYS_keyctl,
+                KEYCTL_READ,
+                serial,
+                secret.as_mut_ptr(),
+                secret.len(),
+            )
+        };
+        if len < 0 || len as usize > secret.len() {
+            return None;
+        }
+
+        secret.truncate(len as usize);
+        Some(secret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session() -> Session {
+        Session {
+            kbs: "kbs.example.org:8080".to_string(),
+            tee_key: base64::encode([7; 32]),
+            protocol_version: Some("0.1.1".to_string()),
+            token: Some("header.claims.signature".to_string()),
+            cookies: Some("kbs-session-id=1234".to_string()),
+        }
+    }
+
+    #[test]
+    fn save_load() {
+        let path = std::env::temp_dir().join(format!("cc-kbc-session-{}", rand::random::<u64>()));
+        save(&path, b"secret", &session()).expect("save failed");
+
+        let mode = fs::metadata(&path).unwrap().permissions();
+        assert_eq!(
+            std::os::unix::fs::PermissionsExt::mode(&mode) & 0o777,
+            0o600
+        );
+        let content = fs::read_to_string(&path).unwrap();
+        assert!(!content.contains("kbs-session-id"));
+
+        assert_eq!(load(&path, b"secret").expect("load failed"), session());
+        assert!(load(&path, b"other secret").is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn load_missing() {
+        assert!(load(Path::new("/non/existent/session"), b"secret").is_err());
+    }
+}
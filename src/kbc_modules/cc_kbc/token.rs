@@ -0,0 +1,455 @@
+// Copyright (c) 2023 Alibaba Cloud
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Token returned by the KBS after a successful attestation. It is sent as
+//! a bearer token on resource requests, so that the session survives
+//! proxies and load balancers that strip cookies.
+//!
+//! With a configured token key, the token must be a JWT signed by the KBS
+//! or its attestation service, valid now, and issued for our TEE key.
+
+use super::crypto::TeePubKey;
+use anyhow::*;
+use p256::pkcs8::DecodePublicKey as _;
+use p256::{ecdsa::signature::Verifier, elliptic_curve::sec1::EncodedPoint};
+use rsa::{pkcs8::DecodePublicKey as _, BigUint, Hash, PaddingScheme, PublicKey, RsaPublicKey};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256, Sha384, Sha512};
+use std::{
+    fs,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+// Tolerated difference between the clocks of the TEE and of the token issuer.
+const CLOCK_SKEW_SEC: u64 = 60;
+
+#[derive(Debug, Clone)]
+pub struct Token {
+    raw: String,
+    exp: Option<SystemTime>,
+    claims: Map<String, Value>,
+}
+
+#[derive(Deserialize)]
+struct Header {
+    alg: String,
+    #[serde(default)]
+    kid: Option<String>,
+}
+
+impl Token {
+    /// Parse a token. JWTs have their `exp` claim tracked, other tokens are
+    /// used as they are until the KBS refuses them.
+    pub fn new(raw: String) -> Token {
+        let claims = match Self::decode_claims(&raw) {
+            Result::Ok(claims) => claims,
+            Err(e) => {
+                log::warn!("CC-KBC: KBS token is not a JWT, its expiry is unknown: {e}");
+                Map::new()
+            }
+        };
+
+        Token {
+            exp: Self::time(&claims, "exp").ok().flatten(),
+            raw,
+            claims,
+        }
+    }
+
+    /// Parse a JWT whose signature is verified with `keys`, checking that it
+    /// is valid now and that its `tee-pubkey` claim is `tee_pubkey`.
+    pub fn verify(raw: String, keys: &TokenKeys, tee_pubkey: &TeePubKey) -> Result<Token> {
+        let parts: Vec<&str> = raw.split('.').collect();
+        let [header, payload, signature] = parts[..] else {
+            bail!("KBS token is not a JWT");
+        };
+        let header: Header = serde_json::from_slice(&decode(header)?)
+            .map_err(|e| anyhow!("Invalid KBS token header: {e}"))?;
+        keys.verify(
+            &header,
+            format!("{}.{}", parts[0], payload).as_bytes(),
+            &decode(signature)?,
+        )?;
+
+        let claims = Self::decode_claims(&raw)?;
+        let now = SystemTime::now();
+        let skew = Duration::from_secs(CLOCK_SKEW_SEC);
+        let exp = Self::time(&claims, "exp")?.ok_or_else(|| anyhow!("KBS token has no expiry"))?;
+        if exp + skew <= now {
+            bail!("KBS token has expired");
+        }
+        if matches!(Self::time(&claims, "nbf")?, Some(nbf) if nbf > now + skew) {
+            bail!("KBS token is not valid yet");
+        }
+        Self::check_tee_pubkey(&claims, tee_pubkey)?;
+
+        Ok(Token {
+            raw,
+            exp: Some(exp),
+            claims,
+        })
+    }
+
+    fn decode_claims(raw: &str) -> Result<Map<String, Value>> {
+        let payload = raw
+            .split('.')
+            .nth(1)
+            .ok_or_else(|| anyhow!("missing JWT payload"))?;
+        serde_json::from_slice(&decode(payload)?).map_err(|e| anyhow!("invalid JWT claims: {e}"))
+    }
+
+    // Time claim, in seconds since the epoch.
+    fn time(claims: &Map<String, Value>, name: &str) -> Result<Option<SystemTime>> {
+        match claims.get(name) {
+            None => Ok(None),
+            Some(value) => {
+                let seconds = value
+                    .as_u64()
+                    .ok_or_else(|| anyhow!("Invalid KBS token {name} claim {value}"))?;
+                Ok(Some(UNIX_EPOCH + Duration::from_secs(seconds)))
+            }
+        }
+    }
+
+    // The key material of the `tee-pubkey` claim must be the one of our key.
+    fn check_tee_pubkey(claims: &Map<String, Value>, tee_pubkey: &TeePubKey) -> Result<()> {
+        let claim = claims
+            .get("tee-pubkey")
+            .ok_or_else(|| anyhow!("KBS token has no tee-pubkey claim"))?;
+        let Value::Object(ours) = serde_json::to_value(tee_pubkey)? else {
+            bail!("Invalid TEE public key");
+        };
+
+        let matches = ours
+            .iter()
+            .filter(|(name, _)| name.as_str() != "alg")
+            .all(|(name, value)| claim.get(name) == Some(value));
+        if !matches {
+            bail!("KBS token was issued for another TEE key");
+        }
+
+        Ok(())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.raw
+    }
+
+    /// Claims of the token, for example its `tcb-status`. They are only
+    /// trustworthy if the token was verified.
+    pub fn claims(&self) -> &Map<String, Value> {
+        &self.claims
+    }
+
+    /// Time left before the token expires, if it has an expiry.
+    pub fn expires_in(&self) -> Option<Duration> {
+        let exp = self.exp?;
+        Some(
+            exp.duration_since(SystemTime::now())
+                .unwrap_or(Duration::ZERO),
+        )
+    }
+
+    /// Whether the token expires within `margin` from now.
+    pub fn expires_within(&self, margin: Duration) -> bool {
+        match self.exp {
+            Some(exp) => SystemTime::now() + margin >= exp,
+            None => false,
+        }
+    }
+}
+
+fn decode(part: &str) -> Result<Vec<u8>> {
+    base64::decode_config(part, base64::URL_SAFE_NO_PAD)
+        .map_err(|e| anyhow!("Invalid KBS token encoding: {e}"))
+}
+
+enum KeyMaterial {
+    Rsa(RsaPublicKey),
+    P256(p256::ecdsa::VerifyingKey),
+    P384(p384::ecdsa::VerifyingKey),
+}
+
+struct VerificationKey {
+    kid: Option<String>,
+    key: KeyMaterial,
+}
+
+#[derive(Deserialize)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Deserialize)]
+struct Jwk {
+    kty: String,
+    #[serde(default)]
+    kid: Option<String>,
+    #[serde(default)]
+    crv: String,
+    #[serde(default)]
+    x: String,
+    #[serde(default)]
+    y: String,
+    #[serde(default)]
+    n: String,
+    #[serde(default)]
+    e: String,
+}
+
+/// Public keys of the KBS or attestation service that sign the tokens.
+pub struct TokenKeys(Vec<VerificationKey>);
+
+impl TokenKeys {
+    /// Load a PEM public key, or a JWKS (JSON Web Key Set) file.
+    pub fn load(path: &Path) -> Result<TokenKeys> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Read KBS token key {} failed", path.display()))?;
+        let keys = match content.trim_start().starts_with('{') {
+            true => Self::from_jwks(&content),
+            false => Self::from_pem(&content),
+        };
+
+        keys.with_context(|| format!("Invalid KBS token key {}", path.display()))
+    }
+
+    fn from_pem(pem: &str) -> Result<TokenKeys> {
+        let key = if let Result::Ok(key) = p256::PublicKey::from_public_key_pem(pem) {
+            KeyMaterial::P256(key.into())
+        } else if let Result::Ok(key) = p384::PublicKey::from_public_key_pem(pem) {
+            KeyMaterial::P384(key.into())
+        } else {
+            KeyMaterial::Rsa(
+                RsaPublicKey::from_public_key_pem(pem)
+                    .map_err(|_| anyhow!("not a P-256, P-384 or RSA public key"))?,
+            )
+        };
+
+        Ok(TokenKeys(vec![VerificationKey { kid: None, key }]))
+    }
+
+    fn from_jwks(jwks: &str) -> Result<TokenKeys> {
+        let jwks: Jwks = serde_json::from_str(jwks)?;
+        let mut keys = Vec::new();
+        for jwk in jwks.keys {
+            let key = match (jwk.kty.as_str(), jwk.crv.as_str()) {
+                ("EC", "P-256") => {
+                    KeyMaterial::P256(p256::ecdsa::VerifyingKey::from_encoded_point(
+                        &EncodedPoint::<p256::NistP256>::from_affine_coordinates(
+                            decode(&jwk.x)?.as_slice().into(),
+                            decode(&jwk.y)?.as_slice().into(),
+                            false,
+                        ),
+                    )?)
+                }
+                ("EC", "P-384") => {
+                    KeyMaterial::P384(p384::ecdsa::VerifyingKey::from_encoded_point(
+                        &EncodedPoint::<p384::NistP384>::from_affine_coordinates(
+                            decode(&jwk.x)?.as_slice().into(),
+                            decode(&jwk.y)?.as_slice().into(),
+                            false,
+                        ),
+                    )?)
+                }
+                ("RSA", _) => KeyMaterial::Rsa(RsaPublicKey::new(
+                    BigUint::from_bytes_be(&decode(&jwk.n)?),
+                    BigUint::from_bytes_be(&decode(&jwk.e)?),
+                )?),
+                (kty, crv) => {
+                    log::warn!("CC-KBC: ignoring unsupported token key {kty} {crv}");
+                    continue;
+                }
+            };
+            keys.push(VerificationKey { kid: jwk.kid, key });
+        }
+
+        if keys.is_empty() {
+            bail!("no supported key in JWKS");
+        }
+        Ok(TokenKeys(keys))
+    }
+
+    // Check the signature with the keys of the `kid` of the header, or with
+    // all keys if it has none.
+    fn verify(&self, header: &Header, message: &[u8], signature: &[u8]) -> Result<()> {
+        let verified = self
+            .0
+            .iter()
+            .filter(|key| header.kid.is_none() || key.kid.is_none() || key.kid == header.kid)
+            .any(|key| key.verify(&header.alg, message, signature));
+        if !verified {
+            bail!(
+                "KBS token signature ({}) matches no configured key",
+                header.alg
+            );
+        }
+
+        Ok(())
+    }
+}
+
+impl VerificationKey {
+    fn verify(&self, alg: &str, message: &[u8], signature: &[u8]) -> bool {
+        let rsa = |key: &RsaPublicKey, hash: Hash, hashed: &[u8]| {
+            key.verify(
+                PaddingScheme::new_pkcs1v15_sign(Some(hash)),
+                hashed,
+                signature,
+            )
+            .is_ok()
+        };
+
+        match (&self.key, alg) {
+            (KeyMaterial::P256(key), "ES256") => p256::ecdsa::Signature::try_from(signature)
+                .map(|signature| key.verify(message, &signature).is_ok())
+                .unwrap_or(false),
+            (KeyMaterial::P384(key), "ES384") => p384::ecdsa::Signature::try_from(signature)
+                .map(|signature| key.verify(message, &signature).is_ok())
+                .unwrap_or(false),
+            (KeyMaterial::Rsa(key), "RS256") => rsa(key, Hash::SHA2_256, &Sha256::digest(message)),
+            (KeyMaterial::Rsa(key), "RS384") => rsa(key, Hash::SHA2_384, &Sha384::digest(message)),
+            (KeyMaterial::Rsa(key), "RS512") => rsa(key, Hash::SHA2_512, &Sha512::digest(message)),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MARGIN: Duration = Duration::from_secs(30);
+
+    fn jwt(claims: &str) -> String {
+        let encode = |data: &str| base64::encode_config(data, base64::URL_SAFE_NO_PAD);
+        format!(
+            "{}.{}.c2lnbmF0dXJl",
+            encode(r#"{"alg":"ES256","typ":"JWT"}"#),
+            encode(claims)
+        )
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    const TEE_PUBKEY: &str = r#""kty":"EC","crv":"P-256","x":"eA","y":"eQ""#;
+
+    fn tee_pubkey() -> TeePubKey {
+        serde_json::from_str(&format!(r#"{{{TEE_PUBKEY},"alg":"ECDH-ES+A256KW"}}"#)).unwrap()
+    }
+
+    fn signed_jwt(key: &p256::ecdsa::SigningKey, kid: &str, claims: &str) -> String {
+        use p256::ecdsa::signature::Signer;
+
+        let encode = |data: &[u8]| base64::encode_config(data, base64::URL_SAFE_NO_PAD);
+        let header = format!(r#"{{"alg":"ES256","typ":"JWT","kid":"{kid}"}}"#);
+        let message = format!(
+            "{}.{}",
+            encode(header.as_bytes()),
+            encode(claims.as_bytes())
+        );
+        let signature: p256::ecdsa::Signature = key.sign(message.as_bytes());
+        format!("{message}.{}", encode(signature.as_ref()))
+    }
+
+    fn key_file(name: &str, content: &str) -> std::path::PathBuf {
+        // Tests run in parallel, each with its own file.
+        let path = std::env::temp_dir().join(format!("cc-kbc-{}-{name}", rand::random::<u64>()));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    fn pem_keys(key: &p256::ecdsa::SigningKey) -> TokenKeys {
+        use p256::pkcs8::{EncodePublicKey, LineEnding};
+
+        let pem = p256::PublicKey::from(key.verifying_key())
+            .to_public_key_pem(LineEnding::LF)
+            .unwrap();
+        let path = key_file("token-key.pem", &pem);
+        let keys = TokenKeys::load(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        keys
+    }
+
+    fn jwks_keys(key: &p256::ecdsa::SigningKey, kid: &str) -> TokenKeys {
+        let encode = |data: &[u8]| base64::encode_config(data, base64::URL_SAFE_NO_PAD);
+        let point = key.verifying_key().to_encoded_point(false);
+        let jwks = format!(
+            r#"{{"keys":[{{"kty":"OKP","crv":"Ed25519","x":"AA"}},{{"kty":"EC","crv":"P-256","kid":"{kid}","x":"{}","y":"{}"}}]}}"#,
+            encode(point.x().unwrap()),
+            encode(point.y().unwrap())
+        );
+        let path = key_file("token-key.jwks", &jwks);
+        let keys = TokenKeys::load(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        keys
+    }
+
+    #[rstest::rstest]
+    #[case(format!(r#"{{"exp":{},"tee-pubkey":{{{TEE_PUBKEY}}},"tcb-status":{{"tdx.svn":"2"}}}}"#, now() + 3600), true)]
+    #[case(format!(r#"{{"exp":{},"nbf":{},"tee-pubkey":{{{TEE_PUBKEY}}}}}"#, now() + 3600, now()), true)]
+    #[case(format!(r#"{{"exp":{},"tee-pubkey":{{{TEE_PUBKEY}}}}}"#, now() - 3600), false)]
+    #[case(format!(r#"{{"exp":{},"nbf":{},"tee-pubkey":{{{TEE_PUBKEY}}}}}"#, now() + 7200, now() + 3600), false)]
+    #[case(format!(r#"{{"tee-pubkey":{{{TEE_PUBKEY}}}}}"#), false)]
+    #[case(format!(r#"{{"exp":{}}}"#, now() + 3600), false)]
+    #[case(format!(r#"{{"exp":{},"tee-pubkey":{{"kty":"EC","crv":"P-256","x":"eQ","y":"eA"}}}}"#, now() + 3600), false)]
+    fn verify(#[case] claims: String, #[case] valid: bool) {
+        let key = p256::ecdsa::SigningKey::random(&mut rand::rngs::OsRng);
+        let raw = signed_jwt(&key, "kbs-1", &claims);
+
+        for keys in [pem_keys(&key), jwks_keys(&key, "kbs-1")] {
+            let token = Token::verify(raw.clone(), &keys, &tee_pubkey());
+            assert_eq!(token.is_ok(), valid);
+            if let Result::Ok(token) = token {
+                assert!(!token.expires_within(MARGIN));
+                assert_eq!(
+                    token.claims().get("tcb-status").is_some(),
+                    claims.contains("tcb-status")
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn verify_signing_key() {
+        let key = p256::ecdsa::SigningKey::random(&mut rand::rngs::OsRng);
+        let other = p256::ecdsa::SigningKey::random(&mut rand::rngs::OsRng);
+        let claims = format!(
+            r#"{{"exp":{},"tee-pubkey":{{{TEE_PUBKEY}}}}}"#,
+            now() + 3600
+        );
+
+        let raw = signed_jwt(&other, "kbs-1", &claims);
+        assert!(Token::verify(raw, &pem_keys(&key), &tee_pubkey()).is_err());
+
+        // The key of another kid is not tried.
+        let raw = signed_jwt(&key, "kbs-2", &claims);
+        assert!(Token::verify(raw, &jwks_keys(&key, "kbs-1"), &tee_pubkey()).is_err());
+
+        let raw = signed_jwt(&key, "kbs-1", &claims);
+        let mut tampered = raw.replace(&raw[raw.len() - 4..], "AAAA");
+        assert!(Token::verify(tampered.clone(), &pem_keys(&key), &tee_pubkey()).is_err());
+        tampered = jwt(&claims);
+        assert!(Token::verify(tampered, &pem_keys(&key), &tee_pubkey()).is_err());
+    }
+
+    #[rstest::rstest]
+    #[case(jwt(&format!(r#"{{"exp":{}}}"#, now() + 3600)), false)]
+    #[case(jwt(&format!(r#"{{"exp":{}}}"#, now() + 10)), true)]
+    #[case(jwt(&format!(r#"{{"exp":{}}}"#, now() - 10)), true)]
+    #[case(jwt(r#"{"sub":"tdx"}"#), false)]
+    #[case("opaque-session-id".to_string(), false)]
+    fn expires_within(#[case] raw: String, #[case] expected: bool) {
+        let token = Token::new(raw.clone());
+        assert_eq!(token.as_str(), raw);
+        assert_eq!(token.expires_within(MARGIN), expected);
+    }
+}
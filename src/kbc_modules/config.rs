@@ -0,0 +1,93 @@
+// Copyright (c) 2023 Alibaba Cloud
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Loading of the JSON configuration files of the KBCs. Each KBC reads its
+//! file from the path given by an environment variable, or from a default
+//! path if the variable is not set, and a missing file means all defaults.
+
+use anyhow::*;
+use serde::de::DeserializeOwned;
+use std::{env, fs, path::Path};
+
+/// Load the configuration from the path of the `env` environment variable,
+/// or from `default_path`.
+pub fn load<T: DeserializeOwned + Default>(env: &str, default_path: &str) -> Result<T> {
+    let path = env::var(env).unwrap_or_else(|_| default_path.to_string());
+    load_from(Path::new(&path))
+}
+
+fn load_from<T: DeserializeOwned + Default>(path: &Path) -> Result<T> {
+    if !path.exists() {
+        return Ok(T::default());
+    }
+
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Read KBC config {} failed", path.display()))?;
+    serde_json::from_str(&content).with_context(|| format!("Invalid KBC config {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Deserialize, Debug, Default, PartialEq, Eq)]
+    #[serde(default, deny_unknown_fields)]
+    struct Config {
+        name: String,
+        timeout_secs: u64,
+    }
+
+    #[test]
+    fn missing_file_is_default() {
+        let config: Config =
+            load_from(Path::new("/non/existent/config.json")).expect("load config failed");
+        assert_eq!(config, Config::default());
+    }
+
+    #[test]
+    fn load_file() {
+        let path = env::temp_dir().join(format!(
+            "aa-kbc-test_load_config-{}.json",
+            std::process::id()
+        ));
+        fs::write(&path, r#"{"timeout_secs":5}"#).unwrap();
+        let config: Config = load_from(&path).expect("load config failed");
+        assert_eq!(
+            config,
+            Config {
+                timeout_secs: 5,
+                ..Default::default()
+            }
+        );
+
+        // Errors name the file.
+        for content in ["foo", r#"{"timeout":5}"#] {
+            fs::write(&path, content).unwrap();
+            let err = load_from::<Config>(&path).unwrap_err();
+            assert!(err.to_string().contains(path.to_str().unwrap()));
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn load_from_env() {
+        let path = env::temp_dir().join(format!(
+            "aa-kbc-test_env_config-{}.json",
+            std::process::id()
+        ));
+        fs::write(&path, r#"{"name":"from env"}"#).unwrap();
+        let var = "AA_KBC_TEST_CONFIG";
+        env::set_var(var, &path);
+        let config: Config = load(var, "/non/existent/config.json").expect("load config failed");
+        assert_eq!(config.name, "from env");
+
+        env::remove_var(var);
+        let config: Config = load(var, path.to_str().unwrap()).expect("load config failed");
+        assert_eq!(config.name, "from env");
+        fs::remove_file(&path).unwrap();
+    }
+}
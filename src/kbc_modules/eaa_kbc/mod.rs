@@ -3,14 +3,13 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
-use crate::kbc_modules::{KbcCheckInfo, KbcInterface};
+use crate::kbc_modules::{kbs_address::KbsAddress, KbcCheckInfo, KbcInterface};
 use crate::uri::ResourceUri;
 use anyhow::*;
 use async_trait::async_trait;
 use log::*;
 use std::collections::HashMap;
-use std::net::TcpStream;
-use std::os::unix::io::AsRawFd;
+use std::os::fd::{AsRawFd, OwnedFd};
 
 pub mod protocol;
 pub mod rats_tls;
@@ -29,7 +28,7 @@ pub struct EAAKbc {
     pub protocol_version: String,
     pub algorithm: String,
     pub key_length: u16,
-    pub tcp_stream: Option<TcpStream>,
+    pub stream: Option<OwnedFd>,
     pub tls_handle: Option<rats_tls::RatsTls>,
 }
 
@@ -52,7 +51,7 @@ impl KbcInterface for EAAKbc {
     async fn decrypt_payload(&mut self, annotation_packet: AnnotationPacket) -> Result<Vec<u8>> {
         debug!("EAA KBC decrypt_payload() is called");
 
-        if self.tcp_stream.is_none() {
+        if self.stream.is_none() {
             debug!("First request, connecting KBS...");
             self.establish_new_kbs_connection()?;
             debug!("connect success! TLS is established");
@@ -60,6 +59,12 @@ impl KbcInterface for EAAKbc {
 
         debug!("start decrypt...");
 
+        // The KBS decrypts, and its protocol has no additional authenticated
+        // data, which then would go unchecked.
+        if annotation_packet.aad.is_some() {
+            bail!("EAA KBC does not support annotations with additional authenticated data");
+        }
+
         let decrypted_payload = self.kbs_decrypt_payload(
             base64::decode(annotation_packet.wrapped_data)?,
             annotation_packet.kid.resource_path(),
@@ -71,7 +76,7 @@ impl KbcInterface for EAAKbc {
     }
 
     async fn get_resource(&mut self, rid: ResourceUri) -> Result<Vec<u8>> {
-        if self.tcp_stream.is_none() {
+        if self.stream.is_none() {
             debug!("First request, connecting KBS...");
             self.establish_new_kbs_connection()?;
             debug!("connect success! TLS is established");
@@ -89,7 +94,7 @@ impl EAAKbc {
             algorithm: String::new(),
             key_length: 0,
             // kek_cache: HashMap::new(),
-            tcp_stream: None,
+            stream: None,
             tls_handle: None,
         }
     }
@@ -99,13 +104,15 @@ impl EAAKbc {
         self.tls_handle =
             Some(rats_tls::RatsTls::new().map_err(|e| anyhow!("create rats_tls failed!:{:?}", e))?);
 
-        self.tcp_stream = Some(TcpStream::connect(&self.kbs_uri)?);
+        // The KBS may be reached over TCP, a Unix socket or vsock.
+        let stream = self.kbs_uri.parse::<KbsAddress>()?.connect_blocking()?;
+        let stream = self.stream.insert(stream);
 
         debug!("start negotiate (attestation) ...");
         self.tls_handle
             .as_ref()
             .unwrap()
-            .negotiate(self.tcp_stream.as_ref().unwrap().as_raw_fd())
+            .negotiate(stream.as_raw_fd())
             .map_err(|e| anyhow!("Negotiate Failed!:{:?}", e))?;
 
         self.protocol_version = self.kbs_query_version()?;
@@ -300,7 +307,7 @@ mod tests {
             protocol_version: kbs_protocol_version.clone(),
             algorithm: String::new(),
             key_length: 32,
-            tcp_stream: None,
+            stream: None,
             tls_handle: None,
         };
 
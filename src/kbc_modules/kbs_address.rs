@@ -0,0 +1,422 @@
+// Copyright (c) 2023 Alibaba Cloud
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Address of a KBS, as given to the network KBCs. Besides `http://` and
+//! `https://` URLs, a KBS can be reached through a Unix socket, e.g. when a
+//! host-side proxy is bind-mounted into the guest, or over vsock when the
+//! guest has no network at all:
+//!
+//! - `https://kbs.example.org:8080`, `http://10.0.0.1:8080`
+//! - `kbs.example.org:8080`, the same as `http://kbs.example.org:8080`
+//! - `unix:///run/kbs.sock`
+//! - `vsock://2:8080`, the context ID and port of the KBS
+//!
+//! Unix socket and vsock connections carry plain HTTP.
+
+use anyhow::*;
+use std::{
+    fmt,
+    io::{self, Error as IoError},
+    path::PathBuf,
+    str::FromStr,
+};
+use url::Url;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KbsAddress {
+    /// `http://` or `https://` URL of the KBS.
+    Http(Url),
+
+    /// Path of the Unix socket of the KBS.
+    Unix(PathBuf),
+
+    /// Context ID and port of the vsock of the KBS.
+    Vsock { cid: u32, port: u32 },
+}
+
+impl FromStr for KbsAddress {
+    type Err = Error;
+
+    fn from_str(address: &str) -> Result<Self> {
+        // A bare `<host>:<port>`, as the EAA and online SEV KBCs used to take.
+        let url = match address.contains("://") {
+            true => Url::parse(address),
+            false => Url::parse(&format!("http://{address}")),
+        }
+        .map_err(|e| anyhow!("Invalid KBS address {address}: {e}"))?;
+
+        match url.scheme() {
+            "http" | "https" => {
+                if !url.has_host() {
+                    bail!("KBS address {address} is missing a host");
+                }
+                Ok(KbsAddress::Http(url))
+            }
+            "unix" => {
+                if url.has_host() || url.path().len() <= 1 {
+                    bail!("KBS address {address} should be unix://<absolute path>");
+                }
+                Ok(KbsAddress::Unix(PathBuf::from(url.path())))
+            }
+            "vsock" => {
+                let (Some(cid), Some(port), "") = (url.host_str(), url.port(), url.path()) else {
+                    bail!("KBS address {address} should be vsock://<cid>:<port>");
+                };
+                let cid = cid
+                    .parse()
+                    .map_err(|e| anyhow!("Invalid vsock context ID {cid}: {e}"))?;
+                Ok(KbsAddress::Vsock {
+                    cid,
+                    port: port.into(),
+                })
+            }
+            scheme => bail!("Unsupported KBS address scheme {scheme} of {address}"),
+        }
+    }
+}
+
+impl fmt::Display for KbsAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KbsAddress::Http(url) => write!(f, "{}", url.as_str().trim_end_matches('/')),
+            KbsAddress::Unix(path) => write!(f, "unix://{}", path.display()),
+            KbsAddress::Vsock { cid, port } => write!(f, "vsock://{cid}:{port}"),
+        }
+    }
+}
+
+impl KbsAddress {
+    // `<host>:<port>` to connect to an HTTP(S) KBS.
+    fn authority(url: &Url) -> io::Result<String> {
+        let host = url
+            .host_str()
+            .ok_or_else(|| IoError::new(io::ErrorKind::InvalidInput, "KBS URL without host"))?;
+        let port = url.port_or_known_default().unwrap_or(80);
+        io::Result::Ok(format!("{host}:{port}"))
+    }
+
+    /// Open a stream to the KBS, blocking until it is connected. TLS of
+    /// `https://` addresses is left to the caller.
+    #[cfg(feature = "eaa_kbc")]
+    pub fn connect_blocking(&self) -> io::Result<std::os::fd::OwnedFd> {
+        use std::os::fd::OwnedFd;
+
+        match self {
+            KbsAddress::Http(url) => {
+                std::net::TcpStream::connect(Self::authority(url)?).map(OwnedFd::from)
+            }
+            KbsAddress::Unix(path) => {
+                std::os::unix::net::UnixStream::connect(path).map(OwnedFd::from)
+            }
+            KbsAddress::Vsock { cid, port } => vsock::connect(*cid, *port),
+        }
+    }
+
+    /// Open a stream to the KBS. TLS of `https://` addresses is left to the
+    /// caller.
+    #[cfg(feature = "tokio")]
+    pub async fn connect(&self) -> io::Result<KbsStream> {
+        io::Result::Ok(match self {
+            KbsAddress::Http(url) => {
+                KbsStream::Tcp(tokio::net::TcpStream::connect(Self::authority(url)?).await?)
+            }
+            KbsAddress::Unix(path) => KbsStream::Unix(tokio::net::UnixStream::connect(path).await?),
+            KbsAddress::Vsock { cid, port } => {
+                let (cid, port) = (*cid, *port);
+                let fd = tokio::task::spawn_blocking(move || vsock::connect(cid, port))
+                    .await
+                    .map_err(IoError::other)??;
+                KbsStream::Vsock(vsock::VsockStream::new(fd)?)
+            }
+        })
+    }
+}
+
+/// Stream to a KBS.
+#[cfg(feature = "tokio")]
+pub enum KbsStream {
+    Tcp(tokio::net::TcpStream),
+    Unix(tokio::net::UnixStream),
+    Vsock(vsock::VsockStream),
+}
+
+#[cfg(feature = "tokio")]
+mod stream {
+    use super::KbsStream;
+    use std::{
+        io,
+        pin::Pin,
+        task::{Context, Poll},
+    };
+    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+    macro_rules! dispatch {
+        ($self:ident, $stream:ident => $call:expr) => {
+            match $self.get_mut() {
+                KbsStream::Tcp($stream) => $call,
+                KbsStream::Unix($stream) => $call,
+                KbsStream::Vsock($stream) => $call,
+            }
+        };
+    }
+
+    impl AsyncRead for KbsStream {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            dispatch!(self, stream => Pin::new(stream).poll_read(cx, buf))
+        }
+    }
+
+    impl AsyncWrite for KbsStream {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            dispatch!(self, stream => Pin::new(stream).poll_write(cx, buf))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            dispatch!(self, stream => Pin::new(stream).poll_flush(cx))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            dispatch!(self, stream => Pin::new(stream).poll_shutdown(cx))
+        }
+    }
+}
+
+mod vsock {
+    use std::{
+        io,
+        os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    };
+
+    pub fn connect(cid: u32, port: u32) -> io::Result<OwnedFd> {
+        // SAFETY: a new socket is owned by nobody else.
+        let fd = unsafe {
+            let fd = libc::socket(libc::AF_VSOCK, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            OwnedFd::from_raw_fd(fd)
+        };
+
+        // SAFETY: all-zero is a valid `sockaddr_vm`.
+        let mut addr: libc::sockaddr_vm = unsafe { std::mem::zeroed() };
+        addr.svm_family = libc::AF_VSOCK as libc::sa_family_t;
+        addr.svm_cid = cid;
+        addr.svm_port = port;
+
+        // SAFETY: `addr` is a `sockaddr_vm` of the given length.
+        let res = unsafe {
+            libc::connect(
+                fd.as_raw_fd(),
+                &addr as *const libc::sockaddr_vm as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t,
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(fd)
+    }
+
+    #[cfg(feature = "tokio")]
+    pub use self::stream::VsockStream;
+
+    #[cfg(feature = "tokio")]
+    mod stream {
+        use std::{
+            io,
+            os::fd::{AsRawFd, OwnedFd, RawFd},
+            pin::Pin,
+            task::{ready, Context, Poll},
+        };
+        use tokio::io::{
+            unix::{AsyncFd, AsyncFdReadyGuard},
+            AsyncRead, AsyncWrite, Interest, ReadBuf,
+        };
+
+        /// Connected vsock, read and written through its file descriptor.
+        pub struct VsockStream(AsyncFd<OwnedFd>);
+
+        impl VsockStream {
+            pub fn new(fd: OwnedFd) -> io::Result<Self> {
+                // SAFETY: `fd` is a valid socket.
+                let flags = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_GETFL) };
+                if flags < 0
+                    || unsafe {
+                        libc::fcntl(fd.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK)
+                    } < 0
+                {
+                    return Err(io::Error::last_os_error());
+                }
+
+                let interest = Interest::READABLE | Interest::WRITABLE;
+                Ok(VsockStream(AsyncFd::with_interest(fd, interest)?))
+            }
+        }
+
+        // Result of a `recv` or `send` on the socket of `guard`, or
+        // `Err(())` if the socket is no longer ready.
+        fn try_io(
+            guard: &mut AsyncFdReadyGuard<'_, OwnedFd>,
+            io: impl FnOnce(RawFd) -> isize,
+        ) -> Result<io::Result<usize>, ()> {
+            guard
+                .try_io(|fd| match io(fd.as_raw_fd()) {
+                    len if len < 0 => Err(io::Error::last_os_error()),
+                    len => Ok(len as usize),
+                })
+                .map_err(|_| ())
+        }
+
+        impl AsyncRead for VsockStream {
+            fn poll_read(
+                self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+                buf: &mut ReadBuf<'_>,
+            ) -> Poll<io::Result<()>> {
+                loop {
+                    let mut guard = ready!(self.0.poll_read_ready(cx))?;
+                    let unfilled = buf.initialize_unfilled();
+                    // SAFETY: `unfilled` is valid for writes of its length.
+                    let res = try_io(&mut guard, |fd| unsafe {
+                        libc::recv(fd, unfilled.as_mut_ptr().cast(), unfilled.len(), 0)
+                    });
+                    if let Ok(res) = res {
+                        return Poll::Ready(res.map(|len| buf.advance(len)));
+                    }
+                }
+            }
+        }
+
+        impl AsyncWrite for VsockStream {
+            fn poll_write(
+                self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+                buf: &[u8],
+            ) -> Poll<io::Result<usize>> {
+                loop {
+                    let mut guard = ready!(self.0.poll_write_ready(cx))?;
+                    // SAFETY: `buf` is valid for reads of its length. A
+                    // closed peer fails with `EPIPE` rather than `SIGPIPE`.
+                    let res = try_io(&mut guard, |fd| unsafe {
+                        libc::send(fd, buf.as_ptr().cast(), buf.len(), libc::MSG_NOSIGNAL)
+                    });
+                    if let Ok(res) = res {
+                        return Poll::Ready(res);
+                    }
+                }
+            }
+
+            fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                Poll::Ready(Ok(()))
+            }
+
+            fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                // SAFETY: the socket is owned by `self`.
+                let res = unsafe { libc::shutdown(self.0.as_raw_fd(), libc::SHUT_WR) };
+                Poll::Ready(match res {
+                    0 => Ok(()),
+                    _ => Err(io::Error::last_os_error()),
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case("https://kbs.example.org:8080", "https://kbs.example.org:8080")]
+    #[case("http://10.0.0.1", "http://10.0.0.1")]
+    #[case("10.0.0.1:50000", "http://10.0.0.1:50000")]
+    #[case("kbs.example.org:50000", "http://kbs.example.org:50000")]
+    #[case("unix:///run/kbs.sock", "unix:///run/kbs.sock")]
+    #[case("vsock://2:8080", "vsock://2:8080")]
+    fn parse(#[case] address: &str, #[case] expected: &str) {
+        let parsed = address.parse::<KbsAddress>().expect("parse failed");
+        assert_eq!(parsed.to_string(), expected);
+    }
+
+    #[rstest]
+    #[case("kbs:///alice/cosign-key/213")]
+    #[case("https://")]
+    #[case("unix://run/kbs.sock")]
+    #[case("unix://")]
+    #[case("vsock://2")]
+    #[case("vsock://host:8080")]
+    #[case("vsock://2:8080/kbs")]
+    fn parse_invalid(#[case] address: &str) {
+        assert!(address.parse::<KbsAddress>().is_err());
+    }
+
+    #[test]
+    fn vsock_address() {
+        assert_eq!(
+            "vsock://4294967295:1024".parse::<KbsAddress>().unwrap(),
+            KbsAddress::Vsock {
+                cid: u32::MAX,
+                port: 1024
+            }
+        );
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn connect_unix() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let path = std::env::temp_dir().join(format!("kbs-address-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.write_all(b"kbs").await.unwrap();
+        });
+
+        let address: KbsAddress = format!("unix://{}", path.display()).parse().unwrap();
+        let mut stream = address.connect().await.expect("connect failed");
+        let mut buf = String::new();
+        stream.read_to_string(&mut buf).await.unwrap();
+        assert_eq!(buf, "kbs");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    // The stream works on any stream socket, here one of a pair of Unix
+    // sockets as vsock is not available in tests.
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn vsock_stream() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (local, peer) = std::os::unix::net::UnixStream::pair().unwrap();
+        let mut stream = vsock::VsockStream::new(local.into()).expect("wrap socket failed");
+        peer.set_nonblocking(true).unwrap();
+        let mut peer = tokio::net::UnixStream::from_std(peer).unwrap();
+
+        stream.write_all(b"request").await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut buf = String::new();
+        peer.read_to_string(&mut buf).await.unwrap();
+        assert_eq!(buf, "request");
+
+        peer.write_all(b"response").await.unwrap();
+        drop(peer);
+        let mut buf = String::new();
+        stream.read_to_string(&mut buf).await.unwrap();
+        assert_eq!(buf, "response");
+    }
+}
@@ -0,0 +1,144 @@
+// Copyright (c) 2023 Alibaba Cloud
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Decryption of an [`AnnotationPacket`] whose KEK is split with Shamir
+//! secret sharing across several KBSes. The shares are fetched as resources
+//! through the KBC, which attests to the KBS of each share, and the KEK is
+//! only reconstructed in the attestation agent.
+
+use anyhow::*;
+use zeroize::Zeroizing;
+
+use super::{AnnotationPacket, KbcInstance};
+use crate::common::crypto::{combine_key_shares, decrypt_with_aad};
+
+/// Fetch the shares of the KEK of `annotation` in order until `threshold`
+/// of them are received, then reconstruct the KEK and decrypt the payload.
+pub async fn decrypt_payload(
+    kbc: &mut KbcInstance,
+    annotation: AnnotationPacket,
+) -> Result<Vec<u8>> {
+    let key_shares = annotation
+        .key_shares
+        .as_ref()
+        .ok_or_else(|| anyhow!("Annotation has no key shares"))?;
+    let threshold = key_shares.threshold;
+    if threshold == 0 || threshold as usize > key_shares.shares.len() {
+        bail!(
+            "Illegal key share threshold {threshold} for {} shares",
+            key_shares.shares.len()
+        );
+    }
+
+    let mut shares = Vec::new();
+    let mut errors = Vec::new();
+    for uri in &key_shares.shares {
+        if shares.len() == threshold as usize {
+            break;
+        }
+
+        match kbc.get_key_share(uri.clone()).await {
+            Result::Ok(share) => shares.push(Zeroizing::new(share)),
+            Err(e) => errors.push(format!("{}: {e}", uri.whole_uri())),
+        }
+    }
+
+    if shares.len() < threshold as usize {
+        bail!(
+            "Only {} of the {threshold} key shares could be fetched: {}",
+            shares.len(),
+            errors.join("; ")
+        );
+    }
+
+    let key = combine_key_shares(threshold, &shares)?;
+    decrypt_with_aad(
+        key,
+        base64::decode(&annotation.wrapped_data)?,
+        base64::decode(&annotation.iv)?,
+        &annotation.aad()?,
+        &annotation.wrap_type,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use async_trait::async_trait;
+    use rstest::rstest;
+    use sharks::Sharks;
+
+    use super::*;
+    use crate::kbc_modules::{uri::ResourceUri, KbcCheckInfo, KbcInterface};
+
+    const KEK: &[u8] = &[
+        217, 155, 119, 5, 176, 186, 122, 22, 130, 149, 179, 163, 54, 114, 112, 176, 221, 155, 55,
+        27, 245, 20, 202, 139, 155, 167, 240, 163, 55, 17, 218, 234,
+    ];
+
+    /// Serves the shares of the KBSes which are up.
+    struct MockKbc {
+        resources: HashMap<String, Vec<u8>>,
+    }
+
+    #[async_trait]
+    impl KbcInterface for MockKbc {
+        fn check(&self) -> Result<KbcCheckInfo> {
+            Ok(KbcCheckInfo {
+                kbs_info: HashMap::new(),
+            })
+        }
+
+        async fn decrypt_payload(&mut self, _annotation: AnnotationPacket) -> Result<Vec<u8>> {
+            bail!("The KEK must not be fetched")
+        }
+
+        async fn get_resource(&mut self, rid: ResourceUri) -> Result<Vec<u8>> {
+            self.resources
+                .get(&rid.whole_uri())
+                .cloned()
+                .ok_or_else(|| anyhow!("KBS unreachable"))
+        }
+    }
+
+    const SHARES: [&str; 3] = [
+        "kbs://kbs1.example.org:8080/default/key-share/1",
+        "kbs://kbs2.example.org:8080/default/key-share/1",
+        "kbs://kbs3.example.org:8080/default/key-share/1",
+    ];
+
+    #[rstest]
+    #[case(2, &[0, 1, 2], true)]
+    #[case(2, &[1, 2], true)]
+    #[case(2, &[0], false)]
+    #[case(3, &[0, 1], false)]
+    #[case(4, &[0, 1, 2], false)]
+    #[case(0, &[0, 1, 2], false)]
+    #[tokio::test]
+    async fn threshold_decrypt(
+        #[case] threshold: u8,
+        #[case] up: &[usize],
+        #[case] decrypted: bool,
+    ) {
+        let dealt = Sharks(threshold.clamp(1, 3)).dealer(KEK);
+        let resources = dealt
+            .zip(SHARES)
+            .enumerate()
+            .filter(|(i, _)| up.contains(i))
+            .map(|(_, (share, uri))| (uri.to_string(), Vec::from(&share)))
+            .collect();
+        let mut kbc: KbcInstance = Box::new(MockKbc { resources });
+
+        let shares = SHARES.map(|uri| format!(r#""{uri}""#)).join(",");
+        let annotation = format!(
+            r#"{{"kid":"kbs:///default/key/split","wrapped_data":"9DzjnFk5jygxrETS3clGaVO87Xqb","iv":"AAAAAAAAAAAAAAAA","wrap_type":"A256GCM","key_shares":{{"threshold":{threshold},"shares":[{shares}]}}}}"#
+        );
+        let annotation: AnnotationPacket = serde_json::from_str(&annotation).unwrap();
+
+        let res = decrypt_payload(&mut kbc, annotation).await;
+        assert_eq!(res.ok(), decrypted.then(|| b"plbco".to_vec()));
+    }
+}
@@ -9,7 +9,7 @@ use anyhow::*;
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
 
-pub use self::annotation_packet::AnnotationPacket;
+pub use self::annotation_packet::{parse_recipients, AnnotationPacket};
 use self::uri::ResourceUri;
 
 // Add your specific kbc declaration here.
@@ -34,6 +34,16 @@ pub mod online_sev_kbc;
 pub mod sample_kbc;
 
 pub mod annotation_packet;
+#[cfg(any(
+    feature = "cc_kbc",
+    feature = "offline_fs_kbc",
+    feature = "online_sev_kbc"
+))]
+pub mod config;
+#[cfg(any(feature = "cc_kbc", feature = "eaa_kbc", feature = "online_sev_kbc"))]
+pub mod kbs_address;
+#[cfg(feature = "key_shares")]
+pub mod key_shares;
 pub mod uri;
 
 // KbcInterface is a standard interface that all KBC modules need to implement.
@@ -52,6 +62,13 @@ pub trait KbcInterface: Send {
     async fn get_resource(&mut self, _rid: ResourceUri) -> Result<Vec<u8>> {
         bail!("Get Resource API of this KBC is unimplement!")
     }
+
+    /// Get a share of a KEK split across several KBSes, which may be held by
+    /// another KBS than the one of the KBC.
+    #[cfg(feature = "key_shares")]
+    async fn get_key_share(&mut self, rid: ResourceUri) -> Result<Vec<u8>> {
+        self.get_resource(rid).await
+    }
 }
 
 /// A container type for [KbcInterface] trait objects.
@@ -7,41 +7,144 @@ use anyhow::{anyhow, Result};
 use base64::decode;
 use std::collections::HashMap;
 use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use super::sealed::unseal;
 
 pub type Keys = HashMap<String, Vec<u8>>;
 pub type Resources = HashMap<String, Vec<u8>>;
 
-pub fn load_keys(keyfile_name: &str) -> Result<Keys> {
-    let keys_json = fs::read_to_string(keyfile_name)?;
+/// Load the keys of `keyfile_names` in order, a key of a later file
+/// overriding the same key ID of an earlier file. Sealed files are unsealed
+/// with `secret`.
+pub fn load_keys<P: AsRef<Path>>(keyfile_names: &[P], secret: Option<&[u8]>) -> Result<Keys> {
+    merge_files(keyfile_names, secret, load_keyfile)
+}
+
+/// Load the resources of `resources_file_names` in order, a resource of a
+/// later file overriding the same resource of an earlier file. Sealed files
+/// are unsealed with `secret`.
+pub fn load_resources<P: AsRef<Path>>(
+    resources_file_names: &[P],
+    secret: Option<&[u8]>,
+) -> Result<Resources> {
+    merge_files(resources_file_names, secret, load_resources_file)
+}
+
+// Entries of a key or resource file.
+type Entries = HashMap<String, Vec<u8>>;
+
+fn merge_files<P: AsRef<Path>>(
+    file_names: &[P],
+    secret: Option<&[u8]>,
+    load: fn(&Path, Option<&[u8]>) -> Result<Entries>,
+) -> Result<Entries> {
+    let mut merged = HashMap::new();
+    for file_name in file_names {
+        merged.extend(load(file_name.as_ref(), secret)?);
+    }
+    Ok(merged)
+}
+
+fn load_keyfile(keyfile_name: &Path, secret: Option<&[u8]>) -> Result<Keys> {
+    let keys_json = fs::read(keyfile_name)
+        .map_err(|e| anyhow!("Failed to read keys file {}: {e}", keyfile_name.display()))?;
+    let keyfile_name = keyfile_name.display();
+    let keys_json = unseal(keys_json, secret)
+        .map_err(|e| anyhow!("Failed to unseal keys file {keyfile_name}: {e}"))?;
     // Redact parsing errors to avoid side-channels
-    let encoded_keys: HashMap<String, String> =
-        serde_json::from_str(&keys_json).map_err(|_| anyhow!("Failed to parse keys JSON file"))?;
+    let encoded_keys: HashMap<String, String> = serde_json::from_slice(&keys_json)
+        .map_err(|_| anyhow!("Failed to parse keys JSON file {keyfile_name}"))?;
     encoded_keys
         .iter()
         .map(|(k, v)| match decode(v) {
             Ok(key) => Ok((k.clone(), key)),
-            Err(_) => Err(anyhow!("Failed to decode key")),
+            Err(_) => Err(anyhow!("Failed to decode key {k} in {keyfile_name}")),
         })
         .collect()
 }
 
-pub fn load_resources(resources_file_name: &str) -> Result<Resources> {
-    let resources_json = fs::read_to_string(resources_file_name)?;
-    let encoded_resources: HashMap<String, String> = serde_json::from_str(&resources_json)
-        .map_err(|_| anyhow!("Failed to parse resources JSON file"))?;
+fn load_resources_file(resources_file_name: &Path, secret: Option<&[u8]>) -> Result<Resources> {
+    let resources_json = fs::read(resources_file_name).map_err(|e| {
+        anyhow!(
+            "Failed to read resources file {}: {e}",
+            resources_file_name.display()
+        )
+    })?;
+    let resources_file_name = resources_file_name.display();
+    let resources_json = unseal(resources_json, secret)
+        .map_err(|e| anyhow!("Failed to unseal resources file {resources_file_name}: {e}"))?;
+    let encoded_resources: HashMap<String, String> = serde_json::from_slice(&resources_json)
+        .map_err(|_| anyhow!("Failed to parse resources JSON file {resources_file_name}"))?;
     encoded_resources
         .iter()
         .map(|(k, v)| match decode(v) {
             Ok(resource) => Ok((k.clone(), resource)),
             Err(e) => Err(anyhow!(
-                "Failed to decode resource for {}: {}",
+                "Failed to decode resource for {} in {}: {}",
                 k,
+                resources_file_name,
                 e.to_string()
             )),
         })
         .collect()
 }
 
+/// Name of the optional key file at the root of a resource directory.
+pub const TREE_KEYS_FILE_NAME: &str = "keys.json";
+
+/// Read the resource `<repository>/<type>/<tag>` from the raw file of the
+/// same path in the directory `dir`, or `None` if there is no such file.
+/// The path must not leave `dir`, neither with `..` nor through symlinks.
+/// A sealed file is unsealed with `secret`.
+pub fn read_tree_resource(
+    dir: &Path,
+    resource_path: &str,
+    secret: Option<&[u8]>,
+) -> Result<Option<Vec<u8>>> {
+    let components: Vec<&str> = resource_path.split('/').collect();
+    if components.len() != 3
+        || components
+            .iter()
+            .any(|c| c.is_empty() || *c == "." || *c == ".." || c.contains(['\\', '\0']))
+    {
+        return Err(anyhow!("Illegal resource path {resource_path}"));
+    }
+
+    let path = match dir.join(resource_path).canonicalize() {
+        Ok(path) => path,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(anyhow!("Failed to resolve resource {resource_path}: {e}")),
+    };
+    let dir = dir
+        .canonicalize()
+        .map_err(|e| anyhow!("Failed to resolve directory {}: {e}", dir.display()))?;
+    if !path.starts_with(&dir) {
+        return Err(anyhow!(
+            "Resource {resource_path} leaves directory {}",
+            dir.display()
+        ));
+    }
+    if !path.is_file() {
+        return Ok(None);
+    }
+
+    let resource = fs::read(&path)
+        .map_err(|e| anyhow!("Failed to read resource file {}: {e}", path.display()))?;
+    let resource = unseal(resource, secret)
+        .map_err(|e| anyhow!("Failed to unseal resource file {}: {e}", path.display()))?;
+    Ok(Some(resource.to_vec()))
+}
+
+/// The key files of the directories `dirs` which have one.
+pub fn tree_keyfiles<P: AsRef<Path>>(dirs: &[P]) -> Vec<PathBuf> {
+    dirs.iter()
+        .map(|dir| dir.as_ref().join(TREE_KEYS_FILE_NAME))
+        .filter(|keyfile| keyfile.exists())
+        .collect()
+}
+
 pub mod tests {
     use crate::kbc_modules::tests::ResourcePath;
 
@@ -117,16 +220,136 @@ pub mod tests {
         let keyfile_path = create_keyfile("aa-offline_fs_kbc-test_load_keys");
         let keyfile_name = keyfile_path.to_str().unwrap();
         assert_eq!(
-            load_keys(keyfile_name).unwrap(),
+            load_keys(&[keyfile_name], None).unwrap(),
             [(KID.to_string(), KEY.to_vec())].iter().cloned().collect()
         );
 
         fs::write(keyfile_path.clone(), "foo").unwrap();
-        assert!(load_keys(keyfile_name).is_err());
+        let err = load_keys(&[keyfile_name], None).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("Failed to parse keys JSON file {keyfile_name}")
+        );
 
         fs::remove_file(keyfile_name).unwrap();
     }
 
+    #[test]
+    fn test_merge_keys() {
+        let first = create_keyfile("aa-offline_fs_kbc-test_merge_keys-1");
+        let second = env::temp_dir().join("aa-offline_fs_kbc-test_merge_keys-2");
+        let other_key = [7u8; 32];
+        fs::write(
+            &second,
+            serde_json::json!({
+                KID: base64::encode(other_key),
+                "bar": base64::encode(KEY),
+            })
+            .to_string(),
+        )
+        .unwrap();
+
+        // Later files override earlier ones.
+        assert_eq!(
+            load_keys(&[&first, &second], None).unwrap(),
+            [
+                (KID.to_string(), other_key.to_vec()),
+                ("bar".to_string(), KEY.to_vec())
+            ]
+            .iter()
+            .cloned()
+            .collect()
+        );
+        assert_eq!(
+            load_keys(&[&second, &first], None).unwrap()[KID],
+            KEY.to_vec()
+        );
+
+        // A missing file is named.
+        let missing = env::temp_dir().join("aa-offline_fs_kbc-test_merge_keys-missing");
+        let err = load_keys(&[&first, &missing], None).unwrap_err();
+        assert!(err.to_string().contains(missing.to_str().unwrap()));
+
+        fs::remove_file(first).unwrap();
+        fs::remove_file(second).unwrap();
+    }
+
+    #[test]
+    fn test_load_sealed_keys() {
+        use super::super::sealed::tests::{
+            AGE_IDENTITY, PASSPHRASE, SEALED_FOR_AGE, SEALED_WITH_PASSPHRASE,
+        };
+
+        let keyfile_path = env::temp_dir().join("aa-offline_fs_kbc-test_load_sealed_keys");
+        let keys: Keys = [(KID.to_string(), KEY.to_vec())].iter().cloned().collect();
+        for (sealed, secret) in [
+            (SEALED_WITH_PASSPHRASE, PASSPHRASE),
+            (SEALED_FOR_AGE, AGE_IDENTITY),
+        ] {
+            fs::write(&keyfile_path, sealed).unwrap();
+            assert_eq!(
+                load_keys(&[&keyfile_path], Some(secret.as_bytes())).unwrap(),
+                keys
+            );
+
+            let err = load_keys(&[&keyfile_path], None).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!(
+                    "Failed to unseal keys file {}: File is sealed, but not unlocked",
+                    keyfile_path.display()
+                )
+            );
+        }
+
+        fs::remove_file(keyfile_path).unwrap();
+    }
+
+    #[test]
+    fn test_read_tree_resource() {
+        let dir = env::temp_dir().join("aa-offline_fs_kbc-test_read_tree_resource");
+        let tree = dir.join("tree");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(tree.join("default/security-policy")).unwrap();
+        fs::write(tree.join("default/security-policy/test"), POLICYJSON).unwrap();
+        fs::write(dir.join("secret"), "secret").unwrap();
+        std::os::unix::fs::symlink(
+            dir.join("secret"),
+            tree.join("default/security-policy/link"),
+        )
+        .unwrap();
+
+        assert_eq!(
+            read_tree_resource(&tree, "default/security-policy/test", None).unwrap(),
+            Some(POLICYJSON.as_bytes().to_vec())
+        );
+        assert_eq!(
+            read_tree_resource(&tree, "default/security-policy/other", None).unwrap(),
+            None
+        );
+        // Directories are no resources.
+        fs::create_dir_all(tree.join("default/security-policy/dir")).unwrap();
+        assert_eq!(
+            read_tree_resource(&tree, "default/security-policy/dir", None).unwrap(),
+            None
+        );
+        for resource_path in [
+            "../tree/secret",
+            "default/../../secret",
+            "default/security-policy",
+            "default//test",
+            "/default/security-policy/test",
+            "default/security-policy/link",
+        ] {
+            assert!(
+                read_tree_resource(&tree, resource_path, None).is_err(),
+                "{resource_path} must be rejected"
+            );
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
     #[test]
     fn test_load_resources() {
         let temp_dir = env::temp_dir();
@@ -134,7 +357,7 @@ pub mod tests {
         create_resources_file(resources_file_path);
         let resources_file_name = &resources_file_path.to_str().unwrap();
         assert_eq!(
-            load_resources(resources_file_name).unwrap(),
+            load_resources(&[resources_file_name], None).unwrap(),
             [
                 (
                     resource_path!(ResourcePath::Policy),
@@ -163,7 +386,7 @@ pub mod tests {
         );
 
         fs::write(resources_file_path.clone(), "foo").unwrap();
-        assert!(load_resources(resources_file_name).is_err());
+        assert!(load_resources(&[resources_file_name], None).is_err());
 
         fs::remove_file(resources_file_name).unwrap();
     }
@@ -0,0 +1,139 @@
+// Copyright (c) 2023 Alibaba Cloud
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Configuration of the offline file system KBC.
+//!
+//! The configuration is a JSON file read from the path given by the
+//! `OFFLINE_FS_KBC_CONFIG` environment variable, or [`DEFAULT_CONFIG_PATH`]
+//! if the variable is not set. A missing file means all defaults. The key
+//! and resource files are loaded in order, an entry of a later file
+//! overriding the same one of an earlier file. Resources missing from the
+//! resource files are then looked up in the resource directories, in order.
+//! Sealed files are unlocked with the secret of `unlock`:
+//!
+//! ```json
+//! {
+//!     "key_files": [
+//!         "/etc/aa-offline_fs_kbc-keys.json",
+//!         "/run/aa/extra-keys.json"
+//!     ],
+//!     "resource_files": [
+//!         "/run/aa/resources.json"
+//!     ],
+//!     "resource_dirs": [
+//!         "/run/aa/resources"
+//!     ],
+//!     "unlock": {
+//!         "cmdline": "aa.offline_fs_kbc.unlock"
+//!     }
+//! }
+//! ```
+
+use anyhow::*;
+use serde::Deserialize;
+use std::path::PathBuf;
+
+use super::unlock::UnlockSource;
+use crate::kbc_modules::config;
+
+pub const CONFIG_PATH_ENV: &str = "OFFLINE_FS_KBC_CONFIG";
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/aa-offline_fs_kbc-config.json";
+
+const DEFAULT_KEYS_PATH: &str = "/etc/aa-offline_fs_kbc-keys.json";
+const DEFAULT_RESOURCES_PATH: &str = "/etc/aa-offline_fs_kbc-resources.json";
+
+/// Files the offline file system KBC loads its keys and resources from.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct OfflineFsConfig {
+    /// JSON files mapping key IDs to base64-encoded keys. Defaults to
+    /// `/etc/aa-offline_fs_kbc-keys.json`.
+    pub key_files: Vec<PathBuf>,
+
+    /// JSON files mapping resource paths to base64-encoded resources.
+    /// Defaults to `/etc/aa-offline_fs_kbc-resources.json`.
+    pub resource_files: Vec<PathBuf>,
+
+    /// Directories laid out as `<repository>/<type>/<tag>`, holding each
+    /// resource as a raw file read when it is requested. A `keys.json`
+    /// key file at the root of a directory is loaded after `key_files`.
+    pub resource_dirs: Vec<PathBuf>,
+
+    /// Source of the secret which unlocks sealed key and resource files.
+    /// Without one, sealed files fail to load.
+    pub unlock: Option<UnlockSource>,
+}
+
+impl Default for OfflineFsConfig {
+    fn default() -> Self {
+        Self {
+            key_files: vec![PathBuf::from(DEFAULT_KEYS_PATH)],
+            resource_files: vec![PathBuf::from(DEFAULT_RESOURCES_PATH)],
+            resource_dirs: Vec::new(),
+            unlock: None,
+        }
+    }
+}
+
+impl OfflineFsConfig {
+    /// Load the configuration from the path of `OFFLINE_FS_KBC_CONFIG`.
+    pub fn load() -> Result<Self> {
+        config::load(CONFIG_PATH_ENV, DEFAULT_CONFIG_PATH)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[rstest::rstest]
+    #[case(r#"{}"#, Some(OfflineFsConfig::default()))]
+    #[case(
+        r#"{"key_files":["/run/a.json","/run/b.json"]}"#,
+        Some(OfflineFsConfig {
+            key_files: vec!["/run/a.json".into(), "/run/b.json".into()],
+            ..Default::default()
+        })
+    )]
+    #[case(
+        r#"{"resource_files":[]}"#,
+        Some(OfflineFsConfig {
+            resource_files: vec![],
+            ..Default::default()
+        })
+    )]
+    #[case(
+        r#"{"resource_files":[],"resource_dirs":["/run/tree"]}"#,
+        Some(OfflineFsConfig {
+            resource_files: vec![],
+            resource_dirs: vec!["/run/tree".into()],
+            ..Default::default()
+        })
+    )]
+    #[case(
+        r#"{"unlock":{"tpm_sealed":"/run/unlock.ctx"}}"#,
+        Some(OfflineFsConfig {
+            unlock: Some(UnlockSource::TpmSealed("/run/unlock.ctx".into())),
+            ..Default::default()
+        })
+    )]
+    #[case(
+        r#"{"unlock":{"resource":{"kbc":"cc_kbc","kbs_uri":"http://127.0.0.1:8080","uri":"kbs:///default/offline-fs/unlock"}}}"#,
+        Some(OfflineFsConfig {
+            unlock: Some(UnlockSource::Resource {
+                kbc: "cc_kbc".to_string(),
+                kbs_uri: "http://127.0.0.1:8080".to_string(),
+                uri: "kbs:///default/offline-fs/unlock".to_string(),
+            }),
+            ..Default::default()
+        })
+    )]
+    #[case(r#"{"keys":["/run/a.json"]}"#, None)]
+    #[case(r#"{"unlock":{"passphrase":"secret"}}"#, None)]
+    fn parse(#[case] content: &str, #[case] expected: Option<OfflineFsConfig>) {
+        let config: Result<OfflineFsConfig, _> = serde_json::from_str(content);
+        assert_eq!(config.ok(), expected);
+    }
+}
@@ -9,41 +9,56 @@ use crate::{
     uri::ResourceUri,
 };
 pub mod common;
+mod config;
+mod sealed;
+mod unlock;
+mod watch;
 use common::*;
+use config::OfflineFsConfig;
+use watch::Store;
 
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    sync::{Arc, PoisonError, RwLock},
+};
 use zeroize::Zeroizing;
 
 use super::AnnotationPacket;
 
-const KEYS_PATH: &str = "/etc/aa-offline_fs_kbc-keys.json";
-const RESOURCES_PATH: &str = "/etc/aa-offline_fs_kbc-resources.json";
-
 pub struct OfflineFsKbc {
     // KBS info for compatibility; unused
     kbs_info: HashMap<String, String>,
-    // Stored keys, loaded from file system; load might fail
-    keys: Result<Keys>,
-    // Stored resources, loaded from file system; load might fail
-    resources: Result<Resources>,
+    // Stored keys and resources, loaded from file system and reloaded
+    // when the files change
+    store: Arc<RwLock<Store>>,
+    // Directory trees of resources, read on request, and the source of the
+    // unlock secret of sealed files
+    config: OfflineFsConfig,
 }
 
 #[async_trait]
 impl KbcInterface for OfflineFsKbc {
     fn check(&self) -> Result<KbcCheckInfo> {
-        Ok(KbcCheckInfo {
-            kbs_info: self.kbs_info.clone(),
-        })
+        let store = self.store.read().unwrap_or_else(PoisonError::into_inner);
+        let mut kbs_info = self.kbs_info.clone();
+        kbs_info.insert("generation".to_string(), store.generation.to_string());
+        if let Some(e) = &store.reload_error {
+            kbs_info.insert("reload_error".to_string(), e.clone());
+        }
+
+        Ok(KbcCheckInfo { kbs_info })
     }
 
     async fn decrypt_payload(&mut self, annotation_packet: AnnotationPacket) -> Result<Vec<u8>> {
+        self.unlock().await;
         let key = self.get_key(&annotation_packet.kid.resource_path()).await?;
-        let plain_payload = crypto::decrypt(
+        let plain_payload = crypto::decrypt_with_aad(
             key,
-            base64::decode(annotation_packet.wrapped_data)?,
-            base64::decode(annotation_packet.iv)?,
+            base64::decode(&annotation_packet.wrapped_data)?,
+            base64::decode(&annotation_packet.iv)?,
+            &annotation_packet.aad()?,
             &annotation_packet.wrap_type,
         )?;
 
@@ -51,28 +66,109 @@ impl KbcInterface for OfflineFsKbc {
     }
 
     async fn get_resource(&mut self, rid: ResourceUri) -> Result<Vec<u8>> {
+        self.unlock().await;
         let resource_path = rid.resource_path();
-        let resources = self.resources.as_ref().map_err(|e| anyhow!("{}", e))?;
-        let resource = resources
-            .get(resource_path.as_str())
-            .ok_or_else(|| anyhow!("Received unknown resource name: {}", resource_path.as_str()))?;
-        Ok(resource.to_vec())
+        let secret = {
+            let store = self.store.read().unwrap_or_else(PoisonError::into_inner);
+            let resources = store.resources.as_ref().map_err(|e| anyhow!("{}", e))?;
+            if let Some(resource) = resources.get(resource_path.as_str()) {
+                return Ok(resource.to_vec());
+            }
+            store.secret.clone()
+        };
+
+        let secret = secret.as_deref().map(Vec::as_slice);
+        for dir in &self.config.resource_dirs {
+            if let Some(resource) = read_tree_resource(dir, &resource_path, secret)? {
+                return Ok(resource);
+            }
+        }
+
+        Err(anyhow!(
+            "Received unknown resource name: {}",
+            resource_path.as_str()
+        ))
     }
 }
 
 impl OfflineFsKbc {
     #[allow(clippy::new_without_default)]
     pub fn new() -> OfflineFsKbc {
+        match OfflineFsConfig::load() {
+            Ok(config) => OfflineFsKbc::with_config(&config),
+            Err(e) => OfflineFsKbc::with_store(
+                Store::new(
+                    Err(anyhow!("Failed to load keys: {:#}", e)),
+                    Err(anyhow!("Failed to load resources: {:#}", e)),
+                ),
+                OfflineFsConfig::default(),
+            ),
+        }
+    }
+
+    fn with_config(config: &OfflineFsConfig) -> OfflineFsKbc {
+        // A local secret is fetched right away, a KBC resource on first use.
+        let secret = match &config.unlock {
+            Some(source) if source.is_local() => match source.fetch_local() {
+                Ok(secret) => Some(secret),
+                Err(e) => {
+                    log::warn!("Offline FS KBC: cannot fetch unlock secret: {e:#}");
+                    None
+                }
+            },
+            _ => None,
+        };
+        let mut store = Store::load(config, secret.as_deref().map(Vec::as_slice));
+        store.secret = secret;
+
+        let kbc = OfflineFsKbc::with_store(store, config.clone());
+        if let Err(e) = watch::watch(config.clone(), Arc::downgrade(&kbc.store)) {
+            log::warn!("Offline FS KBC: files not watched, no reloads: {e}");
+        }
+        kbc
+    }
+
+    fn with_store(store: Store, config: OfflineFsConfig) -> OfflineFsKbc {
         OfflineFsKbc {
             kbs_info: HashMap::new(),
-            keys: load_keys(KEYS_PATH).map_err(|e| anyhow!("Failed to load keys: {}", e)),
-            resources: load_resources(RESOURCES_PATH)
-                .map_err(|e| anyhow!("Failed to load resources: {}", e)),
+            store: Arc::new(RwLock::new(store)),
+            config,
+        }
+    }
+
+    /// Fetch the unlock secret if one is configured but not fetched yet, s.t.
+    /// a KBC resource, and reload the sealed files with it. If it cannot be
+    /// fetched, the plaintext files are still served.
+    async fn unlock(&mut self) {
+        let Some(source) = &self.config.unlock else {
+            return;
+        };
+        let unlocked = self
+            .store
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .secret
+            .is_some();
+        if unlocked {
+            return;
+        }
+
+        match source.fetch().await {
+            Ok(secret) => self.unlock_with(secret),
+            Err(e) => log::warn!("Offline FS KBC: cannot fetch unlock secret: {e:#}"),
         }
     }
 
+    fn unlock_with(&self, secret: Zeroizing<Vec<u8>>) {
+        let fresh = Store::load(&self.config, Some(&secret));
+        let mut store = self.store.write().unwrap_or_else(PoisonError::into_inner);
+        store.secret = Some(secret);
+        store.reload(fresh);
+    }
+
     async fn get_key(&mut self, keyid: &str) -> Result<Zeroizing<Vec<u8>>> {
-        let keys = self.keys.as_ref().map_err(|e| anyhow!("{}", e))?;
+        let store = self.store.read().unwrap_or_else(PoisonError::into_inner);
+        let keys = store.keys.as_ref().map_err(|e| anyhow!("{}", e))?;
         let key = keys
             .get(keyid)
             .ok_or_else(|| anyhow!("Received unknown key ID: {}", keyid))?
@@ -94,46 +190,218 @@ mod tests {
 
     #[tokio::test]
     async fn test_get_key() {
-        let mut kbc = OfflineFsKbc {
-            kbs_info: HashMap::new(),
-            keys: Ok([(KID.to_string(), KEY.to_vec())].iter().cloned().collect()),
-            resources: Ok([].iter().cloned().collect()),
-        };
+        let mut kbc = OfflineFsKbc::with_store(
+            Store::new(
+                Ok([(KID.to_string(), KEY.to_vec())].iter().cloned().collect()),
+                Ok([].iter().cloned().collect()),
+            ),
+            OfflineFsConfig::default(),
+        );
 
         assert_eq!(&kbc.get_key(KID).await.expect("get key failed")[..], KEY);
         assert!(kbc.get_key(WRONG_KEY).await.is_err());
     }
 
+    #[tokio::test]
+    async fn test_configured_files() {
+        let keyfile_path = common::tests::create_keyfile("aa-offline_fs_kbc-test_configured_files");
+        let missing = std::env::temp_dir().join("aa-offline_fs_kbc-test_configured_files-missing");
+        let config = OfflineFsConfig {
+            key_files: vec![keyfile_path.clone()],
+            resource_files: vec![missing.clone()],
+            ..Default::default()
+        };
+
+        let mut kbc = OfflineFsKbc::with_config(&config);
+        assert_eq!(&kbc.get_key(KID).await.expect("get key failed")[..], KEY);
+        let rid = ResourceUri::try_from(ResourcePath::Policy.as_ref()).unwrap();
+        let err = kbc.get_resource(rid).await.unwrap_err();
+        assert!(err.to_string().contains(missing.to_str().unwrap()));
+
+        std::fs::remove_file(keyfile_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_resource_dirs() {
+        let dir = std::env::temp_dir().join("aa-offline_fs_kbc-test_resource_dirs");
+        let _ = std::fs::remove_dir_all(&dir);
+        let (first, second) = (dir.join("first"), dir.join("second"));
+        std::fs::create_dir_all(first.join("default/security-policy")).unwrap();
+        std::fs::create_dir_all(second.join("default/security-policy")).unwrap();
+        std::fs::write(first.join("default/security-policy/test"), POLICYJSON).unwrap();
+        std::fs::write(second.join("default/security-policy/test"), "other").unwrap();
+        std::fs::write(second.join("default/security-policy/second"), "second").unwrap();
+        std::fs::write(
+            second.join(TREE_KEYS_FILE_NAME),
+            serde_json::json!({ KID: base64::encode(KEY) }).to_string(),
+        )
+        .unwrap();
+        let config = OfflineFsConfig {
+            key_files: vec![],
+            resource_files: vec![],
+            resource_dirs: vec![first, second],
+            ..Default::default()
+        };
+
+        let mut kbc = OfflineFsKbc::with_config(&config);
+        assert_eq!(&kbc.get_key(KID).await.expect("get key failed")[..], KEY);
+        for (resource_id, content) in [
+            ("kbs:///default/security-policy/test", Some(POLICYJSON)),
+            ("kbs:///default/security-policy/second", Some("second")),
+            ("kbs:///default/security-policy/none", None),
+        ] {
+            let rid = ResourceUri::try_from(resource_id).unwrap();
+            let res = kbc.get_resource(rid).await;
+            assert_eq!(res.ok(), content.map(|c| c.as_bytes().to_vec()));
+        }
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sealed_files() {
+        use sealed::tests::{PASSPHRASE, PLAINTEXT, SEALED_WITH_PASSPHRASE};
+
+        let dir = std::env::temp_dir().join("aa-offline_fs_kbc-test_sealed_files");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("tree/default/key-list")).unwrap();
+        let keyfile = dir.join("keys.sealed.json");
+        std::fs::write(&keyfile, SEALED_WITH_PASSPHRASE).unwrap();
+        std::fs::write(dir.join("tree/default/key-list/1"), SEALED_WITH_PASSPHRASE).unwrap();
+        std::fs::write(dir.join("tree/default/key-list/plain"), PLAINTEXT).unwrap();
+        let config = OfflineFsConfig {
+            key_files: vec![keyfile],
+            resource_files: vec![],
+            resource_dirs: vec![dir.join("tree")],
+            unlock: Some(unlock::UnlockSource::Cmdline(
+                "aa.offline_fs_kbc.test-sealed-files".to_string(),
+            )),
+        };
+
+        // The secret is not on the kernel command line, so only the
+        // plaintext files are served.
+        let mut kbc = OfflineFsKbc::with_config(&config);
+        let err = kbc.get_key(KID).await.unwrap_err();
+        assert!(err.to_string().contains("not unlocked"));
+        let rid = ResourceUri::try_from("kbs:///default/key-list/1").unwrap();
+        let err = kbc.get_resource(rid.clone()).await.unwrap_err();
+        assert!(err.to_string().contains("not unlocked"));
+        let plain = ResourceUri::try_from("kbs:///default/key-list/plain").unwrap();
+        assert_eq!(
+            kbc.get_resource(plain).await.expect("get resource failed"),
+            PLAINTEXT.as_bytes()
+        );
+
+        kbc.unlock_with(Zeroizing::new(PASSPHRASE.as_bytes().to_vec()));
+        assert_eq!(kbc.check().unwrap().kbs_info["generation"], "1");
+        assert_eq!(&kbc.get_key(KID).await.expect("get key failed")[..], KEY);
+        assert_eq!(
+            kbc.get_resource(rid).await.expect("get resource failed"),
+            PLAINTEXT.as_bytes()
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
     fn kbc_instance() -> OfflineFsKbc {
-        OfflineFsKbc {
-            kbs_info: HashMap::new(),
-            keys: Err(anyhow!("no keys")),
-            resources: Ok([
-                (
-                    resource_path!(ResourcePath::Policy),
-                    POLICYJSON.as_bytes().to_vec(),
-                ),
-                (
-                    resource_path!(ResourcePath::SigstoreConfig),
-                    SIGSTORECONFIG.as_bytes().to_vec(),
-                ),
-                (
-                    resource_path!(ResourcePath::GPGPublicKey),
-                    PUBKEY.as_bytes().to_vec(),
-                ),
-                (
-                    resource_path!(ResourcePath::CosignVerificationKey),
-                    COSIGNKEY.as_bytes().to_vec(),
-                ),
-                (
-                    resource_path!(ResourcePath::Credential),
-                    CREDENTIAL.as_bytes().to_vec(),
-                ),
-            ]
-            .iter()
-            .cloned()
-            .collect()),
+        let resources = [
+            (
+                resource_path!(ResourcePath::Policy),
+                POLICYJSON.as_bytes().to_vec(),
+            ),
+            (
+                resource_path!(ResourcePath::SigstoreConfig),
+                SIGSTORECONFIG.as_bytes().to_vec(),
+            ),
+            (
+                resource_path!(ResourcePath::GPGPublicKey),
+                PUBKEY.as_bytes().to_vec(),
+            ),
+            (
+                resource_path!(ResourcePath::CosignVerificationKey),
+                COSIGNKEY.as_bytes().to_vec(),
+            ),
+            (
+                resource_path!(ResourcePath::Credential),
+                CREDENTIAL.as_bytes().to_vec(),
+            ),
+        ]
+        .iter()
+        .cloned()
+        .collect();
+        OfflineFsKbc::with_store(
+            Store::new(Err(anyhow!("no keys")), Ok(resources)),
+            OfflineFsConfig::default(),
+        )
+    }
+
+    // Wait for the watch to reload the store until `reloaded` holds.
+    fn wait_reload(kbc: &OfflineFsKbc, reloaded: impl Fn(&HashMap<String, String>) -> bool) {
+        for _ in 0..100 {
+            if reloaded(&kbc.check().unwrap().kbs_info) {
+                return;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(50));
         }
+        panic!("no reload");
+    }
+
+    #[tokio::test]
+    async fn test_hot_reload() {
+        let dir = std::env::temp_dir().join("aa-offline_fs_kbc-test_hot_reload");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let keyfile = dir.join("keys.json");
+        let config = OfflineFsConfig {
+            key_files: vec![keyfile.clone()],
+            resource_files: vec![],
+            ..Default::default()
+        };
+
+        // The key file is provisioned after the KBC starts.
+        let mut kbc = OfflineFsKbc::with_config(&config);
+        assert!(kbc.get_key(KID).await.is_err());
+        assert_eq!(kbc.check().unwrap().kbs_info["generation"], "0");
+
+        let write_key = |key: &[u8]| {
+            let content = serde_json::json!({ KID: base64::encode(key) }).to_string();
+            std::fs::write(&keyfile, content).unwrap();
+        };
+        write_key(&KEY);
+        wait_reload(&kbc, |info| info["generation"] == "1");
+        assert_eq!(&kbc.get_key(KID).await.expect("get key failed")[..], KEY);
+
+        // A file replaced by a rename is reloaded.
+        let other_key = [7u8; 32];
+        let staged = dir.join("keys.json.new");
+        std::fs::write(
+            &staged,
+            serde_json::json!({ KID: base64::encode(other_key) }).to_string(),
+        )
+        .unwrap();
+        std::fs::rename(&staged, &keyfile).unwrap();
+        wait_reload(&kbc, |info| info["generation"] == "2");
+        assert_eq!(
+            &kbc.get_key(KID).await.expect("get key failed")[..],
+            other_key
+        );
+
+        // A failed reload keeps the last keys.
+        std::fs::write(&keyfile, "foo").unwrap();
+        wait_reload(&kbc, |info| info.contains_key("reload_error"));
+        let info = kbc.check().unwrap().kbs_info;
+        assert!(info["reload_error"].contains(keyfile.to_str().unwrap()));
+        assert_eq!(info["generation"], "2");
+        assert_eq!(
+            &kbc.get_key(KID).await.expect("get key failed")[..],
+            other_key
+        );
+
+        write_key(&KEY);
+        wait_reload(&kbc, |info| info["generation"] == "3");
+        assert!(!kbc.check().unwrap().kbs_info.contains_key("reload_error"));
+
+        std::fs::remove_dir_all(dir).unwrap();
     }
 
     #[rstest::rstest]
@@ -0,0 +1,163 @@
+// Copyright (c) 2023 Alibaba Cloud
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Key and resource files sealed at rest, s.t. by the `seal-file` command
+//! of the CoCo keyprovider. A file is either
+//!
+//! - sealed with a key derived from a passphrase by Argon2id, and encrypted
+//!   with A256GCM. The file is a JSON object whose `sealed` is
+//!   `argon2id-A256GCM`, and the unlock secret the passphrase.
+//! - sealed for age X25519 recipients. The file is in the age format, and
+//!   the unlock secret an `AGE-SECRET-KEY-1...` identity.
+//!
+//! Any other file is plaintext.
+
+use anyhow::*;
+use argon2::{Argon2, Params};
+use serde::Deserialize;
+use std::io::Read;
+use zeroize::Zeroizing;
+
+use crate::common::crypto;
+
+pub const SEALED_ARGON2ID_A256GCM: &str = "argon2id-A256GCM";
+
+const AGE_MAGIC: &[u8] = b"age-encryption.org/v1";
+
+// Upper bounds of the Argon2id memory size in KiB, number of iterations and
+// degree of parallelism, so that a forged file cannot exhaust the memory of
+// the guest or stall the KBC.
+const MAX_M_COST: u32 = 1024 * 1024;
+const MAX_T_COST: u32 = 32;
+const MAX_P_COST: u32 = 16;
+
+#[derive(Deserialize)]
+struct SealedFile {
+    sealed: String,
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+    salt: String,
+    iv: String,
+    ciphertext: String,
+}
+
+/// Unseal `content` with the unlock `secret`. Plaintext content is returned
+/// as is.
+pub fn unseal(content: Vec<u8>, secret: Option<&[u8]>) -> Result<Zeroizing<Vec<u8>>> {
+    if content.starts_with(AGE_MAGIC) {
+        let secret = secret.ok_or_else(|| anyhow!("File is sealed, but not unlocked"))?;
+        return unseal_age(&content, secret);
+    }
+
+    match serde_json::from_slice::<SealedFile>(&content) {
+        Result::Ok(sealed) => {
+            let secret = secret.ok_or_else(|| anyhow!("File is sealed, but not unlocked"))?;
+            unseal_with_passphrase(sealed, secret)
+        }
+        Err(_) => Ok(Zeroizing::new(content)),
+    }
+}
+
+fn unseal_with_passphrase(sealed: SealedFile, passphrase: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+    if sealed.sealed != SEALED_ARGON2ID_A256GCM {
+        bail!("Unsupported sealing {}", sealed.sealed);
+    }
+    if sealed.m_cost > MAX_M_COST {
+        bail!("Argon2id memory size {} KiB too large", sealed.m_cost);
+    }
+    if sealed.t_cost > MAX_T_COST {
+        bail!("Argon2id number of iterations {} too large", sealed.t_cost);
+    }
+    if sealed.p_cost > MAX_P_COST {
+        bail!("Argon2id degree of parallelism {} too large", sealed.p_cost);
+    }
+
+    let params = Params::new(sealed.m_cost, sealed.t_cost, sealed.p_cost, Some(32))
+        .map_err(|e| anyhow!("Illegal Argon2id parameters: {e}"))?;
+    let mut key = Zeroizing::new(vec![0; 32]);
+    Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+        .hash_password_into(passphrase, &base64::decode(&sealed.salt)?, &mut key)
+        .map_err(|e| anyhow!("Argon2id failed: {e}"))?;
+
+    // Redact decryption errors, a wrong passphrase is the likely cause.
+    crypto::decrypt(
+        key,
+        base64::decode(&sealed.ciphertext)?,
+        base64::decode(&sealed.iv)?,
+        "A256GCM",
+    )
+    .map(Zeroizing::new)
+    .map_err(|_| anyhow!("Failed to unseal, wrong passphrase?"))
+}
+
+fn unseal_age(content: &[u8], identity: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+    let identity: age::x25519::Identity = std::str::from_utf8(identity)
+        .ok()
+        .and_then(|identity| identity.trim().parse().ok())
+        .ok_or_else(|| anyhow!("Unlock secret is no age X25519 identity"))?;
+
+    let age::Decryptor::Recipients(decryptor) = age::Decryptor::new(content)? else {
+        bail!("age file is not sealed for recipients");
+    };
+    let mut plaintext = Zeroizing::new(Vec::new());
+    decryptor
+        .decrypt(std::iter::once(&identity as &dyn age::Identity))
+        .map_err(|_| anyhow!("Failed to unseal, wrong age identity?"))?
+        .read_to_end(&mut plaintext)?;
+    Ok(plaintext)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    // `PLAINTEXT` sealed by `coco_keyprovider seal-file`, with a passphrase
+    // file holding `PASSPHRASE` and a newline
+    pub const SEALED_WITH_PASSPHRASE: &[u8] = include_bytes!("test-keys.sealed.json");
+    pub const SEALED_FOR_AGE: &[u8] = include_bytes!("test-keys.age");
+    pub const PASSPHRASE: &str = "passphrase";
+    pub const AGE_IDENTITY: &str =
+        "AGE-SECRET-KEY-14LS9HS4ZR0386KUQ3HC86UYC6GCHQMA4F7E87EQAU6DEZ8Q9WU5SRUZTVZ\n";
+    pub const PLAINTEXT: &str = r#"{"foo":"cGFzc3BocmFzZXdoaWNobmVlZHN0b2JlMzJieXRlcyE="}"#;
+
+    #[rstest]
+    #[case(SEALED_WITH_PASSPHRASE, Some(PASSPHRASE), true)]
+    #[case(SEALED_WITH_PASSPHRASE, Some("wrong passphrase"), false)]
+    #[case(SEALED_WITH_PASSPHRASE, None, false)]
+    #[case(SEALED_FOR_AGE, Some(AGE_IDENTITY), true)]
+    #[case(SEALED_FOR_AGE, Some(PASSPHRASE), false)]
+    #[case(
+        SEALED_FOR_AGE,
+        Some("AGE-SECRET-KEY-1YL6PYC8CMRRTJCC0ZQQP3PKT9NVXLRSK4AY4H2S346FFCWJD8HKQ4MUCFY"),
+        false
+    )]
+    #[case(SEALED_FOR_AGE, None, false)]
+    #[case(PLAINTEXT.as_bytes(), None, true)]
+    #[case(PLAINTEXT.as_bytes(), Some(PASSPHRASE), true)]
+    fn unseal_file(#[case] content: &[u8], #[case] secret: Option<&str>, #[case] unsealed: bool) {
+        let res = unseal(content.to_vec(), secret.map(str::as_bytes));
+        assert_eq!(
+            res.ok().map(|plaintext| plaintext.to_vec()),
+            unsealed.then(|| PLAINTEXT.as_bytes().to_vec())
+        );
+    }
+
+    #[rstest]
+    #[case(MAX_M_COST + 1, 1, 1)]
+    #[case(1024, MAX_T_COST + 1, 1)]
+    #[case(1024, u32::MAX, 1)]
+    #[case(1024, 1, MAX_P_COST + 1)]
+    #[case(1024, 1, u32::MAX)]
+    fn forged_cost(#[case] m_cost: u32, #[case] t_cost: u32, #[case] p_cost: u32) {
+        let forged = format!(
+            r#"{{"sealed":"argon2id-A256GCM","m_cost":{m_cost},"t_cost":{t_cost},"p_cost":{p_cost},"salt":"AAAAAAAAAAAAAAAAAAAAAA==","iv":"AAAAAAAAAAAAAAAA","ciphertext":"AAAA"}}"#
+        );
+        let err = unseal(forged.into_bytes(), Some(PASSPHRASE.as_bytes())).unwrap_err();
+        assert!(err.to_string().contains("too large"));
+    }
+}
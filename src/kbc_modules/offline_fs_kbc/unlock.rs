@@ -0,0 +1,152 @@
+// Copyright (c) 2023 Alibaba Cloud
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Sources of the secret which unlocks the sealed key and resource files of
+//! the offline file system KBC.
+
+use anyhow::*;
+use serde::Deserialize;
+use std::{fs, path::PathBuf, process::Command};
+use zeroize::Zeroizing;
+
+use crate::kbc_modules::KbcModuleList;
+use crate::uri::ResourceUri;
+
+const CMDLINE_PATH: &str = "/proc/cmdline";
+const TPM2_UNSEAL_PATH: &str = "tpm2_unseal";
+
+/// Where the unlock secret of the sealed files is fetched from.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum UnlockSource {
+    /// The value of the named kernel command line parameter, s.t.
+    /// `aa.offline_fs_kbc.unlock` for `aa.offline_fs_kbc.unlock=<secret>`.
+    Cmdline(String),
+
+    /// A TPM sealed object context, unsealed by `tpm2_unseal -c <path>`.
+    TpmSealed(PathBuf),
+
+    /// A resource of another KBC, fetched the first time a key or
+    /// resource is requested.
+    Resource {
+        kbc: String,
+        kbs_uri: String,
+        uri: String,
+    },
+}
+
+impl UnlockSource {
+    /// Whether the secret is fetched from the guest itself, and may thus be
+    /// fetched when the KBC starts.
+    pub fn is_local(&self) -> bool {
+        !matches!(self, UnlockSource::Resource { .. })
+    }
+
+    /// Fetch the secret from the kernel command line or the TPM.
+    pub fn fetch_local(&self) -> Result<Zeroizing<Vec<u8>>> {
+        match self {
+            UnlockSource::Cmdline(name) => {
+                let cmdline = fs::read_to_string(CMDLINE_PATH)
+                    .with_context(|| format!("Read {CMDLINE_PATH} failed"))?;
+                cmdline_param(&cmdline, name)
+                    .map(|value| Zeroizing::new(value.as_bytes().to_vec()))
+                    .ok_or_else(|| anyhow!("No or empty kernel command line parameter {name}"))
+            }
+            UnlockSource::TpmSealed(context) => {
+                let output = Command::new(TPM2_UNSEAL_PATH)
+                    .arg("-c")
+                    .arg(context)
+                    .output()
+                    .with_context(|| format!("Run {TPM2_UNSEAL_PATH} failed"))?;
+                if !output.status.success() {
+                    bail!(
+                        "Failed to unseal {}: {}",
+                        context.display(),
+                        String::from_utf8_lossy(&output.stderr).trim()
+                    );
+                }
+                Ok(Zeroizing::new(output.stdout))
+            }
+            UnlockSource::Resource { .. } => bail!("Unlock secret is no local secret"),
+        }
+    }
+
+    /// Fetch the secret from its source.
+    pub async fn fetch(&self) -> Result<Zeroizing<Vec<u8>>> {
+        let UnlockSource::Resource { kbc, kbs_uri, uri } = self else {
+            return self.fetch_local();
+        };
+        if kbc == "offline_fs_kbc" {
+            bail!("The offline fs KBC cannot unlock itself");
+        }
+
+        let rid = ResourceUri::try_from(uri.as_str())
+            .map_err(|e| anyhow!("Illegal unlock resource URI {uri}: {e}"))?;
+        let mut kbc = KbcModuleList::new().get_func(kbc)?(kbs_uri.clone());
+        let secret = kbc
+            .get_resource(rid)
+            .await
+            .with_context(|| format!("Fetch unlock resource {uri} failed"))?;
+        Ok(Zeroizing::new(secret))
+    }
+}
+
+/// The value of the last parameter `name` of the kernel command line, if it
+/// is not empty.
+fn cmdline_param<'a>(cmdline: &'a str, name: &str) -> Option<&'a str> {
+    cmdline
+        .split_whitespace()
+        .rev()
+        .filter_map(|param| param.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+        .filter(|value| !value.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case("ro quiet aa.offline_fs_kbc.unlock=secret", Some("secret"))]
+    #[case("aa.offline_fs_kbc.unlock=a=b console=ttyS0", Some("a=b"))]
+    #[case("aa.offline_fs_kbc.unlock=1 aa.offline_fs_kbc.unlock=2\n", Some("2"))]
+    #[case("aa.offline_fs_kbc.unlock= ro", None)]
+    #[case("aa.offline_fs_kbc.unlock=secret aa.offline_fs_kbc.unlock=", None)]
+    #[case("aa.offline_fs_kbc.unlock ro", None)]
+    #[case("xaa.offline_fs_kbc.unlock=secret", None)]
+    fn parse_cmdline(#[case] cmdline: &str, #[case] value: Option<&str>) {
+        assert_eq!(cmdline_param(cmdline, "aa.offline_fs_kbc.unlock"), value);
+    }
+
+    // A file sealed with a passphrase file unseals with the passphrase on
+    // the kernel command line, which cannot hold the newline of the file.
+    #[test]
+    fn unseal_with_cmdline_passphrase() {
+        use crate::kbc_modules::offline_fs_kbc::sealed::{
+            tests::{PASSPHRASE, PLAINTEXT, SEALED_WITH_PASSPHRASE},
+            unseal,
+        };
+
+        let cmdline = format!("ro quiet aa.offline_fs_kbc.unlock={PASSPHRASE}\n");
+        let secret = cmdline_param(&cmdline, "aa.offline_fs_kbc.unlock").unwrap();
+        let plaintext = unseal(SEALED_WITH_PASSPHRASE.to_vec(), Some(secret.as_bytes()))
+            .expect("unseal failed");
+        assert_eq!(plaintext.as_slice(), PLAINTEXT.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn unlock_offline_fs_kbc_itself() {
+        let source = UnlockSource::Resource {
+            kbc: "offline_fs_kbc".to_string(),
+            kbs_uri: "null".to_string(),
+            uri: "kbs:///default/offline-fs/unlock".to_string(),
+        };
+        assert!(!source.is_local());
+        assert!(source.fetch().await.is_err());
+    }
+}
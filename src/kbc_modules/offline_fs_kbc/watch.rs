@@ -0,0 +1,297 @@
+// Copyright (c) 2023 Alibaba Cloud
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Hot reload of the keys and resources of the offline file system KBC.
+//!
+//! The directories of the key and resource files are watched with inotify,
+//! so that files provisioned or replaced after the KBC starts, s.t. by
+//! cloud-init, are loaded. The keys and the resources are each swapped as a
+//! whole, and keep the last set loaded if a reload fails. Sealed files are
+//! unsealed with the unlock secret of the store.
+
+use anyhow::{anyhow, Result};
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+use log::{info, warn};
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+    sync::{PoisonError, RwLock, Weak},
+    thread::{self, JoinHandle},
+};
+use zeroize::Zeroizing;
+
+use super::common::*;
+use super::config::OfflineFsConfig;
+
+// How long the watch waits for events before it checks whether the store
+// was dropped, in milliseconds.
+const WATCH_POLL_INTERVAL_MS: i32 = 1000;
+
+/// Keys and resources of the offline file system KBC.
+pub struct Store {
+    // Stored keys; load might fail
+    pub keys: Result<Keys>,
+    // Stored resources; load might fail
+    pub resources: Result<Resources>,
+    // Number of reloads which changed the keys or resources
+    pub generation: u64,
+    // Errors of the last reload, if it failed
+    pub reload_error: Option<String>,
+    // Secret unlocking the sealed files, once fetched
+    pub secret: Option<Zeroizing<Vec<u8>>>,
+}
+
+impl Store {
+    pub fn new(keys: Result<Keys>, resources: Result<Resources>) -> Store {
+        Store {
+            keys,
+            resources,
+            generation: 0,
+            reload_error: None,
+            secret: None,
+        }
+    }
+
+    /// Load the keys and resources of `config`, unsealing sealed files with
+    /// `secret`.
+    pub fn load(config: &OfflineFsConfig, secret: Option<&[u8]>) -> Store {
+        let mut key_files = config.key_files.clone();
+        key_files.extend(tree_keyfiles(&config.resource_dirs));
+        Store::new(
+            load_keys(&key_files, secret).map_err(|e| anyhow!("Failed to load keys: {}", e)),
+            load_resources(&config.resource_files, secret)
+                .map_err(|e| anyhow!("Failed to load resources: {}", e)),
+        )
+    }
+
+    /// Replace the keys and the resources with those of `fresh` which
+    /// loaded, or which never did before. The secret is kept.
+    pub fn reload(&mut self, fresh: Store) {
+        let mut changed = false;
+        let mut errors = Vec::new();
+        replace_loaded(&mut self.keys, fresh.keys, &mut changed, &mut errors);
+        replace_loaded(
+            &mut self.resources,
+            fresh.resources,
+            &mut changed,
+            &mut errors,
+        );
+
+        if changed {
+            self.generation += 1;
+        }
+        self.reload_error = (!errors.is_empty()).then(|| errors.join("; "));
+    }
+}
+
+fn replace_loaded<T: PartialEq>(
+    current: &mut Result<T>,
+    fresh: Result<T>,
+    changed: &mut bool,
+    errors: &mut Vec<String>,
+) {
+    match fresh {
+        Ok(fresh) => {
+            if current.as_ref().ok() != Some(&fresh) {
+                *current = Ok(fresh);
+                *changed = true;
+            }
+        }
+        Err(e) => {
+            errors.push(e.to_string());
+            if current.is_err() {
+                *current = Err(e);
+            }
+        }
+    }
+}
+
+/// Reload `store` whenever one of the files of `config` is written,
+/// replaced or removed. The watch thread ends, and closes its inotify
+/// instance, within [`WATCH_POLL_INTERVAL_MS`] after the store is dropped.
+pub fn watch(config: OfflineFsConfig, store: Weak<RwLock<Store>>) -> Result<JoinHandle<()>> {
+    let files: Vec<PathBuf> = config
+        .key_files
+        .iter()
+        .chain(&config.resource_files)
+        .cloned()
+        .chain(
+            config
+                .resource_dirs
+                .iter()
+                .map(|dir| dir.join(TREE_KEYS_FILE_NAME)),
+        )
+        .collect();
+
+    let inotify = Inotify::init()?;
+    let mut dirs: HashMap<WatchDescriptor, PathBuf> = HashMap::new();
+    for file in &files {
+        let dir = match file.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        if dirs.values().any(|watched| watched == dir) {
+            continue;
+        }
+
+        let mask = WatchMask::CLOSE_WRITE
+            | WatchMask::MOVED_TO
+            | WatchMask::MOVED_FROM
+            | WatchMask::DELETE;
+        match inotify.watches().add(dir, mask) {
+            Ok(wd) => {
+                dirs.insert(wd, dir.to_path_buf());
+            }
+            Err(e) => warn!("Offline FS KBC: cannot watch {}: {e}", dir.display()),
+        }
+    }
+
+    let handle = thread::Builder::new()
+        .name("offline_fs_kbc-watch".to_string())
+        .spawn(move || watch_loop(inotify, dirs, files, config, store))?;
+    Ok(handle)
+}
+
+fn watch_loop(
+    mut inotify: Inotify,
+    dirs: HashMap<WatchDescriptor, PathBuf>,
+    files: Vec<PathBuf>,
+    config: OfflineFsConfig,
+    store: Weak<RwLock<Store>>,
+) {
+    let mut buffer = [0; 4096];
+    while store.strong_count() > 0 {
+        let mut fd = libc::pollfd {
+            fd: inotify.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // SAFETY: `fd` is one valid `pollfd`.
+        if unsafe { libc::poll(&mut fd, 1, WATCH_POLL_INTERVAL_MS) } == 0 {
+            continue;
+        }
+
+        let events = match inotify.read_events(&mut buffer) {
+            Ok(events) => events,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted) => {
+                continue
+            }
+            Err(e) => {
+                warn!("Offline FS KBC: watch failed, no more reloads: {e}");
+                return;
+            }
+        };
+
+        // Events may be lost when the queue overflows.
+        let changed = events.into_iter().any(|event| {
+            event.mask.contains(EventMask::Q_OVERFLOW)
+                || matches!(
+                    (dirs.get(&event.wd), event.name),
+                    (Some(dir), Some(name)) if files.contains(&dir.join(name))
+                )
+        });
+        if !changed {
+            continue;
+        }
+
+        let Some(store) = store.upgrade() else {
+            return;
+        };
+        let secret = store
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .secret
+            .clone();
+        let fresh = Store::load(&config, secret.as_deref().map(Vec::as_slice));
+        let mut store = store.write().unwrap_or_else(PoisonError::into_inner);
+        store.reload(fresh);
+        match &store.reload_error {
+            None => info!("Offline FS KBC: reloaded, generation {}", store.generation),
+            Some(e) => warn!("Offline FS KBC: reload failed: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use super::*;
+
+    fn keys(key: &[u8]) -> Result<Keys> {
+        Ok([("foo".to_string(), key.to_vec())].into_iter().collect())
+    }
+
+    #[test]
+    fn reload_keeps_last_loaded() {
+        let mut store = Store::new(Err(anyhow!("no keys")), Err(anyhow!("no resources")));
+
+        // A set which never loaded takes the new error.
+        store.reload(Store::new(keys(b"1"), Err(anyhow!("still no resources"))));
+        assert_eq!(store.generation, 1);
+        assert_eq!(store.keys.as_ref().unwrap()["foo"], b"1");
+        assert_eq!(
+            store.resources.as_ref().unwrap_err().to_string(),
+            "still no resources"
+        );
+        assert_eq!(store.reload_error.as_deref(), Some("still no resources"));
+
+        // A set which loaded before is kept.
+        store.reload(Store::new(Err(anyhow!("bad keys")), Ok(Resources::new())));
+        assert_eq!(store.generation, 2);
+        assert_eq!(store.keys.as_ref().unwrap()["foo"], b"1");
+        assert!(store.resources.is_ok());
+        assert_eq!(store.reload_error.as_deref(), Some("bad keys"));
+
+        store.reload(Store::new(Err(anyhow!("bad keys")), Err(anyhow!("bad"))));
+        assert_eq!(store.generation, 2);
+        assert!(store.resources.is_ok());
+
+        // Unchanged sets are no new generation.
+        store.reload(Store::new(keys(b"1"), Ok(Resources::new())));
+        assert_eq!(store.generation, 2);
+        assert_eq!(store.reload_error, None);
+
+        store.reload(Store::new(keys(b"2"), Ok(Resources::new())));
+        assert_eq!(store.generation, 3);
+        assert_eq!(store.keys.as_ref().unwrap()["foo"], b"2");
+        assert_eq!(store.reload_error, None);
+    }
+
+    #[test]
+    fn watch_ends_with_store() {
+        let dir = std::env::temp_dir().join(format!("offline-fs-kbc-watch-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = OfflineFsConfig {
+            key_files: vec![dir.join("keys.json")],
+            resource_files: vec![],
+            ..Default::default()
+        };
+
+        let store = Arc::new(RwLock::new(Store::new(keys(b"1"), Ok(Resources::new()))));
+        let handle = watch(config, Arc::downgrade(&store)).expect("watch failed");
+        // Let the thread wait for events first.
+        thread::sleep(Duration::from_millis(100));
+        drop(store);
+
+        // No file event is needed for the thread to end.
+        let start = Instant::now();
+        while !handle.is_finished() {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "watch still running"
+            );
+            thread::sleep(Duration::from_millis(50));
+        }
+        handle.join().unwrap();
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
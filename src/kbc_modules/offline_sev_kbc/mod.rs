@@ -3,27 +3,55 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
-use crate::common::{crypto, sev::*};
+use crate::common::{
+    crypto,
+    sev::{SecretStore, SecurityFs},
+};
 use crate::kbc_modules::{KbcCheckInfo, KbcInterface};
+use crate::uri::ResourceUri;
 
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 use base64::decode;
+use serde::Deserialize;
 use std::collections::HashMap;
-use std::fs;
+use uuid::{uuid, Uuid};
 use zeroize::Zeroizing;
 
 use super::AnnotationPacket;
 
-const KEYS_PATH: &str = "/sys/kernel/security/secrets/coco/e6f5a162-d67f-4750-a67c-5d065f2a9910";
+const SECRET_GUID: Uuid = uuid!("e6f5a162-d67f-4750-a67c-5d065f2a9910");
 
 type Keys = HashMap<String, Vec<u8>>;
+type Resources = HashMap<String, Vec<u8>>;
+
+/// Injected secret, either a map of key IDs to base64-encoded keys, or
+/// [`SecretSections`].
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Secret {
+    Sections(SecretSections),
+    Keys(HashMap<String, String>),
+}
+
+/// Injected secret with a section of keys, and a section of resource paths
+/// to base64-encoded resources.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SecretSections {
+    #[serde(default)]
+    keys: HashMap<String, String>,
+    #[serde(default)]
+    resources: HashMap<String, String>,
+}
 
 pub struct OfflineSevKbc {
     // KBS info for compatibility; unused
     kbs_info: HashMap<String, String>,
     // Stored keys, loaded from file system; load might fail
     keys: Result<Keys>,
+    // Stored resources, loaded from file system; load might fail
+    resources: Result<Resources>,
 }
 
 #[async_trait]
@@ -36,23 +64,43 @@ impl KbcInterface for OfflineSevKbc {
 
     async fn decrypt_payload(&mut self, annotation_packet: AnnotationPacket) -> Result<Vec<u8>> {
         let key = self.get_key(&annotation_packet.kid.resource_path()).await?;
-        let plain_payload = crypto::decrypt(
+        let plain_payload = crypto::decrypt_with_aad(
             key,
-            base64::decode(annotation_packet.wrapped_data)?,
-            base64::decode(annotation_packet.iv)?,
+            base64::decode(&annotation_packet.wrapped_data)?,
+            base64::decode(&annotation_packet.iv)?,
+            &annotation_packet.aad()?,
             &annotation_packet.wrap_type,
         )?;
 
         Ok(plain_payload)
     }
+
+    async fn get_resource(&mut self, rid: ResourceUri) -> Result<Vec<u8>> {
+        let resource_path = rid.resource_path();
+        let resources = self.resources.as_ref().map_err(|e| anyhow!("{}", e))?;
+        resources
+            .get(resource_path.as_str())
+            .cloned()
+            .ok_or_else(|| anyhow!("Received unknown resource name: {}", resource_path))
+    }
 }
 
 impl OfflineSevKbc {
     #[allow(clippy::new_without_default)]
     pub fn new() -> OfflineSevKbc {
+        let secret = SecurityFs::open().and_then(|mut secrets| load_secret(&mut secrets));
+        let (keys, resources) = match secret {
+            Ok((keys, resources)) => (Ok(keys), Ok(resources)),
+            Err(e) => (
+                Err(anyhow!("Failed to load keys: {}", e)),
+                Err(anyhow!("Failed to load resources: {}", e)),
+            ),
+        };
+
         OfflineSevKbc {
             kbs_info: HashMap::new(),
-            keys: load_keys(KEYS_PATH).map_err(|e| anyhow!("Failed to load keys: {}", e)),
+            keys,
+            resources,
         }
     }
 
@@ -68,33 +116,43 @@ impl OfflineSevKbc {
     }
 }
 
-// This function panics if it is unable to delete the secret file after it has been read
-// or if it is unable to unload the efi_secret kernel module once it has been loaded.
-// Both of these cases could result in exposing the secret.
-//
 // /sys and /proc should be mounted for this to work correctly.
-fn load_keys(keyfile_name: &str) -> Result<Keys> {
-    mount_security_fs()?;
-    let _secret_module = SecretKernelModule::new()?;
-
-    let keys_json = fs::read_to_string(keyfile_name)?;
-    fs::remove_file(keyfile_name).expect("Failed to remove secret file.");
+fn load_secret(secrets: &mut impl SecretStore) -> Result<(Keys, Resources)> {
+    let secret_json = secrets.take(&SECRET_GUID)?;
+    parse_secret(&secret_json)
+}
 
+fn parse_secret(secret_json: &[u8]) -> Result<(Keys, Resources)> {
     // Redact parsing errors to avoid side-channels
-    let encoded_keys: HashMap<String, String> =
-        serde_json::from_str(&keys_json).map_err(|_| anyhow!("Failed to parse keys JSON file"))?;
-
-    encoded_keys
+    let secret: Secret = serde_json::from_slice(secret_json)
+        .map_err(|_| anyhow!("Failed to parse keys JSON file"))?;
+    let (encoded_keys, encoded_resources) = match secret {
+        Secret::Sections(sections) => (sections.keys, sections.resources),
+        Secret::Keys(keys) => (keys, HashMap::new()),
+    };
+
+    let keys = encoded_keys
         .iter()
         .map(|(k, v)| match decode(v) {
             Ok(key) => Ok((k.clone(), key)),
             Err(_) => Err(anyhow!("Failed to decode key")),
         })
-        .collect()
+        .collect::<Result<Keys>>()?;
+    let resources = encoded_resources
+        .iter()
+        .map(|(k, v)| match decode(v) {
+            Ok(resource) => Ok((k.clone(), resource)),
+            Err(_) => Err(anyhow!("Failed to decode resource {}", k)),
+        })
+        .collect::<Result<Resources>>()?;
+
+    Ok((keys, resources))
 }
 
 #[cfg(test)]
 mod tests {
+    use rstest::rstest;
+
     use super::*;
 
     const KID: &str = "foo";
@@ -106,9 +164,80 @@ mod tests {
         let mut kbc = OfflineSevKbc {
             kbs_info: HashMap::new(),
             keys: Ok([(KID.to_string(), KEY.to_vec())].iter().cloned().collect()),
+            resources: Ok(HashMap::new()),
         };
 
         assert_eq!(&kbc.get_key(KID).await.expect("get key failed")[..], KEY);
         assert!(kbc.get_key(WRONG_KEY).await.is_err());
     }
+
+    const POLICY: &str = r#"{"default":[{"type":"insecureAcceptAnything"}]}"#;
+
+    // Secret with the key, the policy, or both, or which does not parse
+    #[rstest]
+    #[case(
+        r#"{"foo":"cGFzc3BocmFzZXdoaWNobmVlZHN0b2JlMzJieXRlcyE="}"#,
+        Some((true, false))
+    )]
+    #[case(
+        r#"{"keys":{"foo":"cGFzc3BocmFzZXdoaWNobmVlZHN0b2JlMzJieXRlcyE="}}"#,
+        Some((true, false))
+    )]
+    #[case(
+        r#"{"keys":{"foo":"cGFzc3BocmFzZXdoaWNobmVlZHN0b2JlMzJieXRlcyE="},"resources":{"default/security-policy/test":"eyJkZWZhdWx0IjpbeyJ0eXBlIjoiaW5zZWN1cmVBY2NlcHRBbnl0aGluZyJ9XX0="}}"#,
+        Some((true, true))
+    )]
+    #[case(
+        r#"{"resources":{"default/security-policy/test":"eyJkZWZhdWx0IjpbeyJ0eXBlIjoiaW5zZWN1cmVBY2NlcHRBbnl0aGluZyJ9XX0="}}"#,
+        Some((false, true))
+    )]
+    #[case(
+        r#"{"keys":{},"resources":{"default/security-policy/test":"%"}}"#,
+        None
+    )]
+    #[case(r#"{"keys":{},"policies":{}}"#, None)]
+    #[case(r#"{"foo":"%"}"#, None)]
+    fn test_parse_secret(#[case] secret: &str, #[case] expected: Option<(bool, bool)>) {
+        let res = parse_secret(secret.as_bytes());
+        assert_eq!(res.is_ok(), expected.is_some());
+        if let (Ok((keys, resources)), Some((key, policy))) = (res, expected) {
+            assert_eq!(keys.get(KID).cloned(), key.then(|| KEY.to_vec()));
+            assert_eq!(
+                resources.get("default/security-policy/test").cloned(),
+                policy.then(|| POLICY.as_bytes().to_vec())
+            );
+        }
+    }
+
+    #[test]
+    fn test_load_secret() {
+        use crate::common::sev::{fixtures::SECRET_AREA, EfiSecretArea};
+
+        let mut secrets = EfiSecretArea::parse(SECRET_AREA).unwrap();
+        let (keys, resources) = load_secret(&mut secrets).expect("load secret failed");
+        assert_eq!(keys[KID], KEY);
+        assert_eq!(resources["default/security-policy/test"], POLICY.as_bytes());
+        assert!(load_secret(&mut secrets).is_err());
+    }
+
+    #[rstest]
+    #[case("kbs:///default/security-policy/test", Some(POLICY))]
+    #[case("kbs:///default/credential/test", None)]
+    #[tokio::test]
+    async fn test_get_resource(#[case] resource_id: &str, #[case] content: Option<&str>) {
+        let mut kbc = OfflineSevKbc {
+            kbs_info: HashMap::new(),
+            keys: Ok(HashMap::new()),
+            resources: Ok([(
+                "default/security-policy/test".to_string(),
+                POLICY.as_bytes().to_vec(),
+            )]
+            .into_iter()
+            .collect()),
+        };
+
+        let rid = ResourceUri::try_from(resource_id).unwrap();
+        let res = kbc.get_resource(rid).await;
+        assert_eq!(res.ok(), content.map(|c| c.as_bytes().to_vec()));
+    }
 }
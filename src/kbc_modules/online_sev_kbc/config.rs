@@ -0,0 +1,155 @@
+// Copyright (c) 2023 Alibaba Cloud
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Configuration of the gRPC connection of the online SEV KBC to the KBS.
+//!
+//! The configuration is a JSON file read from the path given by the
+//! `ONLINE_SEV_KBC_CONFIG` environment variable, or [`DEFAULT_CONFIG_PATH`]
+//! if the variable is not set. A missing file means all defaults. The TLS
+//! settings only apply to `https://` KBS URIs:
+//!
+//! ```json
+//! {
+//!     "ca_cert": "/etc/kbs/ca.pem",
+//!     "client_cert": "/run/aa/client.pem",
+//!     "client_key": "/run/aa/client.key",
+//!     "domain_name": "kbs.example.org",
+//!     "connect_timeout_secs": 10,
+//!     "request_timeout_secs": 30
+//! }
+//! ```
+
+use anyhow::*;
+use serde::Deserialize;
+use std::{path::PathBuf, time::Duration};
+
+use crate::kbc_modules::config;
+
+pub const CONFIG_PATH_ENV: &str = "ONLINE_SEV_KBC_CONFIG";
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/aa-online_sev_kbc-config.json";
+
+const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 10;
+const DEFAULT_REQUEST_TIMEOUT_SECS: u64 = 30;
+
+/// How the online SEV KBC connects to the KBS.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct OnlineSevConfig {
+    /// PEM file of the CA certificates the KBS certificate is verified
+    /// with. Defaults to the webpki roots.
+    pub ca_cert: Option<PathBuf>,
+
+    /// PEM file of the client certificate, for KBSes requiring mutual TLS.
+    /// Set along with `client_key`.
+    pub client_cert: Option<PathBuf>,
+
+    /// PEM file of the PKCS #8 or RSA private key of `client_cert`.
+    pub client_key: Option<PathBuf>,
+
+    /// Name the KBS certificate is verified for. Defaults to the host of
+    /// the KBS URI.
+    pub domain_name: Option<String>,
+
+    /// Timeout of connecting to the KBS.
+    pub connect_timeout_secs: u64,
+
+    /// Timeout of each request to the KBS.
+    pub request_timeout_secs: u64,
+}
+
+impl Default for OnlineSevConfig {
+    fn default() -> Self {
+        Self {
+            ca_cert: None,
+            client_cert: None,
+            client_key: None,
+            domain_name: None,
+            connect_timeout_secs: DEFAULT_CONNECT_TIMEOUT_SECS,
+            request_timeout_secs: DEFAULT_REQUEST_TIMEOUT_SECS,
+        }
+    }
+}
+
+impl OnlineSevConfig {
+    /// Load the configuration from the path of `ONLINE_SEV_KBC_CONFIG`.
+    pub fn load() -> Result<Self> {
+        let config: Self = config::load(CONFIG_PATH_ENV, DEFAULT_CONFIG_PATH)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.client_cert.is_some() != self.client_key.is_some() {
+            bail!(
+                "Invalid online SEV KBC config: client_cert and client_key should be set together"
+            );
+        }
+        Ok(())
+    }
+
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_secs(self.connect_timeout_secs)
+    }
+
+    pub fn request_timeout(&self) -> Duration {
+        Duration::from_secs(self.request_timeout_secs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[rstest::rstest]
+    #[case(r#"{}"#, Some(OnlineSevConfig::default()))]
+    #[case(
+        r#"{"ca_cert":"/etc/kbs/ca.pem","domain_name":"kbs.example.org"}"#,
+        Some(OnlineSevConfig {
+            ca_cert: Some("/etc/kbs/ca.pem".into()),
+            domain_name: Some("kbs.example.org".to_string()),
+            ..Default::default()
+        })
+    )]
+    #[case(
+        r#"{"client_cert":"/run/aa/client.pem","client_key":"/run/aa/client.key"}"#,
+        Some(OnlineSevConfig {
+            client_cert: Some("/run/aa/client.pem".into()),
+            client_key: Some("/run/aa/client.key".into()),
+            ..Default::default()
+        })
+    )]
+    #[case(
+        r#"{"connect_timeout_secs":1,"request_timeout_secs":5}"#,
+        Some(OnlineSevConfig {
+            connect_timeout_secs: 1,
+            request_timeout_secs: 5,
+            ..Default::default()
+        })
+    )]
+    #[case(r#"{"ca_certs":["/etc/kbs/ca.pem"]}"#, None)]
+    #[case(r#"{"request_timeout_secs":-1}"#, None)]
+    fn parse(#[case] content: &str, #[case] expected: Option<OnlineSevConfig>) {
+        let config: Result<OnlineSevConfig, _> = serde_json::from_str(content);
+        assert_eq!(config.ok(), expected);
+    }
+
+    #[rstest::rstest]
+    #[case(Some("/run/aa/client.pem"), Some("/run/aa/client.key"), true)]
+    #[case(None, None, true)]
+    #[case(Some("/run/aa/client.pem"), None, false)]
+    #[case(None, Some("/run/aa/client.key"), false)]
+    fn client_identity(
+        #[case] client_cert: Option<&str>,
+        #[case] client_key: Option<&str>,
+        #[case] valid: bool,
+    ) {
+        let config = OnlineSevConfig {
+            client_cert: client_cert.map(PathBuf::from),
+            client_key: client_key.map(PathBuf::from),
+            ..Default::default()
+        };
+        assert_eq!(config.validate().is_ok(), valid);
+    }
+}
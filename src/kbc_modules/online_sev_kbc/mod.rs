@@ -4,17 +4,19 @@
 //
 
 use crate::common::crypto::WrapType;
-use crate::common::{crypto, sev::*};
-use crate::kbc_modules::{KbcCheckInfo, KbcInterface};
+use crate::common::{
+    crypto,
+    sev::{SecretStore, SecurityFs},
+};
+use crate::kbc_modules::{kbs_address::KbsAddress, KbcCheckInfo, KbcInterface};
 use crate::uri::ResourceUri;
 
 use anyhow::*;
 use async_trait::async_trait;
 use serde::Deserialize;
-use std::collections::HashMap;
-use std::fs;
-use tonic::codegen::http::Uri;
-use uuid::Uuid;
+use std::{collections::HashMap, fs};
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Identity};
+use uuid::{uuid, Uuid};
 use zeroize::Zeroizing;
 
 use keybroker::key_broker_service_client::KeyBrokerServiceClient;
@@ -22,10 +24,13 @@ use keybroker::{OnlineSecretRequest, RequestDetails};
 
 use super::AnnotationPacket;
 
+use config::OnlineSevConfig;
+
+mod config;
 #[rustfmt::skip]
 mod keybroker;
 
-const KEYS_PATH: &str = "/sys/kernel/security/secrets/coco/1ee27366-0c87-43a6-af48-28543eaf7cb0";
+const SECRET_GUID: Uuid = uuid!("1ee27366-0c87-43a6-af48-28543eaf7cb0");
 
 #[derive(Deserialize, Clone)]
 struct Connection {
@@ -38,6 +43,9 @@ pub struct OnlineSevKbc {
     kbs_info: HashMap<String, String>,
     kbs_uri: String,
     connection: Result<Connection>,
+    config: Result<OnlineSevConfig>,
+    // Connected on the first request, and reused by later ones
+    channel: Option<Channel>,
 }
 
 #[async_trait]
@@ -49,11 +57,12 @@ impl KbcInterface for OnlineSevKbc {
     }
 
     async fn decrypt_payload(&mut self, annotation_packet: AnnotationPacket) -> Result<Vec<u8>> {
-        let key = self.get_key_from_kbs(annotation_packet.kid).await?;
-        let plain_payload = crypto::decrypt(
+        let key = self.get_key_from_kbs(annotation_packet.kid.clone()).await?;
+        let plain_payload = crypto::decrypt_with_aad(
             key,
-            base64::decode(annotation_packet.wrapped_data)?,
-            base64::decode(annotation_packet.iv)?,
+            base64::decode(&annotation_packet.wrapped_data)?,
+            base64::decode(&annotation_packet.iv)?,
+            &annotation_packet.aad()?,
             &annotation_packet.wrap_type,
         )?;
 
@@ -80,15 +89,28 @@ impl OnlineSevKbc {
         OnlineSevKbc {
             kbs_info: HashMap::new(),
             kbs_uri,
-            connection: load_connection(),
+            connection: SecurityFs::open().and_then(|mut secrets| load_connection(&mut secrets)),
+            config: OnlineSevConfig::load(),
+            channel: None,
         }
     }
 
-    async fn query_kbs(&self, secret_type: String, secret_id: String) -> Result<Vec<u8>> {
-        let uri = format!("http://{}", self.kbs_uri).parse::<Uri>()?;
+    fn channel(&mut self) -> Result<Channel> {
+        if let Some(channel) = &self.channel {
+            return Ok(channel.clone());
+        }
+
+        let config = self
+            .config
+            .as_ref()
+            .map_err(|e| anyhow!("Failed to load online SEV KBC config. {:?}", e))?;
+        let channel = kbs_channel(self.kbs_uri.parse()?, config)?;
+        self.channel = Some(channel.clone());
+        Ok(channel)
+    }
 
-        let channel = tonic::transport::Channel::builder(uri).connect_lazy();
-        let mut client = KeyBrokerServiceClient::new(channel);
+    async fn query_kbs(&mut self, secret_type: String, secret_id: String) -> Result<Vec<u8>> {
+        let mut client = KeyBrokerServiceClient::new(self.channel()?);
 
         let connection = self
             .connection
@@ -123,7 +145,7 @@ impl OnlineSevKbc {
             .to_vec())
     }
 
-    async fn get_key_from_kbs(&self, rid: ResourceUri) -> Result<Zeroizing<Vec<u8>>> {
+    async fn get_key_from_kbs(&mut self, rid: ResourceUri) -> Result<Zeroizing<Vec<u8>>> {
         let key = self
             .query_kbs("key".to_string(), rid.resource_path())
             .await?;
@@ -131,18 +153,277 @@ impl OnlineSevKbc {
         Ok(key)
     }
 
-    async fn get_resource_from_kbs(&self, rid: ResourceUri) -> Result<Vec<u8>> {
+    async fn get_resource_from_kbs(&mut self, rid: ResourceUri) -> Result<Vec<u8>> {
         self.query_kbs("resource".to_string(), rid.resource_path())
             .await
     }
 }
 
-fn load_connection() -> Result<Connection> {
-    mount_security_fs()?;
-    let _secret_module = SecretKernelModule::new()?;
+// gRPC channel to the KBS, over TCP, a Unix socket or vsock. The channel
+// connects on its first request, and reconnects when the connection drops.
+fn kbs_channel(address: KbsAddress, config: &OnlineSevConfig) -> Result<Channel> {
+    let timeouts = |endpoint: Endpoint| {
+        endpoint
+            .connect_timeout(config.connect_timeout())
+            .timeout(config.request_timeout())
+    };
+
+    match address {
+        KbsAddress::Http(url) if url.scheme() == "https" => {
+            let endpoint = Endpoint::from_shared(url.to_string())?
+                .tls_config(kbs_tls_config(config)?)
+                .with_context(|| format!("Invalid TLS config of KBS {url}"))?;
+            Ok(timeouts(endpoint).connect_lazy())
+        }
+        KbsAddress::Http(url) => {
+            Ok(timeouts(Endpoint::from_shared(url.to_string())?).connect_lazy())
+        }
+        // The URI only sets the `:authority` of the requests.
+        address => Ok(
+            timeouts(Endpoint::from_static("http://kbs")).connect_with_connector_lazy(
+                tower::service_fn(move |_| {
+                    let address = address.clone();
+                    async move { address.connect().await }
+                }),
+            ),
+        ),
+    }
+}
+
+fn kbs_tls_config(config: &OnlineSevConfig) -> Result<ClientTlsConfig> {
+    let read = |path: &std::path::Path| {
+        fs::read(path).with_context(|| format!("Read {} failed", path.display()))
+    };
+
+    let mut tls_config = ClientTlsConfig::new();
+    if let Some(ca_cert) = &config.ca_cert {
+        tls_config = tls_config.ca_certificate(Certificate::from_pem(read(ca_cert)?));
+    }
+    match (&config.client_cert, &config.client_key) {
+        (Some(cert), Some(key)) => {
+            tls_config = tls_config.identity(Identity::from_pem(read(cert)?, read(key)?));
+        }
+        (None, None) => (),
+        _ => bail!("client_cert and client_key should be set together"),
+    }
+    if let Some(domain_name) = &config.domain_name {
+        tls_config = tls_config.domain_name(domain_name);
+    }
+    Ok(tls_config)
+}
+
+fn load_connection(secrets: &mut impl SecretStore) -> Result<Connection> {
+    let connection_json = secrets.take(&SECRET_GUID)?;
+    Ok(serde_json::from_slice(&connection_json)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::sev::{fixtures::SECRET_AREA, EfiSecretArea};
+
+    #[test]
+    fn test_load_connection() {
+        let mut secrets = EfiSecretArea::parse(SECRET_AREA).unwrap();
+        let connection = load_connection(&mut secrets).expect("load connection failed");
+        assert_eq!(
+            connection.client_id,
+            uuid!("8bbd3d7e-0ee8-4f36-9c3b-2d5b1d4a3c51")
+        );
+        assert_eq!(base64::decode(connection.key).unwrap().len(), 32);
+        assert!(load_connection(&mut secrets).is_err());
+    }
+
+    #[cfg(feature = "rust-crypto")]
+    mod kbs {
+        use aes_gcm::{aead::Aead, Aes256Gcm, KeyInit, Nonce};
+        use std::{
+            collections::HashSet,
+            net::SocketAddr,
+            path::PathBuf,
+            sync::{Arc, Mutex},
+            time::Duration,
+        };
+        use tokio::net::TcpListener;
+        use tonic::{
+            transport::{server::TcpIncoming, Server, ServerTlsConfig},
+            Request, Response, Status,
+        };
+
+        use super::*;
+        use crate::kbc_modules::online_sev_kbc::keybroker::{
+            key_broker_service_server::{KeyBrokerService, KeyBrokerServiceServer},
+            OnlineSecretResponse,
+        };
+
+        const KEY: [u8; 32] = [7; 32];
+        const CLIENT_ID: Uuid = uuid!("8bbd3d7e-0ee8-4f36-9c3b-2d5b1d4a3c51");
+
+        fn fixture(name: &str) -> PathBuf {
+            PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                .join("src/kbc_modules/online_sev_kbc")
+                .join(name)
+        }
+
+        // KBS serving each secret as its ID, and recording the addresses of
+        // its clients.
+        #[derive(Default)]
+        struct MockKbs {
+            clients: Arc<Mutex<HashSet<SocketAddr>>>,
+        }
+
+        #[tonic::async_trait]
+        impl KeyBrokerService for MockKbs {
+            async fn get_online_secret(
+                &self,
+                request: Request<OnlineSecretRequest>,
+            ) -> Result<Response<OnlineSecretResponse>, Status> {
+                if let Some(addr) = request.remote_addr() {
+                    self.clients.lock().unwrap().insert(addr);
+                }
+                let request = request.into_inner();
+                if request.client_id != CLIENT_ID.hyphenated().to_string() {
+                    return Err(Status::permission_denied("unknown client"));
+                }
+
+                let mut payload = HashMap::new();
+                for details in request.secret_requests {
+                    if details.id == "default/slow/1" {
+                        tokio::time::sleep(Duration::from_secs(10)).await;
+                    }
+                    payload.insert(details.guid, details.id.into_bytes());
+                }
+                let payload = bincode::serialize(&payload).unwrap();
+                let iv = [1; 12];
+                let encrypted = Aes256Gcm::new_from_slice(&KEY)
+                    .unwrap()
+                    .encrypt(Nonce::from_slice(&iv), &payload[..])
+                    .unwrap();
+                Result::Ok(Response::new(OnlineSecretResponse {
+                    payload: base64::encode(encrypted),
+                    iv: base64::encode(iv),
+                }))
+            }
+        }
+
+        // Serve a mock KBS on a local port, with TLS if `tls` is given.
+        async fn serve(
+            tls: Option<ServerTlsConfig>,
+        ) -> (SocketAddr, Arc<Mutex<HashSet<SocketAddr>>>) {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let kbs = MockKbs::default();
+            let clients = kbs.clients.clone();
+
+            let mut server = Server::builder();
+            if let Some(tls) = tls {
+                server = server.tls_config(tls).unwrap();
+            }
+            let incoming = TcpIncoming::from_listener(listener, true, None).unwrap();
+            tokio::spawn(
+                server
+                    .add_service(KeyBrokerServiceServer::new(kbs))
+                    .serve_with_incoming(incoming),
+            );
+            (addr, clients)
+        }
+
+        fn server_tls(client_ca: bool) -> ServerTlsConfig {
+            let identity = Identity::from_pem(
+                fs::read(fixture("test-server.pem")).unwrap(),
+                fs::read(fixture("test-server.key")).unwrap(),
+            );
+            let tls = ServerTlsConfig::new().identity(identity);
+            match client_ca {
+                true => tls.client_ca_root(Certificate::from_pem(
+                    fs::read(fixture("test-ca.pem")).unwrap(),
+                )),
+                false => tls,
+            }
+        }
+
+        fn online_sev_kbc(kbs_uri: String, config: OnlineSevConfig) -> OnlineSevKbc {
+            OnlineSevKbc {
+                kbs_info: HashMap::new(),
+                kbs_uri,
+                connection: Ok(Connection {
+                    client_id: CLIENT_ID,
+                    key: base64::encode(KEY),
+                }),
+                config: Ok(config),
+                channel: None,
+            }
+        }
+
+        fn resource(id: &str) -> ResourceUri {
+            ResourceUri::try_from(format!("kbs:///default/{id}/1").as_str()).unwrap()
+        }
+
+        #[tokio::test]
+        async fn reuse_connection() {
+            let (addr, clients) = serve(None).await;
+            let mut kbc = online_sev_kbc(format!("http://{addr}"), OnlineSevConfig::default());
 
-    let connection_json = fs::read_to_string(KEYS_PATH)?;
-    fs::remove_file(KEYS_PATH).expect("Failed to remove secret file.");
+            for id in ["a", "b"] {
+                let secret = kbc.get_resource(resource(id)).await.expect("query failed");
+                assert_eq!(secret, format!("default/{id}/1").into_bytes());
+            }
+            assert_eq!(clients.lock().unwrap().len(), 1);
+        }
 
-    Ok(serde_json::from_str(&connection_json)?)
+        #[tokio::test]
+        async fn request_timeout() {
+            let (addr, _) = serve(None).await;
+            let config = OnlineSevConfig {
+                request_timeout_secs: 1,
+                ..Default::default()
+            };
+            let mut kbc = online_sev_kbc(format!("http://{addr}"), config);
+
+            let err = kbc.get_resource(resource("slow")).await.unwrap_err();
+            assert!(format!("{err:?}").contains("Timeout expired"), "{err:?}");
+            // The channel outlives the timed out request.
+            assert!(kbc.get_resource(resource("a")).await.is_ok());
+        }
+
+        #[tokio::test]
+        async fn https() {
+            let (addr, _) = serve(Some(server_tls(false))).await;
+            let config = OnlineSevConfig {
+                ca_cert: Some(fixture("test-ca.pem")),
+                ..Default::default()
+            };
+            let mut kbc = online_sev_kbc(format!("https://localhost:{}", addr.port()), config);
+            assert!(kbc.get_resource(resource("a")).await.is_ok());
+
+            // The KBS certificate is not issued by the webpki roots.
+            let mut untrusted = online_sev_kbc(
+                format!("https://localhost:{}", addr.port()),
+                OnlineSevConfig::default(),
+            );
+            assert!(untrusted.get_resource(resource("a")).await.is_err());
+        }
+
+        #[rstest::rstest]
+        #[case(true)]
+        #[case(false)]
+        #[tokio::test]
+        async fn https_client_cert(#[case] with_client_cert: bool) {
+            let (addr, _) = serve(Some(server_tls(true))).await;
+            let mut config = OnlineSevConfig {
+                ca_cert: Some(fixture("test-ca.pem")),
+                domain_name: Some("localhost".to_string()),
+                ..Default::default()
+            };
+            if with_client_cert {
+                config.client_cert = Some(fixture("test-client.pem"));
+                config.client_key = Some(fixture("test-client.key"));
+            }
+            let mut kbc = online_sev_kbc(format!("https://{addr}"), config);
+            assert_eq!(
+                kbc.get_resource(resource("a")).await.is_ok(),
+                with_client_cert
+            );
+        }
+    }
 }
@@ -3,7 +3,7 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
-use crate::common::crypto::{decrypt, WrapType};
+use crate::common::crypto::{decrypt_with_aad, WrapType};
 use crate::kbc_modules::{KbcCheckInfo, KbcInterface};
 use crate::uri::ResourceUri;
 
@@ -63,10 +63,11 @@ impl KbcInterface for SampleKbc {
 
     async fn decrypt_payload(&mut self, annotation_packet: AnnotationPacket) -> Result<Vec<u8>> {
         let key = Zeroizing::new(HARDCODED_KEY.to_vec());
-        let plain_text = decrypt(
+        let plain_text = decrypt_with_aad(
             key,
-            base64::decode(annotation_packet.wrapped_data)?,
-            base64::decode(annotation_packet.iv)?,
+            base64::decode(&annotation_packet.wrapped_data)?,
+            base64::decode(&annotation_packet.iv)?,
+            &annotation_packet.aad()?,
             WrapType::Aes256Gcm.as_ref(),
         )?;
 
@@ -9,9 +9,12 @@ extern crate strum;
 
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
-use kbc_modules::{uri::ResourceUri, AnnotationPacket};
-use std::collections::HashMap;
+#[cfg(feature = "key_shares")]
+use kbc_modules::key_shares;
+use kbc_modules::{parse_recipients, uri::ResourceUri};
+use std::{collections::HashMap, str::FromStr};
 
+use crate::common::crypto::WrapType;
 use crate::kbc_modules::{KbcCheckInfo, KbcInstance, KbcModuleList};
 
 pub mod common;
@@ -61,6 +64,20 @@ pub trait AttestationAPIs {
         annotation: &str,
     ) -> Result<Vec<u8>>;
 
+    /// Decrypt the encrypted information in `annotation` like
+    /// [`AttestationAPIs::decrypt_image_layer_annotation`], if it is bound to
+    /// `aad`, s.t. the digest of the layer.
+    ///
+    /// Recipients of the annotation with another `aad` are refused, so that an
+    /// annotation moved to another layer or image is not decrypted.
+    async fn decrypt_bound_image_layer_annotation(
+        &mut self,
+        kbc_name: &str,
+        kbs_uri: &str,
+        annotation: &str,
+        aad: &[u8],
+    ) -> Result<Vec<u8>>;
+
     /// Request KBS to obtain confidential resources, including confidential data or files.
     ///
     /// `resource_uri` is a KBS Resource URI pointing to a specific resource.
@@ -76,6 +93,8 @@ pub trait AttestationAPIs {
 pub struct AttestationAgent {
     kbc_module_list: KbcModuleList,
     kbc_instance_map: HashMap<String, KbcInstance>,
+    reject_unauthenticated_wrap_types: bool,
+    recipient_preference: Vec<String>,
 }
 
 impl Default for AttestationAgent {
@@ -90,9 +109,26 @@ impl AttestationAgent {
         AttestationAgent {
             kbc_module_list: KbcModuleList::new(),
             kbc_instance_map: HashMap::new(),
+            reject_unauthenticated_wrap_types: false,
+            recipient_preference: Vec::new(),
         }
     }
 
+    /// Refuse to decrypt annotations whose wrap type does not detect
+    /// tampering, s.t. `A256CTR`, or is unknown. Off by default for older
+    /// images.
+    pub fn reject_unauthenticated_wrap_types(&mut self, reject: bool) {
+        self.reject_unauthenticated_wrap_types = reject;
+    }
+
+    /// Try first the recipients of an annotation whose kid starts with the
+    /// first of `prefixes`, s.t. `kbs://kbs.example.org:8080/`, then with
+    /// the second one, and so on. The other recipients are tried last, all
+    /// in the order of the annotation.
+    pub fn recipient_preference(&mut self, prefixes: Vec<String>) {
+        self.recipient_preference = prefixes;
+    }
+
     pub fn about(&self) -> String {
         let kbc_names_list = self.kbc_module_list.names().join(", ");
         format!("KBCs: {kbc_names_list}")
@@ -116,26 +152,99 @@ impl AttestationAgent {
             .ok_or_else(|| anyhow!("The KBC instance does not exist!"))?
             .check()
     }
-}
 
-#[async_trait]
-impl AttestationAPIs for AttestationAgent {
-    async fn decrypt_image_layer_annotation(
+    /// Decrypt the first recipient of `annotation` that can be, refusing those
+    /// not bound to `aad` if it is given.
+    async fn decrypt_annotation(
         &mut self,
         kbc_name: &str,
         kbs_uri: &str,
         annotation: &str,
+        aad: Option<&[u8]>,
     ) -> Result<Vec<u8>> {
         if !self.kbc_instance_map.contains_key(kbc_name) {
             self.instantiate_kbc(kbc_name, kbs_uri)?;
         }
 
-        let annotation: AnnotationPacket = serde_json::from_str(annotation)?;
+        let mut recipients = parse_recipients(annotation)?;
+        let preference = &self.recipient_preference;
+        recipients.sort_by_key(|recipient| {
+            let kid = recipient.kid.whole_uri();
+            preference
+                .iter()
+                .position(|prefix| kid.starts_with(prefix))
+                .unwrap_or(preference.len())
+        });
 
-        self.kbc_instance_map
+        let reject_unauthenticated = self.reject_unauthenticated_wrap_types;
+        let kbc = self
+            .kbc_instance_map
             .get_mut(kbc_name)
-            .ok_or_else(|| anyhow!("The KBC instance does not existing!"))?
-            .decrypt_payload(annotation)
+            .ok_or_else(|| anyhow!("The KBC instance does not existing!"))?;
+        let count = recipients.len();
+        let mut errors = Vec::new();
+        for recipient in recipients {
+            let kid = recipient.kid.whole_uri();
+            let res = match WrapType::from_str(&recipient.wrap_type) {
+                Ok(wrap_type) if reject_unauthenticated && !wrap_type.is_authenticated() => {
+                    Err(anyhow!(
+                        "Unauthenticated wrap type {} is rejected",
+                        recipient.wrap_type
+                    ))
+                }
+                // Wrap types unknown here are otherwise left to the KBC.
+                Err(_) if reject_unauthenticated => Err(anyhow!(
+                    "Unknown wrap type {} is rejected",
+                    recipient.wrap_type
+                )),
+                _ if !recipient.is_bound_to(aad) => Err(anyhow!(
+                    "The aad of the annotation differs from the expected one"
+                )),
+                #[cfg(feature = "key_shares")]
+                _ if recipient.key_shares.is_some() => {
+                    key_shares::decrypt_payload(kbc, recipient).await
+                }
+                #[cfg(not(feature = "key_shares"))]
+                _ if recipient.key_shares.is_some() => {
+                    Err(anyhow!("Key shares are not supported by this build"))
+                }
+                _ => kbc.decrypt_payload(recipient).await,
+            };
+
+            match res {
+                Ok(plaintext) => return Ok(plaintext),
+                Err(e) if count == 1 => return Err(e),
+                Err(e) => errors.push(format!("{kid}: {e}")),
+            }
+        }
+
+        Err(anyhow!(
+            "No recipient of the annotation could be decrypted: {}",
+            errors.join("; ")
+        ))
+    }
+}
+
+#[async_trait]
+impl AttestationAPIs for AttestationAgent {
+    async fn decrypt_image_layer_annotation(
+        &mut self,
+        kbc_name: &str,
+        kbs_uri: &str,
+        annotation: &str,
+    ) -> Result<Vec<u8>> {
+        self.decrypt_annotation(kbc_name, kbs_uri, annotation, None)
+            .await
+    }
+
+    async fn decrypt_bound_image_layer_annotation(
+        &mut self,
+        kbc_name: &str,
+        kbs_uri: &str,
+        annotation: &str,
+        aad: &[u8],
+    ) -> Result<Vec<u8>> {
+        self.decrypt_annotation(kbc_name, kbs_uri, annotation, Some(aad))
             .await
     }
 
@@ -158,3 +267,114 @@ impl AttestationAPIs for AttestationAgent {
             .await
     }
 }
+
+#[cfg(feature = "sample_kbc")]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reject_unauthenticated_wrap_types() {
+        let annotation = r#"{"kid":"kbs:///default/test-key/1","wrapped_data":"","iv":"","wrap_type":"A256CTR"}"#;
+        let mut aa = AttestationAgent::new();
+        aa.reject_unauthenticated_wrap_types(true);
+        let err = aa
+            .decrypt_image_layer_annotation("sample_kbc", "null", annotation)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unauthenticated wrap type A256CTR is rejected"
+        );
+
+        aa.reject_unauthenticated_wrap_types(false);
+        let err = aa
+            .decrypt_image_layer_annotation("sample_kbc", "null", annotation)
+            .await
+            .unwrap_err();
+        assert!(!err.to_string().contains("Unauthenticated"));
+    }
+
+    #[tokio::test]
+    async fn reject_unknown_wrap_types() {
+        let annotation = r#"{"kid":"kbs:///default/test-key/1","wrapped_data":"","iv":"","wrap_type":"A256XYZ"}"#;
+        let mut aa = AttestationAgent::new();
+        aa.reject_unauthenticated_wrap_types(true);
+        let err = aa
+            .decrypt_image_layer_annotation("sample_kbc", "null", annotation)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Unknown wrap type A256XYZ is rejected");
+
+        aa.reject_unauthenticated_wrap_types(false);
+        let err = aa
+            .decrypt_image_layer_annotation("sample_kbc", "null", annotation)
+            .await
+            .unwrap_err();
+        assert!(!err.to_string().contains("Unknown wrap type"));
+    }
+
+    const RECIPIENTS: &str = r#"{"version":2,"recipients":[
+        {"kid":"kbs://kbs.example.org:8080/default/key/1","wrapped_data":"","iv":"AAAAAAAAAAAAAAAA","wrap_type":"A256GCM"},
+        {"kid":"kbs:///default/test-key/1","wrapped_data":"9DzjnFk5jygxrETS3clGaVO87Xqb","iv":"AAAAAAAAAAAAAAAA","wrap_type":"A256GCM"}
+    ]}"#;
+
+    #[tokio::test]
+    async fn multiple_recipients() {
+        let mut aa = AttestationAgent::new();
+        let plaintext = aa
+            .decrypt_image_layer_annotation("sample_kbc", "null", RECIPIENTS)
+            .await
+            .expect("decrypt failed");
+        assert_eq!(plaintext, b"plbco");
+
+        // No recipient can be decrypted, in the order of preference.
+        let annotation = RECIPIENTS.replace("9DzjnF", "AAAAAA");
+        let err = aa
+            .decrypt_image_layer_annotation("sample_kbc", "null", &annotation)
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(err.find("kbs://kbs.example.org:8080/").unwrap() < err.find("kbs:///").unwrap());
+
+        aa.recipient_preference(vec!["kbs:///".to_string()]);
+        let err = aa
+            .decrypt_image_layer_annotation("sample_kbc", "null", &annotation)
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(err.find("kbs://kbs.example.org:8080/").unwrap() > err.find("kbs:///").unwrap());
+    }
+
+    #[tokio::test]
+    async fn relocated_annotation() {
+        // Bound to the layer with the digest `sha256:layer-1`.
+        let annotation = r#"{"kid":"kbs:///default/test-key/1","wrapped_data":"9DzjnFkUAH4pAd7z7onaZUOIG/8V","iv":"AAAAAAAAAAAAAAAA","wrap_type":"A256GCM","aad":"c2hhMjU2OmxheWVyLTE="}"#;
+        let mut aa = AttestationAgent::new();
+        let plaintext = aa
+            .decrypt_bound_image_layer_annotation(
+                "sample_kbc",
+                "null",
+                annotation,
+                b"sha256:layer-1",
+            )
+            .await
+            .expect("decrypt failed");
+        assert_eq!(plaintext, b"plbco");
+
+        // Moved to another layer, or not bound at all.
+        let unbound = r#"{"kid":"kbs:///default/test-key/1","wrapped_data":"9DzjnFk5jygxrETS3clGaVO87Xqb","iv":"AAAAAAAAAAAAAAAA","wrap_type":"A256GCM"}"#;
+        for annotation in [annotation, unbound] {
+            let err = aa
+                .decrypt_bound_image_layer_annotation(
+                    "sample_kbc",
+                    "null",
+                    annotation,
+                    b"sha256:layer-2",
+                )
+                .await
+                .unwrap_err();
+            assert!(err.to_string().contains("aad"));
+        }
+    }
+}